@@ -0,0 +1,18 @@
+DROP INDEX local_files_contenthash;
+
+-- SQLite can't drop columns, so the table is rebuilt without it.
+CREATE TABLE local_files_old
+(
+  path  varchar(1024) PRIMARY KEY NOT NULL,
+  size  integer NOT NULL,
+  mtime integer NOT NULL,
+  ctime integer NOT NULL,
+  fileid integer NOT NULL,
+  FOREIGN KEY(fileid) REFERENCES caps(fileid)
+);
+
+INSERT INTO local_files_old SELECT path, size, mtime, ctime, fileid FROM local_files;
+
+DROP TABLE local_files;
+
+ALTER TABLE local_files_old RENAME TO local_files;
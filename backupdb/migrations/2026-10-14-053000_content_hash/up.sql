@@ -0,0 +1,3 @@
+ALTER TABLE local_files ADD COLUMN contenthash varchar(64); -- SHA-256 of the contents, if it was computed
+
+CREATE INDEX local_files_contenthash ON local_files (contenthash, size);
@@ -0,0 +1,8 @@
+CREATE TABLE backups
+(
+ id integer PRIMARY KEY NOT NULL,
+ timestamp TIMESTAMP NOT NULL,      -- when the archive was attached
+ root_cap varchar(256) NOT NULL,    -- URI:DIR2-CHK:... of the backed-up tree
+ file_count integer NOT NULL,
+ byte_count integer NOT NULL
+);
@@ -0,0 +1,7 @@
+-- Directories finished by the current or last interrupted run, so --resume
+-- can skip them without reading them again. Emptied once a run completes.
+CREATE TABLE journal
+(
+ path varchar(1024) PRIMARY KEY NOT NULL,
+ dircap varchar(256) NOT NULL
+);
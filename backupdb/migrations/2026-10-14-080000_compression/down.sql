@@ -0,0 +1,12 @@
+-- SQLite can't drop columns, so the table is rebuilt without it.
+CREATE TABLE caps_old
+(
+ fileid integer PRIMARY KEY NOT NULL,
+ filecap varchar(256) UNIQUE NOT NULL
+);
+
+INSERT INTO caps_old SELECT fileid, filecap FROM caps;
+
+DROP TABLE caps;
+
+ALTER TABLE caps_old RENAME TO caps;
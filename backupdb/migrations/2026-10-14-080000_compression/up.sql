@@ -0,0 +1 @@
+ALTER TABLE caps ADD COLUMN compression varchar(16); -- gzip or zstd, if the contents were compressed
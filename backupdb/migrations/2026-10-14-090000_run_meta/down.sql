@@ -0,0 +1,15 @@
+-- SQLite can't drop columns, so the table is rebuilt without them.
+CREATE TABLE backups_old
+(
+ id integer PRIMARY KEY NOT NULL,
+ timestamp TIMESTAMP NOT NULL,
+ root_cap varchar(256) NOT NULL,
+ file_count integer NOT NULL,
+ byte_count integer NOT NULL
+);
+
+INSERT INTO backups_old SELECT id, timestamp, root_cap, file_count, byte_count FROM backups;
+
+DROP TABLE backups;
+
+ALTER TABLE backups_old RENAME TO backups;
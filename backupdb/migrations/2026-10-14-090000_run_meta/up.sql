@@ -0,0 +1,4 @@
+ALTER TABLE backups ADD COLUMN node_url varchar(256); -- the node the archive was uploaded through
+ALTER TABLE backups ADD COLUMN shares_needed integer; -- k/happy/N asked for, NULL for the node's default
+ALTER TABLE backups ADD COLUMN shares_happy integer;
+ALTER TABLE backups ADD COLUMN shares_total integer;
@@ -0,0 +1,13 @@
+-- SQLite can't drop columns, so the table is rebuilt without it.
+CREATE TABLE directories_old
+(
+ dirhash integer PRIMARY KEY NOT NULL,
+ dircap varchar(256) NOT NULL,
+ last_uploaded TIMESTAMP DEFAULT CURRENT_TIMESTAMP
+);
+
+INSERT INTO directories_old SELECT dirhash, dircap, last_uploaded FROM directories;
+
+DROP TABLE directories;
+
+ALTER TABLE directories_old RENAME TO directories;
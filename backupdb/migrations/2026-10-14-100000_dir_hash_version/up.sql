@@ -0,0 +1 @@
+ALTER TABLE directories ADD COLUMN hash_version integer NOT NULL DEFAULT 0; -- Dir::hash as of DIR_HASH_VERSION, 0 from before there was one
@@ -0,0 +1,9 @@
+-- What every cap uploaded to the main grid is on each mirror grid, by the
+-- mirror's node URL, so the archive can be put together there as well.
+CREATE TABLE mirror_caps
+(
+ grid varchar(256) NOT NULL,
+ cap varchar(256) NOT NULL,
+ mirror_cap varchar(256) NOT NULL,
+ PRIMARY KEY (grid, cap)
+);
@@ -1,7 +1,16 @@
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::result;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::NaiveDateTime;
 use diesel;
-use diesel::{insert_into, select, sql_types};
+use diesel::{insert_into, replace_into, select, sql_types};
 use diesel::prelude::*;
+use diesel::connection::SimpleConnection;
 use diesel::sqlite::SqliteConnection;
+use diesel::r2d2::{self, ConnectionManager, CustomizeConnection, Pool, PooledConnection};
 use diesel::result::Error::DatabaseError;
 use diesel::result::DatabaseErrorKind::UniqueViolation;
 use models::*;
@@ -9,60 +18,328 @@ use errors::*;
 
 embed_migrations!();
 
+fn to_timestamp(time: SystemTime) -> NaiveDateTime {
+    let since_epoch = time.duration_since(UNIX_EPOCH)
+        .unwrap_or_else(|_| Duration::new(0, 0));
+    NaiveDateTime::from_timestamp(since_epoch.as_secs() as i64, since_epoch.subsec_nanos())
+}
+
+fn from_timestamp(timestamp: NaiveDateTime) -> SystemTime {
+    let secs = timestamp.timestamp().max(0) as u64;
+    UNIX_EPOCH + Duration::new(secs, timestamp.timestamp_subsec_nanos())
+}
+
+/// What `BackupDB::prune` removed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PruneStats {
+    pub files: usize,
+    pub caps: usize,
+}
+
+fn uploaded_before_filter(
+    rows: Vec<(String, Option<NaiveDateTime>)>,
+    before: Option<SystemTime>,
+) -> Vec<String> {
+    rows.into_iter()
+        .filter(|&(_, uploaded)| match (before, uploaded) {
+            (Some(before), Some(uploaded)) => from_timestamp(uploaded) < before,
+            _ => true,
+        })
+        .map(|(cap, _)| cap)
+        .collect()
+}
+
+type Manager = ConnectionManager<SqliteConnection>;
+
+/// The pragmas every connection is set up with. The timeout comes first so
+/// switching to WAL waits as well. synchronous=NORMAL is safe with WAL, only
+/// the latest transactions can be lost on power failure.
+///
+/// A `readonly` connection leaves the journal mode as the writer set it, and
+/// has SQLite refuse to write anything.
+fn pragmas(busy_timeout: Duration, readonly: bool) -> Vec<String> {
+    let millis =
+        busy_timeout.as_secs() * 1000 + u64::from(busy_timeout.subsec_millis());
+    let timeout = format!("PRAGMA busy_timeout = {};", millis);
+    if readonly {
+        return vec![timeout, String::from("PRAGMA query_only = ON;")];
+    }
+    vec![
+        timeout,
+        String::from("PRAGMA journal_mode = WAL;"),
+        String::from("PRAGMA synchronous = NORMAL;"),
+    ]
+}
+
+/// Sets up each connection the pool opens like the first one.
+#[derive(Debug)]
+struct Pragmas(Vec<String>);
+
+impl CustomizeConnection<SqliteConnection, r2d2::Error> for Pragmas {
+    fn on_acquire(&self, connection: &mut SqliteConnection) -> result::Result<(), r2d2::Error> {
+        for pragma in &self.0 {
+            connection.batch_execute(pragma).map_err(r2d2::Error::QueryError)?;
+        }
+        Ok(())
+    }
+}
+
 pub struct BackupDB {
-    connection: SqliteConnection,
+    pool: Pool<Manager>,
+    readonly: bool,
 }
 
+/// How long `BackupDB::new` waits for a database another run has locked.
+const DEFAULT_BUSY_TIMEOUT_MS: u64 = 5000;
+
+/// How many connections `BackupDB::new` keeps open.
+const DEFAULT_POOL_SIZE: u32 = 4;
+
 impl BackupDB {
+    /// Opens the database, creating or migrating it as needed, with the
+    /// default busy timeout and pool size.
     pub fn new(database_url: &str) -> Result<BackupDB> {
-        SqliteConnection::establish(database_url)
-            .chain_err(|| ErrorKind::Connection(String::from(database_url)))
-            .and_then(|connection| {
-                embedded_migrations::run(&connection)
-                    .chain_err(|| "Failed to run migrations.")
-                    .map(|_| connection)
-            })
-            .map(|connection| BackupDB { connection })
+        BackupDB::with_options(
+            database_url,
+            Duration::from_millis(DEFAULT_BUSY_TIMEOUT_MS),
+            DEFAULT_POOL_SIZE,
+        )
+    }
+
+    /// Opens the database like `new`. It's put in WAL mode, so a verify or
+    /// prune can read while a backup writes, and a writer that finds it
+    /// locked waits up to `busy_timeout` before failing. Up to `pool_size`
+    /// connections are kept open, each operation taking one for as long as
+    /// it runs.
+    pub fn with_options(database_url: &str, busy_timeout: Duration, pool_size: u32) -> Result<BackupDB> {
+        BackupDB::open(database_url, busy_timeout, pool_size, false)
+    }
+
+    /// Opens an existing database only to read it, for reports that run
+    /// alongside a backup. It's neither created nor migrated, it takes no
+    /// write locks, and everything that would write fails with
+    /// `ErrorKind::ReadOnly`.
+    pub fn open_readonly(database_url: &str, busy_timeout: Duration, pool_size: u32) -> Result<BackupDB> {
+        // SQLite would create a database that isn't there.
+        fs::metadata(database_url).chain_err(|| ErrorKind::Connection(String::from(database_url)))?;
+        BackupDB::open(database_url, busy_timeout, pool_size, true)
+    }
+
+    fn open(database_url: &str, busy_timeout: Duration, pool_size: u32, readonly: bool) -> Result<BackupDB> {
+        // A first connection of its own makes a database that can't be opened
+        // fail right away, and migrates it before the pool shares it out.
+        let connection = SqliteConnection::establish(database_url)
+            .chain_err(|| ErrorKind::Connection(String::from(database_url)))?;
+        let pragmas = pragmas(busy_timeout, readonly);
+        for pragma in &pragmas {
+            connection
+                .batch_execute(pragma)
+                .chain_err(|| ErrorKind::Pragma(pragma.clone()))?;
+        }
+        if !readonly {
+            embedded_migrations::run(&connection).chain_err(|| "Failed to run migrations.")?;
+        }
+        let pool = Pool::builder()
+            .max_size(pool_size.max(1))
+            .connection_customizer(Box::new(Pragmas(pragmas)))
+            .build(ConnectionManager::new(database_url))
+            .chain_err(|| ErrorKind::Connection(String::from(database_url)))?;
+        Ok(BackupDB { pool, readonly })
     }
 
-    pub fn check_dir(&self, hash: i64) -> Option<String> {
+    fn connection(&self) -> Result<PooledConnection<Manager>> {
+        self.pool.get().chain_err(|| "Couldn't get a database connection")
+    }
+
+    /// A connection for something that writes, unless the database was
+    /// opened read-only.
+    fn write_connection(&self) -> Result<PooledConnection<Manager>> {
+        if self.readonly {
+            bail!(ErrorKind::ReadOnly);
+        }
+        self.connection()
+    }
+
+    /// The cap recorded for the directory with hash `hash`, computed the way
+    /// `version` says. With a `max_age`, caps uploaded longer ago than that
+    /// (or at an unknown time) are ignored, so the directory gets uploaded
+    /// again.
+    pub fn check_dir(&self, hash: i64, version: i32, max_age: Option<Duration>) -> Option<String> {
         use schema::directories::dsl::*;
-        directories
+        let connection = self.connection().ok()?;
+        let (cap, uploaded) = directories
             .find(hash)
+            .filter(hash_version.eq(version))
+            .select((dircap, last_uploaded))
+            .first::<(String, Option<NaiveDateTime>)>(&connection)
+            .ok()?;
+        match max_age {
+            None => Some(cap),
+            Some(max_age) => uploaded
+                .map(from_timestamp)
+                .and_then(|time| time.elapsed().ok())
+                .and_then(|age| if age <= max_age { Some(cap) } else { None }),
+        }
+    }
+
+    /// Records `cap` for the directory with hash `hash`, computed the way
+    /// `version` says. If the hash is already known, the newly uploaded cap
+    /// replaces the old one.
+    pub fn add_dir(&self, hash: i64, version: i32, cap: &str) -> Result<()> {
+        use schema::directories::dsl::*;
+        let connection = self.write_connection()?;
+        let now = to_timestamp(SystemTime::now());
+        match insert_into(directories)
+            .values((dirhash.eq(hash), dircap.eq(cap), last_uploaded.eq(now), hash_version.eq(version)))
+            .execute(&connection)
+        {
+            Ok(_) => Ok(()),
+            Err(DatabaseError(UniqueViolation, _)) => diesel::update(directories.find(hash))
+                .set((dircap.eq(cap), last_uploaded.eq(now), hash_version.eq(version)))
+                .execute(&connection)
+                .chain_err(|| "Failed to update dir in db")
+                .map(|_| ()),
+            Err(e) => Err(Error::with_chain(e, "Failed to add dir to db")),
+        }
+    }
+
+    /// The cap of the directory at `dir`, if the run being resumed finished
+    /// it.
+    pub fn journaled_dir(&self, dir: &str) -> Option<String> {
+        use schema::journal::dsl::*;
+        let connection = self.connection().ok()?;
+        journal
+            .find(dir)
             .select(dircap)
-            .first(&self.connection)
+            .first(&connection)
             .ok()
     }
 
-    pub fn add_dir(&self, hash: i64, cap: &str) -> Result<()> {
-        use schema::directories::dsl::*;
-        insert_into(directories)
-            .values((dirhash.eq(hash), dircap.eq(cap)))
-            .execute(&self.connection)
-            .chain_err(|| "Failed to add dir to db")
+    /// Records that the directory at `dir` is done, with cap `cap`, in case
+    /// this run gets interrupted.
+    pub fn journal_dir(&self, dir: &str, cap: &str) -> Result<()> {
+        use schema::journal::dsl::*;
+        let connection = self.write_connection()?;
+        replace_into(journal)
+            .values((path.eq(dir), dircap.eq(cap)))
+            .execute(&connection)
+            .chain_err(|| "Failed to add dir to journal")
+            .map(|_| ())
+    }
+
+    /// Forgets the directories recorded with `journal_dir`, for a run that
+    /// completed or one that starts over.
+    pub fn clear_journal(&self) -> Result<()> {
+        use schema::journal::dsl::*;
+        let connection = self.write_connection()?;
+        diesel::delete(journal)
+            .execute(&connection)
+            .chain_err(|| "Failed to clear journal")
+            .map(|_| ())
+    }
+
+    /// Moves what's in the write-ahead log into the database file. In WAL
+    /// mode with `synchronous = NORMAL`, whatever was committed survives the
+    /// process dying, but only what's been synced to disk survives losing
+    /// power, and the log is synced before every checkpoint. Writers aren't
+    /// waited for, so a busy one may leave some of the log for next time.
+    pub fn checkpoint(&self) -> Result<()> {
+        let connection = self.write_connection()?;
+        connection
+            .batch_execute("PRAGMA wal_checkpoint(PASSIVE);")
+            .chain_err(|| "Failed to checkpoint the database")
+    }
+
+    /// What `primary` was uploaded as to the mirror grid `node_url`, if it was.
+    pub fn mirror_cap(&self, node_url: &str, primary: &str) -> Option<String> {
+        use schema::mirror_caps::dsl::*;
+        let connection = self.connection().ok()?;
+        mirror_caps
+            .find((node_url, primary))
+            .select(mirror_cap)
+            .first(&connection)
+            .ok()
+    }
+
+    /// Records that `primary` is `mirrored` on the mirror grid `node_url`.
+    pub fn add_mirror_cap(&self, node_url: &str, primary: &str, mirrored: &str) -> Result<()> {
+        use schema::mirror_caps::dsl::*;
+        let connection = self.write_connection()?;
+        replace_into(mirror_caps)
+            .values((grid.eq(node_url), cap.eq(primary), mirror_cap.eq(mirrored)))
+            .execute(&connection)
+            .chain_err(|| "Failed to add mirror cap")
             .map(|_| ())
     }
 
+    /// The cap recorded for `path`, if its size and times are still the same.
+    /// Otherwise the file is forgotten. A ctime of 0 is one the file system
+    /// doesn't have, so it isn't compared when either side is 0.
     pub fn check_file(&self, path: &str, size: i64, ctime: i64, mtime: i64) -> Option<String> {
         use schema::local_files::all_columns;
         use schema::local_files::dsl::local_files;
         use schema::caps::dsl::{caps, filecap};
 
+        let connection = self.connection().ok()?;
         local_files
             .find(path)
             .inner_join(caps)
             .select((all_columns, filecap))
-            .first::<(LocalFile, String)>(&self.connection)
+            .first::<(LocalFile, String)>(&connection)
             .ok()
             .and_then(|(file, cap)| {
-                if file.size != size || file.ctime != ctime || file.mtime != mtime {
-                    diesel::delete(&file).execute(&self.connection);
+                let ctime_changed = ctime != 0 && file.ctime != 0 && file.ctime != ctime;
+                if file.size != size || ctime_changed || file.mtime != mtime {
+                    // Forgetting it only tidies up. If that fails, the stale
+                    // record is simply found not to match again next time.
+                    if !self.readonly {
+                        diesel::delete(&file).execute(&connection).ok();
+                    }
                     return None;
                 }
                 Some(cap)
             })
     }
 
+    /// The cap recorded for `path`, if its size and mtime are still the same.
+    /// Unlike `check_file` the ctime isn't compared, and a mismatch doesn't
+    /// forget the file.
+    pub fn known_cap(&self, path: &str, size: i64, mtime: i64) -> Option<String> {
+        use schema::local_files::all_columns;
+        use schema::local_files::dsl::local_files;
+        use schema::caps::dsl::{caps, filecap};
+
+        let connection = self.connection().ok()?;
+        local_files
+            .find(path)
+            .inner_join(caps)
+            .select((all_columns, filecap))
+            .first::<(LocalFile, String)>(&connection)
+            .ok()
+            .and_then(|(file, cap)| {
+                if file.size != size || file.mtime != mtime {
+                    return None;
+                }
+                Some(cap)
+            })
+    }
+
+    /// Looks for a file with the same contents recorded under any path, by the
+    /// hash passed to `add_file`.
+    pub fn check_content(&self, hash: &str, size: i64) -> Option<String> {
+        use schema::local_files::dsl::{contenthash, local_files, size as filesize};
+        use schema::caps::dsl::{caps, filecap};
+
+        let connection = self.connection().ok()?;
+        local_files
+            .filter(contenthash.eq(hash))
+            .filter(filesize.eq(size))
+            .inner_join(caps)
+            .select(filecap)
+            .first(&connection)
+            .ok()
+    }
+
     pub fn add_file(
         &self,
         cap: &str,
@@ -70,39 +347,350 @@ impl BackupDB {
         size: i64,
         ctime: i64,
         mtime: i64,
+        contenthash: Option<String>,
     ) -> Result<()> {
         use schema::caps::dsl::fileid as capid;
         use schema::caps::dsl::{caps, filecap};
-        use schema::last_upload::dsl::{fileid, last_upload};
+        use schema::last_upload::dsl::{fileid, last_upload, last_uploaded};
         use schema::local_files::dsl::local_files;
         no_arg_sql_function!(last_insert_rowid, sql_types::Integer, "last_insert_rowid");
 
-        let id = match insert_into(caps)
-            .values(filecap.eq(cap))
-            .execute(&self.connection)
+        let connection = self.write_connection()?;
+        connection.transaction(|| {
+            let id = match insert_into(caps)
+                .values(filecap.eq(cap))
+                .execute(&connection)
+            {
+                Ok(_) => select(last_insert_rowid).first(&connection)?,
+                Err(DatabaseError(UniqueViolation, _)) => caps.filter(filecap.eq(cap))
+                    .select(capid)
+                    .first(&connection)?,
+                Err(e) => return Err(Error::with_chain(e, "Failed to insert cap")),
+            };
+            diesel::delete(last_upload.find(id)).execute(&connection)?;
+            insert_into(last_upload)
+                .values((fileid.eq(id), last_uploaded.eq(to_timestamp(SystemTime::now()))))
+                .execute(&connection)
+                .chain_err(|| "Failed to insert last upload")?;
+            diesel::delete(local_files.find(&path)).execute(&connection)?;
+            insert_into(local_files)
+                .values(&LocalFile {
+                    fileid: id,
+                    path,
+                    size,
+                    ctime,
+                    mtime,
+                    contenthash,
+                })
+                .execute(&connection)
+                .chain_err(|| "Failed to insert local file")?;
+            Ok(())
+        })
+    }
+
+    /// How the contents of the file `cap` were compressed, if they were.
+    pub fn cap_compression(&self, cap: &str) -> Option<String> {
+        use schema::caps::dsl::{caps, compression, filecap};
+
+        let connection = self.connection().ok()?;
+        caps.filter(filecap.eq(cap))
+            .select(compression)
+            .first::<Option<String>>(&connection)
+            .ok()
+            .and_then(|method| method)
+    }
+
+    /// Records that the contents of `cap` were compressed with `method`,
+    /// whether or not it's recorded for any file.
+    pub fn set_compression(&self, cap: &str, method: &str) -> Result<()> {
+        use schema::caps::dsl::{caps, compression, filecap};
+
+        let connection = self.write_connection()?;
+        match insert_into(caps)
+            .values((filecap.eq(cap), compression.eq(method)))
+            .execute(&connection)
         {
-            Ok(_) => select(last_insert_rowid).first(&self.connection)?,
-            Err(DatabaseError(UniqueViolation, _)) => caps.filter(filecap.eq(cap))
-                .select(capid)
-                .first(&self.connection)?,
-            Err(e) => return Err(Error::with_chain(e, "Failed to insert cap")),
-        };
-        diesel::delete(last_upload.find(fileid)).execute(&self.connection);
-        insert_into(last_upload)
-            .values(fileid.eq(id))
-            .execute(&self.connection)
-            .chain_err(|| "Failed to insert last upload")?;
-        diesel::delete(local_files.find(&path)).execute(&self.connection);
-        insert_into(local_files)
-            .values(&LocalFile {
-                fileid: id,
-                path,
-                size,
-                ctime,
-                mtime,
-            })
-            .execute(&self.connection)
-            .chain_err(|| "Failed to insert local file")?;
+            Ok(_) => Ok(()),
+            Err(DatabaseError(UniqueViolation, _)) => diesel::update(caps.filter(filecap.eq(cap)))
+                .set(compression.eq(method))
+                .execute(&connection)
+                .chain_err(|| "Failed to set compression")
+                .map(|_| ()),
+            Err(e) => Err(Error::with_chain(e, "Failed to insert cap")),
+        }
+    }
+
+    /// Everything recorded for `path`, without checking it against the file.
+    pub fn lookup_file(&self, path: &str) -> Option<FileRecord> {
+        use schema::caps::dsl::{caps, compression, filecap};
+        use schema::last_upload::dsl::{last_upload, last_uploaded};
+        use schema::local_files::all_columns;
+        use schema::local_files::dsl::local_files;
+
+        let connection = self.connection().ok()?;
+        let (file, cap, method) = local_files
+            .find(path)
+            .inner_join(caps)
+            .select((all_columns, filecap, compression))
+            .first::<(LocalFile, String, Option<String>)>(&connection)
+            .ok()?;
+        let uploaded = last_upload
+            .find(file.fileid)
+            .select(last_uploaded)
+            .first::<Option<NaiveDateTime>>(&connection)
+            .ok()
+            .and_then(|time| time);
+        Some(FileRecord {
+            file,
+            filecap: cap,
+            compression: method,
+            last_uploaded: uploaded,
+        })
+    }
+
+    /// When the file currently recorded for `path` was last uploaded.
+    pub fn last_backup_time(&self, path: &str) -> Option<SystemTime> {
+        use schema::last_upload::dsl::{last_upload, last_uploaded};
+        use schema::local_files::dsl::{fileid, local_files};
+
+        let connection = self.connection().ok()?;
+        let id: i32 = local_files
+            .find(path)
+            .select(fileid)
+            .first(&connection)
+            .ok()?;
+        last_upload
+            .find(id)
+            .select(last_uploaded)
+            .first::<Option<NaiveDateTime>>(&connection)
+            .ok()
+            .and_then(|time| time)
+            .map(from_timestamp)
+    }
+
+    /// When the most recent file upload happened.
+    pub fn latest_upload(&self) -> Option<SystemTime> {
+        use diesel::dsl::max;
+        use schema::last_upload::dsl::{last_upload, last_uploaded};
+
+        let connection = self.connection().ok()?;
+        last_upload
+            .select(max(last_uploaded))
+            .first::<Option<NaiveDateTime>>(&connection)
+            .ok()
+            .and_then(|time| time)
+            .map(from_timestamp)
+    }
+
+    /// Every file cap recorded. With `uploaded_before`, only those last
+    /// uploaded before that time, or at an unknown time.
+    pub fn file_caps(&self, uploaded_before: Option<SystemTime>) -> Result<Vec<String>> {
+        use schema::caps::dsl::{caps, filecap};
+        use schema::last_upload::dsl::{last_upload, last_uploaded};
+        let connection = self.connection()?;
+        let rows = caps.left_join(last_upload)
+            .select((filecap, last_uploaded.nullable()))
+            .load::<(String, Option<NaiveDateTime>)>(&connection)
+            .chain_err(|| "Failed to load file caps")?;
+        Ok(uploaded_before_filter(rows, uploaded_before))
+    }
+
+    /// Every directory cap recorded, filtered like `file_caps`.
+    pub fn dir_caps(&self, uploaded_before: Option<SystemTime>) -> Result<Vec<String>> {
+        use schema::directories::dsl::{dircap, directories, last_uploaded};
+        let connection = self.connection()?;
+        let rows = directories
+            .select((dircap, last_uploaded))
+            .load::<(String, Option<NaiveDateTime>)>(&connection)
+            .chain_err(|| "Failed to load directory caps")?;
+        Ok(uploaded_before_filter(rows, uploaded_before))
+    }
+
+    /// Records a backup run whose archive was attached at `timestamp`.
+    pub fn record_run(
+        &self,
+        timestamp: NaiveDateTime,
+        cap: &str,
+        files: i64,
+        bytes: i64,
+        meta: &RunMeta,
+    ) -> Result<()> {
+        use schema::backups::dsl::{backups, byte_count, file_count, node_url, root_cap, shares_happy,
+                                   shares_needed, shares_total};
+        use schema::backups::dsl::timestamp as run_timestamp;
+
+        let connection = self.write_connection()?;
+        insert_into(backups)
+            .values((
+                run_timestamp.eq(timestamp),
+                root_cap.eq(cap),
+                file_count.eq(files),
+                byte_count.eq(bytes),
+                node_url.eq(&meta.node_url),
+                shares_needed.eq(meta.shares_needed),
+                shares_happy.eq(meta.shares_happy),
+                shares_total.eq(meta.shares_total),
+            ))
+            .execute(&connection)
+            .chain_err(|| "Failed to record backup run")?;
         Ok(())
     }
+
+    /// Every recorded backup run, oldest first.
+    pub fn list_runs(&self) -> Result<Vec<BackupRun>> {
+        use schema::backups::dsl::{backups, id};
+
+        let connection = self.connection()?;
+        backups
+            .order(id)
+            .load(&connection)
+            .chain_err(|| "Failed to load backup runs")
+    }
+
+    /// The latest recorded run whose archive is `cap`.
+    pub fn find_run(&self, cap: &str) -> Option<BackupRun> {
+        use schema::backups::dsl::{backups, id, root_cap};
+
+        let connection = self.connection().ok()?;
+        backups
+            .filter(root_cap.eq(cap))
+            .order(id.desc())
+            .first(&connection)
+            .ok()
+    }
+
+    /// Forgets about local files that no longer exist. `local_path` turns a
+    /// stored path back into the one on disk. With `collect_caps`, caps no
+    /// local file refers to anymore are dropped as well.
+    pub fn prune<F>(&self, local_path: F, collect_caps: bool) -> Result<PruneStats>
+    where
+        F: Fn(&str) -> PathBuf,
+    {
+        use schema::caps::dsl::{caps, fileid as capid};
+        use schema::last_upload::dsl::{fileid as uploadid, last_upload};
+        use schema::local_files::dsl::{fileid, local_files, path};
+
+        let connection = self.write_connection()?;
+        connection.transaction(|| {
+            let mut stats = PruneStats::default();
+            let paths: Vec<String> = local_files.select(path).load(&connection)?;
+            for file in paths {
+                match fs::symlink_metadata(local_path(&file)) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                        stats.files += diesel::delete(local_files.find(&file))
+                            .execute(&connection)?;
+                    }
+                    _ => {}
+                }
+            }
+            if collect_caps {
+                diesel::delete(last_upload.filter(uploadid.ne_any(local_files.select(fileid))))
+                    .execute(&connection)?;
+                stats.caps += diesel::delete(caps.filter(capid.ne_any(local_files.select(fileid))))
+                    .execute(&connection)?;
+            }
+            Ok(stats)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A migrated database that lives in memory. It needs a pool of one
+    /// connection, as every connection to `:memory:` gets its own database.
+    fn memory_db() -> BackupDB {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(ConnectionManager::new(":memory:"))
+            .unwrap();
+        embedded_migrations::run(&*pool.get().unwrap()).unwrap();
+        BackupDB { pool, readonly: false }
+    }
+
+    fn count_rows(db: &BackupDB, table: &str) -> i64 {
+        use diesel::dsl::sql;
+        let connection = db.connection().unwrap();
+        select(sql::<sql_types::BigInt>(&format!("(SELECT COUNT(*) FROM {})", table)))
+            .first(&*connection)
+            .unwrap()
+    }
+
+    #[test]
+    fn add_file_is_undone_when_an_insert_fails() {
+        let db = memory_db();
+        db.connection()
+            .unwrap()
+            .batch_execute(
+                "CREATE TRIGGER fail_local_files BEFORE INSERT ON local_files \
+                 BEGIN SELECT RAISE(ABORT, 'no local files'); END;",
+            )
+            .unwrap();
+        let res = db.add_file("URI:CHK:abc", String::from("/a/file"), 10, 1, 2, None);
+        assert!(res.is_err());
+        assert_eq!(count_rows(&db, "caps"), 0);
+        assert_eq!(count_rows(&db, "last_upload"), 0);
+        assert_eq!(count_rows(&db, "local_files"), 0);
+    }
+
+    #[test]
+    fn add_file_records_all_three_tables() {
+        let db = memory_db();
+        db.add_file("URI:CHK:abc", String::from("/a/file"), 10, 1, 2, None).unwrap();
+        assert_eq!(count_rows(&db, "caps"), 1);
+        assert_eq!(count_rows(&db, "last_upload"), 1);
+        assert_eq!(db.check_file("/a/file", 10, 1, 2), Some(String::from("URI:CHK:abc")));
+    }
+
+    #[test]
+    fn check_dir_finds_a_first_insert() {
+        let db = memory_db();
+        assert_eq!(db.check_dir(42, 2, None), None);
+        db.add_dir(42, 2, "URI:DIR2-CHK:one").unwrap();
+        assert_eq!(db.check_dir(42, 2, None), Some(String::from("URI:DIR2-CHK:one")));
+        // Hashed another way, it's another directory.
+        assert_eq!(db.check_dir(42, 1, None), None);
+    }
+
+    #[test]
+    fn check_dir_reuses_a_recent_cap() {
+        let db = memory_db();
+        db.add_dir(42, 2, "URI:DIR2-CHK:one").unwrap();
+        let day = Duration::from_secs(24 * 60 * 60);
+        assert_eq!(db.check_dir(42, 2, Some(day)), Some(String::from("URI:DIR2-CHK:one")));
+        assert_eq!(db.check_dir(42, 2, None), Some(String::from("URI:DIR2-CHK:one")));
+    }
+
+    #[test]
+    fn add_dir_reuses_the_row_of_the_same_cap() {
+        let db = memory_db();
+        db.add_dir(42, 2, "URI:DIR2-CHK:one").unwrap();
+        db.add_dir(42, 2, "URI:DIR2-CHK:one").unwrap();
+        assert_eq!(db.check_dir(42, 2, None), Some(String::from("URI:DIR2-CHK:one")));
+        assert_eq!(count_rows(&db, "directories"), 1);
+    }
+
+    #[test]
+    fn add_dir_replaces_the_cap_of_a_known_hash() {
+        let db = memory_db();
+        db.add_dir(42, 2, "URI:DIR2-CHK:one").unwrap();
+        db.add_dir(42, 2, "URI:DIR2-CHK:two").unwrap();
+        assert_eq!(db.check_dir(42, 2, None), Some(String::from("URI:DIR2-CHK:two")));
+        assert_eq!(count_rows(&db, "directories"), 1);
+    }
+
+    #[test]
+    fn new_opens_a_file_with_the_defaults() {
+        let path = ::std::env::temp_dir().join(format!("backupdb-test-{}.sqlite", ::std::process::id()));
+        fs::remove_file(&path).ok();
+        {
+            let db = BackupDB::new(path.to_str().unwrap()).unwrap();
+            db.add_dir(1, 1, "URI:DIR2-CHK:abc").unwrap();
+            assert_eq!(db.check_dir(1, 1, None), Some(String::from("URI:DIR2-CHK:abc")));
+        }
+        for suffix in &["", "-wal", "-shm"] {
+            fs::remove_file(format!("{}{}", path.display(), suffix)).ok();
+        }
+    }
 }
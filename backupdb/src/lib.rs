@@ -6,6 +6,7 @@ extern crate diesel;
 #[macro_use]
 extern crate diesel_migrations;
 
+extern crate chrono;
 extern crate dotenv;
 
 #[macro_use]
@@ -27,6 +28,14 @@ pub mod errors {
                 description("Failed to connect to database"),
                 display("Failed to connect to database: '{}'", url),
             }
+            Pragma(pragma: String) {
+                description("Failed to set up database connection"),
+                display("Failed to set up database connection: '{}'", pragma),
+            }
+            ReadOnly {
+                description("The database is open read-only"),
+                display("The database is open read-only, it can't be written to"),
+            }
         }
     }
 }
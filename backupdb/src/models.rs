@@ -1,12 +1,50 @@
-use std::time::SystemTime;
+use chrono::NaiveDateTime;
 
 use schema::*;
 
+/// A completed backup run.
+#[derive(Queryable)]
+#[primary_key(id)]
+pub struct BackupRun {
+    pub id: i32,
+    pub timestamp: NaiveDateTime,
+    pub root_cap: String,
+    pub file_count: i64,
+    pub byte_count: i64,
+    /// Missing for runs recorded before these were kept.
+    pub node_url: Option<String>,
+    pub shares_needed: Option<i32>,
+    pub shares_happy: Option<i32>,
+    pub shares_total: Option<i32>,
+}
+
+impl BackupRun {
+    /// Which grid the run went to and how, if that was recorded.
+    pub fn meta(&self) -> Option<RunMeta> {
+        Some(RunMeta {
+            node_url: self.node_url.clone()?,
+            shares_needed: self.shares_needed,
+            shares_happy: self.shares_happy,
+            shares_total: self.shares_total,
+        })
+    }
+}
+
+/// The node a backup run was uploaded through and the encoding it asked for,
+/// `None` being the node's default.
+pub struct RunMeta {
+    pub node_url: String,
+    pub shares_needed: Option<i32>,
+    pub shares_happy: Option<i32>,
+    pub shares_total: Option<i32>,
+}
+
 #[derive(Queryable)]
 #[primary_key(fileid)]
 pub struct Cap {
     pub fileid: i32,
     pub filecap: String,
+    pub compression: Option<String>,
 }
 
 #[derive(Insertable)]
@@ -15,7 +53,7 @@ pub struct Cap {
 pub struct Directory {
     pub dirhash: i64,
     pub dircap: String,
-    pub last_uploaded: SystemTime,
+    pub last_uploaded: Option<NaiveDateTime>,
 }
 
 #[derive(Queryable, Insertable)]
@@ -23,7 +61,7 @@ pub struct Directory {
 #[primary_key(fileid)]
 pub struct LastUpload {
     pub fileid: i32,
-    pub last_uploaded: SystemTime,
+    pub last_uploaded: Option<NaiveDateTime>,
 }
 
 #[derive(Queryable, Identifiable, Insertable)]
@@ -35,6 +73,16 @@ pub struct LocalFile {
     pub mtime: i64,
     pub ctime: i64,
     pub fileid: i32,
+    pub contenthash: Option<String>,
+}
+
+/// Everything recorded for a local file: its row, the cap it was uploaded
+/// as, and when that was.
+pub struct FileRecord {
+    pub file: LocalFile,
+    pub filecap: String,
+    pub compression: Option<String>,
+    pub last_uploaded: Option<NaiveDateTime>,
 }
 
 #[derive(Queryable)]
@@ -1,7 +1,22 @@
+table! {
+    backups (id) {
+        id -> Integer,
+        timestamp -> Timestamp,
+        root_cap -> Text,
+        file_count -> BigInt,
+        byte_count -> BigInt,
+        node_url -> Nullable<Text>,
+        shares_needed -> Nullable<Integer>,
+        shares_happy -> Nullable<Integer>,
+        shares_total -> Nullable<Integer>,
+    }
+}
+
 table! {
     caps (fileid) {
         fileid -> Integer,
         filecap -> Text,
+        compression -> Nullable<Text>,
     }
 }
 
@@ -10,6 +25,14 @@ table! {
         dirhash -> BigInt,
         dircap -> Text,
         last_uploaded -> Nullable<Timestamp>,
+        hash_version -> Integer,
+    }
+}
+
+table! {
+    journal (path) {
+        path -> Text,
+        dircap -> Text,
     }
 }
 
@@ -27,6 +50,15 @@ table! {
         mtime -> BigInt,
         ctime -> BigInt,
         fileid -> Integer,
+        contenthash -> Nullable<Text>,
+    }
+}
+
+table! {
+    mirror_caps (grid, cap) {
+        grid -> Text,
+        cap -> Text,
+        mirror_cap -> Text,
     }
 }
 
@@ -40,4 +72,4 @@ table! {
 joinable!(last_upload -> caps (fileid));
 joinable!(local_files -> caps (fileid));
 
-allow_tables_to_appear_in_same_query!(caps, directories, last_upload, local_files, version,);
+allow_tables_to_appear_in_same_query!(backups, caps, directories, journal, last_upload, local_files, mirror_caps, version,);
@@ -0,0 +1,59 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Read};
+use std::path::Path;
+
+use errors::*;
+
+/// Whether `target` is a cap, maybe with a path below it, rather than an
+/// alias.
+pub fn is_cap(target: &str) -> bool {
+    target.starts_with("URI:")
+}
+
+/// The caps in the Tahoe CLI's aliases file at `path`, which has a
+/// `name: cap` line for each alias. A missing file has none.
+fn load(path: &Path) -> Result<HashMap<String, String>> {
+    let logpath = path.display().to_string();
+    let mut contents = String::new();
+    match fs::File::open(path) {
+        Ok(mut f) => {
+            f.read_to_string(&mut contents).chain_err(|| ErrorKind::AliasesRead(logpath))?;
+        }
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(HashMap::new()),
+        Err(e) => return Err(Error::with_chain(e, ErrorKind::AliasesRead(logpath))),
+    }
+    Ok(contents
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, ':');
+            let name = parts.next()?.trim();
+            let cap = parts.next()?.trim();
+            Some((name.to_owned(), cap.to_owned()))
+        })
+        .collect())
+}
+
+/// The cap `target` stands for: itself if it's a cap, or else the cap of
+/// the alias it names in the aliases file at `path`. Like the Tahoe CLI
+/// takes them, `name:` is the aliased directory itself and `name:some/path`
+/// is below it. A bare `name` works too.
+pub fn resolve(path: &Path, target: &str) -> Result<String> {
+    if is_cap(target) {
+        return Ok(target.to_owned());
+    }
+    let mut parts = target.splitn(2, ':');
+    let name = parts.next().unwrap();
+    let below = parts.next().unwrap_or("").trim_matches('/');
+    let aliases = load(path)?;
+    let cap = aliases
+        .get(name)
+        .ok_or_else(|| ErrorKind::UnknownAlias(name.to_owned(), path.display().to_string()))?;
+    if below.is_empty() {
+        Ok(cap.clone())
+    } else {
+        Ok(format!("{}/{}", cap, below))
+    }
+}
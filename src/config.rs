@@ -0,0 +1,48 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+
+use toml;
+
+use errors::*;
+
+/// Defaults for routine backups, from `config.toml`. The command line and
+/// the environment take precedence over anything set here.
+#[derive(Deserialize, Default)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct Config {
+    pub database: Option<PathBuf>,
+    pub node_url: Option<String>,
+    pub upload_url: Option<String>,
+    pub threads: Option<usize>,
+    /// Globs to exclude when none are given with `--exclude`.
+    pub exclude: Option<Vec<String>>,
+}
+
+/// `$XDG_CONFIG_HOME/tahoe-backup/config.toml`, which defaults to
+/// `~/.config/tahoe-backup/config.toml`.
+pub fn default_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(ref dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::home_dir()?.join(".config"),
+    };
+    Some(base.join("tahoe-backup").join("config.toml"))
+}
+
+impl Config {
+    /// Reads the config at `path`, which is an empty one if there's no such
+    /// file.
+    pub fn load(path: &Path) -> Result<Config> {
+        let logpath = path.display().to_string();
+        let mut contents = String::new();
+        match fs::File::open(path) {
+            Ok(mut f) => {
+                f.read_to_string(&mut contents).chain_err(|| ErrorKind::ConfigRead(logpath.clone()))?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(Error::with_chain(e, ErrorKind::ConfigRead(logpath))),
+        }
+        toml::from_str(&contents).chain_err(|| ErrorKind::ConfigRead(logpath))
+    }
+}
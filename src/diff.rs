@@ -0,0 +1,188 @@
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use futures::{future, stream, Future, IntoFuture, Stream};
+
+use filetime::FileTime;
+
+use tahoe::client::{ChildInfo, NodeType, Tahoe};
+
+use errors::*;
+
+use failures::Failures;
+
+use restore::{is_compressed, local_name, symlink_target};
+
+#[derive(Serialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    /// Only on disk.
+    Added,
+    /// Only in the archive.
+    Removed,
+    Modified,
+}
+
+impl Kind {
+    /// How a change of this kind is marked in a listing.
+    pub fn prefix(&self) -> char {
+        match *self {
+            Kind::Added => '+',
+            Kind::Removed => '-',
+            Kind::Modified => 'M',
+        }
+    }
+}
+
+/// A difference between an archive and the local folder it's compared with.
+#[derive(Serialize)]
+pub struct Change {
+    pub kind: Kind,
+    /// Relative to the compared folder.
+    pub path: String,
+    /// What differs about a modified entry: `type`, `size`, `mtime` or
+    /// `target`.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub differs: Vec<&'static str>,
+}
+
+impl Change {
+    fn new(kind: Kind, rel: &Path) -> Self {
+        Change {
+            kind,
+            path: rel.to_string_lossy().into_owned(),
+            differs: Vec::new(),
+        }
+    }
+}
+
+/// How the local entry at `path` differs from the archived `child`, if both
+/// are files or symlinks. Directories are compared by their contents.
+fn differences(child: &ChildInfo, path: &Path, metadata: &fs::Metadata) -> Vec<&'static str> {
+    let target = symlink_target(child);
+    let file_type = metadata.file_type();
+    if metadata.is_dir() != (child.nodetype == NodeType::Dir) || file_type.is_symlink() != target.is_some() {
+        return vec!["type"];
+    }
+    // Restore doesn't set anything but the target of a symlink.
+    if let Some(target) = target {
+        return match fs::read_link(path) {
+            Ok(ref local) if *local == target => Vec::new(),
+            _ => vec!["target"],
+        };
+    }
+    let mut differs = Vec::new();
+    // The grid only knows the compressed size of a compressed file.
+    if !is_compressed(child) && child.size.is_some_and(|size| size != metadata.len()) {
+        differs.push("size");
+    }
+    let mtime = FileTime::from_last_modification_time(metadata).seconds();
+    if child.time("mtime").is_some_and(|recorded| recorded != mtime) {
+        differs.push("mtime");
+    }
+    differs
+}
+
+type Entries = BTreeMap<OsString, (Option<ChildInfo>, Option<fs::Metadata>)>;
+
+/// The children of the archived directory and of the local folder, by local
+/// name.
+fn entries(failures: &Failures, children: Vec<(String, ChildInfo)>, path: &Path) -> Result<Entries> {
+    let mut entries = Entries::new();
+    for (name, child) in children {
+        match local_name(&name, &child) {
+            Some(local) => entries.entry(local).or_insert((None, None)).0 = Some(child),
+            None => {
+                let error = ErrorKind::UnsafeName(name.clone()).into();
+                failures.log_and_record(&path.join(&name), error)
+            }
+        }
+    }
+    let dir = fs::read_dir(path).chain_err(|| format!("Couldn't read dir '{}'", path.display()))?;
+    for entry in dir {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                failures.log_and_record(path, e.into());
+                continue;
+            }
+        };
+        match fs::symlink_metadata(entry.path()) {
+            Ok(metadata) => entries.entry(entry.file_name()).or_insert((None, None)).1 = Some(metadata),
+            Err(e) => {
+                let err = Error::with_chain(e, ErrorKind::ReadMetadata(entry.path().display().to_string()));
+                failures.log_and_record(&entry.path(), err);
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Compares an entry that's both in the archive and on disk, going into it
+/// if it's a directory on both sides.
+fn compare<'a>(
+    client: &'a Tahoe,
+    failures: &'a Failures,
+    child: ChildInfo,
+    metadata: fs::Metadata,
+    path: PathBuf,
+    rel: PathBuf,
+) -> Box<Future<Item = Vec<Change>, Error = Error> + 'a> {
+    match child.uri() {
+        Some(cap) if child.nodetype == NodeType::Dir && metadata.is_dir() => {
+            let failpath = path.clone();
+            Box::new(diff(client, failures, cap, path, rel).or_else(move |e| {
+                failures.log_and_record(&failpath, e);
+                Ok(Vec::new())
+            }))
+        }
+        _ => {
+            let differs = differences(&child, &path, &metadata);
+            if differs.is_empty() {
+                Box::new(future::ok(Vec::new()))
+            } else {
+                Box::new(future::ok(vec![Change {
+                    differs,
+                    ..Change::new(Kind::Modified, &rel)
+                }]))
+            }
+        }
+    }
+}
+
+/// Compares the archived directory `cap` with the local folder `path`,
+/// `rel` being where it is below the folder the comparison started from.
+/// Anything below that can't be read on either side is recorded in
+/// `failures` and left out.
+pub fn diff<'a>(
+    client: &'a Tahoe,
+    failures: &'a Failures,
+    cap: &str,
+    path: PathBuf,
+    rel: PathBuf,
+) -> Box<Future<Item = Vec<Change>, Error = Error> + 'a> {
+    Box::new(
+        client
+            .read_dir(cap)
+            .into_future()
+            .flatten()
+            .map_err(|e| Error::with_chain(e, "couldn't read dir"))
+            .and_then(move |children| {
+                let entries = entries(failures, children, &path)?;
+                let changes = entries.into_iter().map(move |(name, entry)| {
+                    let rel = rel.join(&name);
+                    match entry {
+                        (Some(child), Some(metadata)) => {
+                            compare(client, failures, child, metadata, path.join(&name), rel)
+                        }
+                        (Some(_), None) => Box::new(future::ok(vec![Change::new(Kind::Removed, &rel)])),
+                        (None, _) => Box::new(future::ok(vec![Change::new(Kind::Added, &rel)])),
+                    }
+                });
+                Ok(stream::iter_ok(changes).buffered(client.concurrency()).concat2())
+            })
+            .flatten(),
+    )
+}
@@ -0,0 +1,94 @@
+use console::Term;
+
+use unicode_width::UnicodeWidthChar;
+
+/// Put in place of what's left out.
+const ELLIPSIS: &str = "...";
+
+/// Never leave less than this for a message, so a narrow terminal still
+/// shows something of the path.
+const MIN_WIDTH: usize = 16;
+
+/// The columns `c` takes up in a terminal. Control characters are taken as
+/// taking none.
+fn char_width(c: char) -> usize {
+    c.width().unwrap_or(0)
+}
+
+fn width(s: &str) -> usize {
+    s.chars().map(char_width).sum()
+}
+
+/// The end of `s` that fits in `columns`, without splitting a character.
+fn tail(s: &str, columns: usize) -> &str {
+    let mut used = 0;
+    for (i, c) in s.char_indices().rev() {
+        used += char_width(c);
+        if used > columns {
+            return &s[i + c.len_utf8()..];
+        }
+    }
+    s
+}
+
+/// `path` shortened to at most `columns`, by putting `...` in place of whole
+/// components in the middle: `/home/.../project/src/file.rs`. The first
+/// component and as many of the last as fit are kept. When even the last
+/// one doesn't fit next to the first, only the end of the path is shown.
+pub fn elide(path: &str, columns: usize) -> String {
+    if width(path) <= columns {
+        return path.to_owned();
+    }
+    let components: Vec<&str> = path.split('/').collect();
+    // With a leading slash, the first component is empty.
+    let head_len = if components[0].is_empty() { 2 } else { 1 };
+    if components.len() > head_len {
+        let head = components[..head_len].join("/");
+        let mut used = width(&head) + 1 + width(ELLIPSIS);
+        let mut start = components.len();
+        while start > head_len && used + 1 + width(components[start - 1]) <= columns {
+            start -= 1;
+            used += 1 + width(components[start]);
+        }
+        if start < components.len() {
+            return format!("{}/{}/{}", head, ELLIPSIS, components[start..].join("/"));
+        }
+    }
+    let rest = columns.saturating_sub(width(ELLIPSIS));
+    format!("{}{}", ELLIPSIS, tail(path, rest))
+}
+
+/// `path` shortened to fit the message of a progress bar, next to the
+/// `reserved` columns the rest of its template takes up.
+pub fn bar_message(path: &str, reserved: usize) -> String {
+    let (_, columns) = Term::stderr().size();
+    let columns = (columns as usize).saturating_sub(reserved).max(MIN_WIDTH);
+    elide(path, columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn short_paths_are_kept() {
+        assert_eq!(elide("/home/user/file.rs", 18), "/home/user/file.rs");
+    }
+
+    #[test]
+    fn middle_components_go_first() {
+        assert_eq!(elide("/home/user/project/src/file.rs", 25), "/home/.../src/file.rs");
+        assert_eq!(elide("home/user/project/src/file.rs", 24), "home/.../src/file.rs");
+    }
+
+    #[test]
+    fn a_long_last_component_keeps_its_end() {
+        assert_eq!(elide("/home/a-very-long-file-name.rs", 12), "...e-name.rs");
+    }
+
+    #[test]
+    fn wide_characters_count_double() {
+        // Each of these takes two columns.
+        assert_eq!(elide("/家/家家家家", 9), "...家家家");
+    }
+}
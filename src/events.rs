@@ -0,0 +1,24 @@
+use std::path::Path;
+use std::sync::Arc;
+
+use errors::Error;
+
+/// Something that happened to an entry during a backup. A dry run reports
+/// nothing but skips and failures, since it doesn't upload anything.
+pub enum Event<'a> {
+    /// The file or symlink at `path` is about to be sent to the grid.
+    Started { path: &'a Path, size: u64 },
+    /// Another `bytes` of the file at `path` were sent. Symlinks go in one
+    /// piece, without any.
+    Progress { path: &'a Path, bytes: u64 },
+    /// The file or symlink at `path` is on the grid as `cap`.
+    Finished { path: &'a Path, cap: &'a str },
+    /// The file or symlink at `path` didn't need to be uploaded again.
+    Skipped { path: &'a Path },
+    /// The entry at `path` couldn't be backed up and is left out.
+    Failed { path: &'a Path, error: &'a Error },
+}
+
+/// Gets every `Event` of a backup. `Progress` comes from the upload threads,
+/// everything else from the one running the backup.
+pub type EventSink = Arc<Fn(&Event) + Send + Sync>;
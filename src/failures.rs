@@ -0,0 +1,39 @@
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+
+use errors::*;
+
+use log_chain;
+
+/// The files and directories that couldn't be backed up or restored, and
+/// why. They're left out of the result, so any at all mean it's incomplete.
+#[derive(Default)]
+pub struct Failures(RefCell<Vec<(PathBuf, Error)>>);
+
+impl Failures {
+    pub fn record(&self, path: &Path, error: Error) {
+        self.0.borrow_mut().push((path.to_owned(), error));
+    }
+
+    /// Logs `error` and keeps it as a failure of `path`.
+    pub fn log_and_record(&self, path: &Path, error: Error) {
+        log_chain(&error);
+        self.record(path, error);
+    }
+
+    /// One line per failure: the path, then the error chain.
+    pub fn lines(&self) -> Vec<String> {
+        self.0
+            .borrow()
+            .iter()
+            .map(|(path, error)| {
+                let chain: Vec<String> = error.iter().map(|e| e.to_string()).collect();
+                format!("'{}': {}", path.display(), chain.join(": "))
+            })
+            .collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+}
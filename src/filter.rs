@@ -0,0 +1,182 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::path::Path;
+
+use globset::GlobSet;
+
+use gitignore::Gitignores;
+
+/// Decides which entries of the tree get backed up.
+#[derive(Clone, Default)]
+pub struct Filter {
+    pub excludes: Option<GlobSet>,
+    /// If set, only files matching one of these are backed up, whether or not
+    /// they or a directory above them are also excluded.
+    pub includes: Option<GlobSet>,
+    /// With `--one-file-system`, the device the backup root is on. Entries on
+    /// any other device are skipped.
+    pub device: Option<u64>,
+    /// With `--exclude-fstype`, the devices of the mounts of the excluded
+    /// types, and those types. Entries on them are skipped.
+    pub excluded_mounts: HashMap<u64, String>,
+    /// Files smaller than this are skipped.
+    pub min_size: Option<u64>,
+    /// Files larger than this are skipped.
+    pub max_size: Option<u64>,
+    /// How many levels of directories below the backup root are gone into.
+    /// Deeper ones are skipped.
+    pub max_depth: Option<usize>,
+    /// With `--exclude-caches`, only the tag of a directory tagged with
+    /// `CACHEDIR.TAG` is backed up.
+    pub exclude_caches: bool,
+    /// With `--respect-gitignore`, what `.gitignore` files ignore is skipped
+    /// as well.
+    pub gitignore: bool,
+}
+
+/// The file that marks a cache directory, as at
+/// <http://www.brynosaurus.com/cachedir/>.
+pub const CACHEDIR_TAG: &str = "CACHEDIR.TAG";
+
+/// What a `CACHEDIR.TAG` has to start with.
+const CACHEDIR_SIGNATURE: &[u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+impl Filter {
+    /// Whether the entry at `path` should be backed up. With includes, every
+    /// directory is gone into, excluded or not, since an included file may be
+    /// anywhere below it.
+    pub fn wanted(&self, path: &Path, is_dir: bool) -> bool {
+        if let Some(ref includes) = self.includes {
+            return is_dir || includes.is_match(path);
+        }
+        match self.excludes {
+            Some(ref excludes) => !excludes.is_match(path),
+            None => true,
+        }
+    }
+
+    /// Whether an entry with `metadata` is on the same file system as the
+    /// backup root, or that doesn't matter.
+    pub fn same_device(&self, metadata: &fs::Metadata) -> bool {
+        match self.device {
+            Some(device) => device_of(metadata).is_none_or(|dev| dev == device),
+            None => true,
+        }
+    }
+
+    /// The type of the file system an entry with `metadata` is on, if it's
+    /// one of the excluded types.
+    pub fn excluded_fs_type(&self, metadata: &fs::Metadata) -> Option<&str> {
+        if self.excluded_mounts.is_empty() {
+            return None;
+        }
+        device_of(metadata)
+            .and_then(|dev| self.excluded_mounts.get(&dev))
+            .map(|fs_type| fs_type.as_str())
+    }
+
+    /// Whether an entry with `metadata` is in the size range. Only regular
+    /// files are ever too small or too large.
+    pub fn size_wanted(&self, metadata: &fs::Metadata) -> bool {
+        if !metadata.is_file() {
+            return true;
+        }
+        let len = metadata.len();
+        self.min_size.is_none_or(|min| len >= min) && self.max_size.is_none_or(|max| len <= max)
+    }
+
+    /// Whether a directory `depth` levels below the backup root is gone
+    /// into, those in the root itself being at 1.
+    pub fn within_depth(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max| depth <= max)
+    }
+
+    /// Whether the directory at `path` is a cache whose contents are left
+    /// out, all but its tag.
+    pub fn is_excluded_cache(&self, path: &Path) -> bool {
+        if !self.exclude_caches {
+            return false;
+        }
+        let mut start = vec![0; CACHEDIR_SIGNATURE.len()];
+        fs::File::open(path.join(CACHEDIR_TAG))
+            .and_then(|mut f| f.read_exact(&mut start))
+            .map(|_| start == CACHEDIR_SIGNATURE)
+            .unwrap_or(false)
+    }
+
+    /// The `.gitignore` rules inside the directory at `dir`, `parent` being
+    /// those of the directory it's in.
+    pub fn gitignores(&self, dir: &Path, parent: &Gitignores) -> Gitignores {
+        if self.gitignore {
+            parent.enter(dir)
+        } else {
+            Gitignores::default()
+        }
+    }
+
+    /// Whether `wanted` needs to know if the entry is a directory.
+    pub fn needs_type(&self) -> bool {
+        self.includes.is_some() || self.gitignore
+    }
+}
+
+/// The id of the device a file is on, where there is such a thing.
+#[cfg(unix)]
+pub fn device_of(metadata: &fs::Metadata) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+    Some(metadata.dev())
+}
+
+#[cfg(not(unix))]
+pub fn device_of(_metadata: &fs::Metadata) -> Option<u64> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use globset::{Glob, GlobSetBuilder};
+
+    fn globs(patterns: &[&str]) -> Option<GlobSet> {
+        let mut builder = GlobSetBuilder::new();
+        for pattern in patterns {
+            builder.add(Glob::new(pattern).unwrap());
+        }
+        Some(builder.build().unwrap())
+    }
+
+    #[test]
+    fn excluded_directories_are_skipped() {
+        let filter = Filter {
+            excludes: globs(&["*/cache"]),
+            ..Filter::default()
+        };
+        assert!(!filter.wanted(Path::new("/home/cache"), true));
+        assert!(filter.wanted(Path::new("/home/notes.txt"), false));
+    }
+
+    #[test]
+    fn an_include_rescues_an_excluded_file() {
+        let filter = Filter {
+            excludes: globs(&["*.conf"]),
+            includes: globs(&["*.conf"]),
+            ..Filter::default()
+        };
+        assert!(filter.wanted(Path::new("/etc/app.conf"), false));
+        assert!(!filter.wanted(Path::new("/etc/motd"), false));
+    }
+
+    #[test]
+    fn an_include_rescues_a_file_below_an_excluded_directory() {
+        let filter = Filter {
+            excludes: globs(&["/etc/app", "/etc/app/*"]),
+            includes: globs(&["*.conf"]),
+            ..Filter::default()
+        };
+        assert!(filter.wanted(Path::new("/etc/app"), true));
+        assert!(filter.wanted(Path::new("/etc/app/app.conf"), false));
+        assert!(!filter.wanted(Path::new("/etc/app/app.log"), false));
+    }
+}
@@ -0,0 +1,50 @@
+use std::path::Path;
+use std::rc::Rc;
+
+use ignore::Match;
+use ignore::gitignore::Gitignore;
+
+const GITIGNORE: &str = ".gitignore";
+
+/// The `.gitignore` files that apply inside a directory, from the one it
+/// has itself up to the backup root.
+#[derive(Clone, Default)]
+pub struct Gitignores(Option<Rc<Level>>);
+
+struct Level {
+    rules: Gitignore,
+    parent: Gitignores,
+}
+
+impl Gitignores {
+    /// The rules inside `dir`, a directory these apply in: its own
+    /// `.gitignore`, if it has one, on top of these.
+    pub fn enter(&self, dir: &Path) -> Gitignores {
+        let path = dir.join(GITIGNORE);
+        if !path.is_file() {
+            return self.clone();
+        }
+        let (rules, err) = Gitignore::new(&path);
+        if let Some(err) = err {
+            warn!("Couldn't read all of '{}': {}", path.display(), err);
+        }
+        Gitignores(Some(Rc::new(Level {
+            rules,
+            parent: self.clone(),
+        })))
+    }
+
+    /// Whether the entry at `path` is ignored. As with git, the closest
+    /// `.gitignore` with a pattern matching it decides, so one further down
+    /// can re-include what one further up ignores.
+    pub fn ignores(&self, path: &Path, is_dir: bool) -> bool {
+        let mut level = self.0.as_ref();
+        while let Some(current) = level {
+            match current.rules.matched(path, is_dir) {
+                Match::None => level = current.parent.0.as_ref(),
+                matched => return matched.is_ignore(),
+            }
+        }
+        false
+    }
+}
@@ -0,0 +1,33 @@
+use std::process::Command;
+
+use errors::*;
+
+/// Runs `command` through the shell once an archive is attached, with the
+/// cap of the new archive as `$1` and those of the archives pruned after
+/// it as the rest of the arguments.
+pub fn post_archive(command: &str, cap: &str, pruned: &[String]) -> Result<()> {
+    let status = shell(command)
+        .arg(cap)
+        .args(pruned)
+        .status()
+        .chain_err(|| format!("Couldn't run '{}'", command))?;
+    if !status.success() {
+        bail!("'{}' failed with {}", command, status);
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    // The name the script sees as `$0`.
+    shell.arg("-c").arg(command).arg("tahoe-backup");
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
@@ -0,0 +1,138 @@
+use std::fs;
+use std::io;
+use std::ops::Add;
+use std::path::{Path, PathBuf};
+
+use futures::{stream, Future, IntoFuture, Stream};
+
+use backupdb::BackupDB;
+use tahoe::client::{ChildInfo, Dir, DirNode, NodeType, Tahoe, DIR_HASH_VERSION};
+
+use errors::*;
+
+use failures::Failures;
+
+use restore::{is_compressed, local_name, symlink_target};
+
+use {file_times, names};
+
+/// What an import recorded in the database.
+#[derive(Default, Clone, Copy)]
+pub struct Imported {
+    pub files: usize,
+    pub dirs: usize,
+}
+
+impl Add for Imported {
+    type Output = Imported;
+
+    fn add(self, other: Imported) -> Imported {
+        Imported {
+            files: self.files + other.files,
+            dirs: self.dirs + other.dirs,
+        }
+    }
+}
+
+/// Mutable directories may change after they're listed, so backups never
+/// reuse them and they aren't recorded.
+fn is_immutable_dir_cap(cap: &str) -> bool {
+    cap.starts_with("URI:DIR2-CHK:") || cap.starts_with("URI:DIR2-LIT:")
+}
+
+/// Records the archived `child` as the upload of the local file at `path`,
+/// if it's still the file that was backed up: same mtime and, unless it was
+/// compressed, same size. Otherwise the next backup has to upload it anyway.
+fn import_file(db: &BackupDB, child: &ChildInfo, path: &Path) -> Result<bool> {
+    let cap = match child.uri() {
+        Some(cap) if child.nodetype == NodeType::File && symlink_target(child).is_none() => cap,
+        _ => return Ok(false),
+    };
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(false),
+        Err(e) => return Err(Error::with_chain(e, ErrorKind::ReadMetadata(path.display().to_string()))),
+    };
+    let (ctime, mtime) = file_times(&metadata);
+    if !metadata.is_file() || child.time("mtime") != Some(mtime as u64)
+        || (!is_compressed(child) && child.size != Some(metadata.len()))
+    {
+        return Ok(false);
+    }
+    db.add_file(cap, names::db_key(path), metadata.len() as i64, ctime, mtime, None)?;
+    if let Some(method) = child.metadata.get(names::COMPRESSION_KEY).and_then(|v| v.as_str()) {
+        db.set_compression(cap, method)?;
+    }
+    info!("'{}' -> '{}'", path.display(), cap);
+    Ok(true)
+}
+
+/// Fills the database from the archived directory `cap`, which was backed
+/// up from the local folder `path`: the hash of every immutable directory
+/// below it, and the cap of every file that's unchanged since. Anything
+/// that can't be read on either side is recorded in `failures` and left
+/// out.
+pub fn import<'a>(
+    client: &'a Tahoe,
+    db: &'a BackupDB,
+    failures: &'a Failures,
+    cap: &str,
+    path: PathBuf,
+) -> Box<Future<Item = Imported, Error = Error> + 'a> {
+    let dircap = cap.to_owned();
+    Box::new(
+        client
+            .read_dir(cap)
+            .into_future()
+            .flatten()
+            .map_err(|e| Error::with_chain(e, "couldn't read dir"))
+            .and_then(move |children| {
+                let mut imported = Imported::default();
+                if is_immutable_dir_cap(&dircap) {
+                    let dir: Option<Dir> = children
+                        .iter()
+                        .map(|(name, child)| DirNode::from_child(child).map(|node| (name.clone(), node)))
+                        .collect();
+                    if let Some(dir) = dir {
+                        db.add_dir(dir.hash() as i64, DIR_HASH_VERSION, &dircap)?;
+                        imported.dirs += 1;
+                    }
+                }
+                let mut subdirs = Vec::new();
+                for (name, child) in children {
+                    let local = match local_name(&name, &child) {
+                        Some(local) => path.join(local),
+                        None => {
+                            let error = ErrorKind::UnsafeName(name.clone()).into();
+                            failures.log_and_record(&path.join(&name), error);
+                            continue;
+                        }
+                    };
+                    match child.uri() {
+                        Some(cap) if child.nodetype == NodeType::Dir => {
+                            // Nothing below a folder that's gone can be unchanged.
+                            if fs::symlink_metadata(&local).map(|m| m.is_dir()).unwrap_or(false) {
+                                subdirs.push((cap.to_owned(), local));
+                            }
+                        }
+                        _ => match import_file(db, &child, &local) {
+                            Ok(true) => imported.files += 1,
+                            Ok(false) => {}
+                            Err(e) => failures.log_and_record(&local, e),
+                        },
+                    }
+                }
+                let subdirs = subdirs.into_iter().map(move |(cap, local)| {
+                    let failpath = local.clone();
+                    import(client, db, failures, &cap, local).or_else(move |e| {
+                        failures.log_and_record(&failpath, e);
+                        Ok(Imported::default())
+                    })
+                });
+                Ok(stream::iter_ok(subdirs)
+                    .buffered(client.concurrency())
+                    .fold(imported, |total, imported| Ok::<_, Error>(total + imported)))
+            })
+            .flatten(),
+    )
+}
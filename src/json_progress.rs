@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde_json;
+
+use tahoe_backup::events::{Event, EventSink};
+
+/// How often a `progress` line is written for a file at most.
+const TICK_MILLIS: u64 = 250;
+
+/// One line of the output.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+enum Line<'a> {
+    Started { path: String, size: u64 },
+    /// `sent` is everything sent of the file so far.
+    Progress { path: String, sent: u64 },
+    Finished { path: String, cap: &'a str },
+    Skipped { path: String },
+    Failed { path: String, error: String },
+}
+
+/// How far along a file being uploaded is.
+struct Upload {
+    sent: u64,
+    /// When its last `progress` line was written.
+    shown: Instant,
+}
+
+struct Output {
+    out: Box<Write + Send>,
+    uploads: HashMap<PathBuf, Upload>,
+}
+
+impl Output {
+    fn line<'e>(&mut self, event: &Event<'e>) -> Option<Line<'e>> {
+        let show = |path: &Path| path.to_string_lossy().into_owned();
+        Some(match *event {
+            Event::Started { path, size } => {
+                let upload = Upload { sent: 0, shown: Instant::now() };
+                self.uploads.insert(path.to_owned(), upload);
+                Line::Started { path: show(path), size }
+            }
+            Event::Progress { path, bytes } => {
+                let upload = self.uploads.get_mut(path)?;
+                upload.sent += bytes;
+                if upload.shown.elapsed() < Duration::from_millis(TICK_MILLIS) {
+                    return None;
+                }
+                upload.shown = Instant::now();
+                Line::Progress { path: show(path), sent: upload.sent }
+            }
+            Event::Finished { path, cap } => {
+                self.uploads.remove(path);
+                Line::Finished { path: show(path), cap }
+            }
+            Event::Skipped { path } => Line::Skipped { path: show(path) },
+            Event::Failed { path, error } => {
+                self.uploads.remove(path);
+                Line::Failed { path: show(path), error: error.to_string() }
+            }
+        })
+    }
+}
+
+/// Writes every event to `out` as a line of JSON, for another program to
+/// show the progress its own way. Progress is written at most every
+/// `TICK_MILLIS` for each file.
+pub fn sink(out: Box<Write + Send>) -> EventSink {
+    let output = Mutex::new(Output { out, uploads: HashMap::new() });
+    Arc::new(move |event: &Event| {
+        let mut output = match output.lock() {
+            Ok(output) => output,
+            Err(_) => return,
+        };
+        let line = match output.line(event).map(|line| serde_json::to_vec(&line)) {
+            Some(Ok(line)) => line,
+            _ => return,
+        };
+        // Whoever reads it may have gone away, which doesn't stop the backup.
+        let _ = output.out.write_all(&line).and_then(|_| output.out.write_all(b"\n"));
+    })
+}
@@ -0,0 +1,43 @@
+use futures::{stream, Future, IntoFuture, Stream};
+
+use tahoe::client::{is_literal_cap, Tahoe};
+
+use errors::*;
+
+/// How many leases were renewed, and on how many caps that failed.
+#[derive(Clone, Copy, Default)]
+pub struct Tally {
+    pub renewed: u64,
+    pub failed: u64,
+}
+
+/// Renews the leases on all of `caps`. Literal caps have none, so they're
+/// left out.
+pub fn renew<'a>(client: &'a Tahoe, caps: Vec<String>) -> Box<Future<Item = Tally, Error = Error> + 'a> {
+    let caps: Vec<String> = caps.into_iter().filter(|cap| !is_literal_cap(cap)).collect();
+    Box::new(
+        stream::iter_ok(caps)
+            .map(move |cap| {
+                client
+                    .renew_lease(&cap)
+                    .into_future()
+                    .flatten()
+                    .then(move |res| Ok::<_, Error>((cap, res)))
+            })
+            .buffer_unordered(client.concurrency())
+            .fold(Tally::default(), |mut tally, (cap, res)| {
+                match res {
+                    Ok(()) => {
+                        info!("Renewed lease on '{}'", cap);
+                        tally.renewed += 1;
+                    }
+                    Err(e) => {
+                        warn!("Couldn't renew lease on '{}'", cap);
+                        ::log_err(e);
+                        tally.failed += 1;
+                    }
+                }
+                Ok::<_, Error>(tally)
+            }),
+    )
+}
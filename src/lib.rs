@@ -0,0 +1,1901 @@
+//! Backs up directory trees to a Tahoe-LAFS grid, uploading only what changed
+//! since the last run. This is everything `tahoe-backup` does, for use from
+//! other programs; the binary is a command line over it.
+
+#![recursion_limit = "256"]
+
+#[cfg(unix)]
+extern crate base64;
+extern crate backupdb;
+extern crate chrono;
+extern crate console;
+extern crate filetime;
+extern crate futures;
+extern crate futures_cpupool;
+extern crate globset;
+extern crate ignore;
+extern crate indicatif;
+#[cfg(unix)]
+extern crate libc;
+extern crate serde;
+extern crate serde_json;
+extern crate sha2;
+extern crate tahoe;
+extern crate tokio_core;
+extern crate unicode_width;
+#[cfg(unix)]
+extern crate xattr;
+
+#[macro_use]
+extern crate log;
+
+#[macro_use]
+extern crate error_chain;
+
+#[macro_use]
+extern crate serde_derive;
+
+pub mod errors {
+    use tahoe;
+    use backupdb;
+    error_chain!{
+        links {
+            Tahoe(tahoe::errors::Error, tahoe::errors::ErrorKind);
+            BackupDB(backupdb::errors::Error, backupdb::errors::ErrorKind);
+        }
+        foreign_links {
+            Io(::std::io::Error);
+        }
+        errors {
+            FileOpen(path: String) {
+                description("Couldn't open file"),
+                display("Couldn't open file: '{}'", path),
+            }
+            ReadMetadata(path: String) {
+                description("Couldn't read metadata"),
+                display("Couldn't read metadata: '{}'", path),
+            }
+            FileUpload(path: String) {
+                description("Couldn't upload file"),
+                display("Couldn't upload file: '{}'", path),
+            }
+            GlobParse(glob: String) {
+                description("Couldn't parse glob"),
+                display("Couldn't parse glob: '{}'", glob),
+            }
+            UnknownFile(path: String) {
+                description("Unexpected file"),
+                display("Unexpected file: '{}'", path),
+            }
+            FileDownload(path: String) {
+                description("Couldn't download file"),
+                display("Couldn't download file: '{}'", path),
+            }
+            CreateDir(path: String) {
+                description("Couldn't create directory"),
+                display("Couldn't create directory: '{}'", path),
+            }
+            RestoreMetadata(path: String) {
+                description("Couldn't restore metadata"),
+                display("Couldn't restore metadata: '{}'", path),
+            }
+            MissingCap(path: String) {
+                description("Missing capability"),
+                display("Missing capability: '{}'", path),
+            }
+            UnsafeName(name: String) {
+                description("Refusing unsafe file name"),
+                display("Refusing unsafe file name: '{}'", name),
+            }
+            IgnoreFileParse(path: String, line: usize) {
+                description("Couldn't parse ignore file"),
+                display("Couldn't parse ignore file '{}' at line {}", path, line),
+            }
+            LeaseRenewal(count: u64) {
+                description("Couldn't renew leases"),
+                display("Couldn't renew leases on {} caps", count),
+            }
+            Interrupted {
+                description("Interrupted"),
+                display("Interrupted"),
+            }
+            Unhealthy(count: u64) {
+                description("Caps not healthy"),
+                display("{} caps aren't healthy", count),
+            }
+            ReadLink(path: String) {
+                description("Couldn't read symlink"),
+                display("Couldn't read symlink: '{}'", path),
+            }
+            CreateSymlink(path: String) {
+                description("Couldn't create symlink"),
+                display("Couldn't create symlink: '{}'", path),
+            }
+            SymlinkLoop(path: String) {
+                description("Symlink loop"),
+                display("Symlink loop: '{}'", path),
+            }
+            DateTimeParse(value: String) {
+                description("Couldn't parse date and time"),
+                display("Couldn't parse date and time: '{}'", value),
+            }
+            Incomplete(count: usize) {
+                description("Some files or directories failed"),
+                display("{} files or directories failed", count),
+            }
+            ConvergenceRead(path: String) {
+                description("Couldn't read convergence secret"),
+                display("Couldn't read convergence secret: '{}'", path),
+            }
+            ConvergenceMismatch(path: String) {
+                description("The node's convergence secret is different"),
+                display("The node's convergence secret in '{}' isn't the one in --convergence-file", path),
+            }
+            ConfigRead(path: String) {
+                description("Couldn't read config file"),
+                display("Couldn't read config file: '{}'", path),
+            }
+            AliasesRead(path: String) {
+                description("Couldn't read aliases file"),
+                display("Couldn't read aliases file: '{}'", path),
+            }
+            UnknownAlias(name: String, path: String) {
+                description("Unknown alias"),
+                display("Unknown alias '{}', it isn't in '{}'", name, path),
+            }
+            ReadXattrs(path: String) {
+                description("Couldn't read extended attributes"),
+                display("Couldn't read extended attributes: '{}'", path),
+            }
+            RestoreXattr(path: String, name: String) {
+                description("Couldn't restore extended attribute"),
+                display("Couldn't restore extended attribute '{}': '{}'", name, path),
+            }
+            UnknownCompression(name: String) {
+                description("Unknown compression"),
+                display("Unknown compression: '{}'", name),
+            }
+            SizeMismatch(expected: u64, stored: u64) {
+                description("Uploaded file has the wrong size"),
+                display("The grid has {} bytes, expected {}", stored, expected),
+            }
+            ReadMismatch(expected: u64, read: u64) {
+                description("File changed size while it was uploaded"),
+                display("Read {} bytes of the file, expected {}", read, expected),
+            }
+            Target(cap: String) {
+                description("Can't back up to the target"),
+                display("Can't back up to '{}'", cap),
+            }
+            Attach(cap: String, missing: String) {
+                description("Couldn't link the archive"),
+                display("Couldn't link the archive '{}' as {}, link it by hand", cap, missing),
+            }
+            GridFull(available: u64, min_free: u64) {
+                description("Not enough free space on the grid"),
+                display("The grid has {} bytes free, less than the {} it should have", available, min_free),
+            }
+            Mirror(node_url: String) {
+                description("Couldn't back up to a mirror"),
+                display("Couldn't back up to the mirror '{}'", node_url),
+            }
+            SelfTest(step: &'static str) {
+                description("Self-test failed"),
+                display("Self-test failed {}", step),
+            }
+            SnapshotName(template: String) {
+                description("Invalid snapshot name"),
+                display("Invalid snapshot name: '{}'", template),
+            }
+            TimestampFormat(format: String) {
+                description("Invalid timestamp format"),
+                display("Invalid timestamp format: '{}'", format),
+            }
+            Aborted {
+                description("Stopped at the first failure"),
+                display("Stopped at the first failure"),
+            }
+        }
+    }
+}
+
+
+pub mod diff;
+mod elide;
+pub mod events;
+pub mod failures;
+mod filter;
+mod gitignore;
+mod hook;
+pub mod import;
+pub mod leases;
+pub mod list;
+mod mounts;
+pub mod names;
+mod prescan;
+mod snapshot;
+pub mod restore;
+pub mod retention;
+pub mod selftest;
+pub mod stats;
+mod throughput;
+pub mod verify;
+mod walk;
+mod xattrs;
+
+use std::{fs, io};
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
+use std::io::{Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::thread;
+use std::time::{Duration, UNIX_EPOCH};
+
+use tokio_core::reactor::{Core, Interval};
+
+use futures::{future, stream, Future, IntoFuture, Stream};
+use futures_cpupool::CpuPool;
+
+use tahoe::client::{is_writable_dir_cap, Dir, DirNode, Tahoe, DIR_HASH_VERSION};
+use tahoe::compress::Compression;
+
+use backupdb::BackupDB;
+use backupdb::models::RunMeta;
+
+use errors::*;
+
+use events::{Event, EventSink};
+
+use filter::Filter;
+
+use prescan::Prescan;
+
+use stats::{Report, Stats};
+
+use throughput::Throughput;
+
+use walk::Walk;
+
+use filetime::FileTime;
+
+use chrono::Utc;
+
+use sha2::{Digest, Sha256};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+
+/// The most columns `style()` takes up besides its message.
+const STYLE_RESERVED: usize = 88;
+
+/// The most columns `dir_children_style()` takes up besides its message,
+/// which is also enough for `dir_style()`.
+const DIR_STYLE_RESERVED: usize = 70;
+
+fn style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta}) {wide_msg}",
+        )
+        .progress_chars("#>-")
+}
+
+fn dir_style() -> ProgressStyle {
+    ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {wide_msg}")
+}
+
+/// A directory's bar once it's been read, counting its children as they're
+/// done.
+fn dir_children_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template("{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {pos}/{len} {wide_msg}")
+        .progress_chars("#>-")
+}
+
+fn scanning_style() -> ProgressStyle {
+    ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {bytes} {wide_msg}")
+}
+
+fn finished_style() -> ProgressStyle {
+    ProgressStyle::default_bar()
+        .template(
+            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {total_bytes} {wide_msg}",
+        )
+        .progress_chars("#>-")
+}
+
+/// Placeholder returned instead of a file cap during a dry run. It never makes
+/// it into the database, so every directory containing it counts as new.
+const DRY_RUN_FILECAP: &str = "URI:CHK:dry-run";
+const DRY_RUN_DIRCAP: &str = "URI:DIR2-CHK:dry-run";
+
+/// How often the stats are logged when there are no progress bars.
+const PROGRESS_LOG_SECS: u64 = 60;
+
+/// Files smaller than this aren't compressed, as there'd be little to gain.
+const MIN_COMPRESS_SIZE: u64 = 4096;
+
+/// Extensions of files whose contents are compressed already.
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "bz2", "gif", "gz", "jpeg", "jpg", "lz", "lzma", "mkv", "mov", "mp3", "mp4", "ogg", "png",
+    "rar", "tgz", "webm", "webp", "xz", "zip", "zst",
+];
+
+/// How often the throughput bar is updated.
+const THROUGHPUT_SECS: u64 = 1;
+
+/// Everything `upload` needs that stays the same for the whole backup.
+struct Context<'a> {
+    /// Where progress bars are drawn, unless they're disabled.
+    progress: Option<&'a MultiProgress>,
+    client: &'a Tahoe,
+    /// Other grids everything goes to as well.
+    mirrors: &'a [Mirror],
+    db: &'a BackupDB,
+    stats: &'a Stats,
+    /// In a dry run, the bar that shows the running tally in place of the
+    /// per-file progress bars. Nothing is sent to the grid then.
+    dry_run: Option<&'a ProgressBar>,
+    /// Advances by the size of every file once it's uploaded or skipped.
+    overall: Option<&'a ProgressBar>,
+    /// Counts the bytes sent to the grid, from the upload threads.
+    sent: Arc<AtomicUsize>,
+    /// Where events go, for a program that embeds the backup.
+    events: Option<EventSink>,
+    /// How many directories are uploaded at once.
+    dir_concurrency: usize,
+    /// How long a cached directory cap may be reused before it's uploaded
+    /// again. Forever if unset.
+    dir_cache_ttl: Option<Duration>,
+    /// Upload directories as mutable MDMF directories, which are never
+    /// reused.
+    mutable_dirs: bool,
+    /// Back up what symlinks point to, rather than skipping them.
+    follow_symlinks: bool,
+    /// Back up what symlinks point to if it's inside the source they're in,
+    /// treating the others as if this wasn't set.
+    follow_internal_symlinks: bool,
+    /// The real paths of the sources, for `follow_internal_symlinks`.
+    roots: Vec<PathBuf>,
+    /// Store the targets of symlinks, rather than skipping them.
+    store_symlinks: bool,
+    /// Files last modified at or before this time, in seconds since the
+    /// epoch, keep the cap recorded for them without being looked at again.
+    newer_than: Option<i64>,
+    /// Tell whether a file changed by its size and mtime alone.
+    ignore_ctime: bool,
+    /// Fail on FIFOs, sockets and devices instead of skipping them.
+    fail_on_special: bool,
+    /// Leave out directories that can't be read for lack of permission,
+    /// rather than failing them.
+    skip_unreadable: bool,
+    /// Log the cap each skipped file reuses.
+    list_skipped: bool,
+    /// Stop starting anything new after the first failure.
+    fail_fast: bool,
+    /// Caps of the files with several hardlinks uploaded in this run, by device
+    /// and inode, so the other links reuse them.
+    hardlinks: RefCell<HashMap<(u64, u64), String>>,
+    /// Store extended attributes in the metadata of every child.
+    xattrs: bool,
+    /// Reuse the directories the interrupted run before this one finished,
+    /// without reading them again.
+    resume: bool,
+    /// Upload everything, whatever the database has.
+    no_reuse: bool,
+    /// Read back the size of every uploaded file before recording it.
+    verify_uploads: bool,
+    /// How to compress files that are likely to be worth it.
+    compress: Option<Compression>,
+    /// Where files are hashed to find their contents elsewhere in the
+    /// database, with `--dedup-by-content`.
+    hasher: Option<&'a CpuPool>,
+    /// Set on Ctrl-C, or on the first failure with `fail_fast`. From then on
+    /// nothing new is started, but what's already running gets to finish and
+    /// be recorded.
+    interrupted: &'a AtomicBool,
+}
+
+impl<'a> Context<'a> {
+    /// Adds `bar` to the progress display, or hides it if there is none.
+    fn bar(&self, bar: ProgressBar) -> ProgressBar {
+        match self.progress {
+            Some(progress) => progress.add(bar),
+            None => ProgressBar::hidden(),
+        }
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        if self.follow_symlinks {
+            return fs::metadata(path);
+        }
+        let metadata = fs::symlink_metadata(path)?;
+        if self.follow_internal_symlinks
+            && metadata.file_type().is_symlink()
+            && self.roots.iter().any(|root| path.starts_with(root) && points_inside(path, root))
+        {
+            return fs::metadata(path);
+        }
+        Ok(metadata)
+    }
+
+    /// What's added to the message for a skipped file that reuses `cap`.
+    fn reused(&self, cap: &str) -> String {
+        if self.list_skipped {
+            format!(", reusing '{}'", cap)
+        } else {
+            String::new()
+        }
+    }
+
+    /// The cap the database has for the file at `key`, if it's unchanged
+    /// since and may be reused.
+    fn known_file(&self, key: &str, size: u64, ctime: i64, mtime: i64) -> Option<String> {
+        if self.no_reuse {
+            return None;
+        }
+        self.db
+            .check_file(key, size as i64, self.compared_ctime(ctime), mtime)
+            .filter(|cap| self.mirrored(cap))
+    }
+
+    /// What `cap` is on `mirror`, if it's there too.
+    fn mirror_cap(&self, mirror: &Mirror, cap: &str) -> Option<String> {
+        // A literal cap holds the contents itself, so it's the same anywhere.
+        if cap.starts_with("URI:LIT:") {
+            return Some(cap.to_owned());
+        }
+        self.db.mirror_cap(mirror.client.node_url(), cap)
+    }
+
+    /// Whether `cap` is on every mirror as well, so it may be reused.
+    fn mirrored(&self, cap: &str) -> bool {
+        self.mirrors.iter().all(|mirror| self.mirror_cap(mirror, cap).is_some())
+    }
+
+    /// The clients of the mirrors, in order, to upload to all of them.
+    fn mirror_clients(&self) -> Vec<&'a Tahoe> {
+        self.mirrors.iter().map(|mirror| &mirror.client).collect()
+    }
+
+    /// The ctime to compare with the recorded one, 0 for none.
+    fn compared_ctime(&self, ctime: i64) -> i64 {
+        if self.ignore_ctime {
+            0
+        } else {
+            ctime
+        }
+    }
+
+    fn file_done(&self, size: u64) {
+        if let Some(overall) = self.overall {
+            overall.inc(size);
+        }
+        if let Some(tally) = self.dry_run {
+            tally.set_message(&self.stats.to_string());
+        }
+    }
+
+    fn event(&self, event: Event) {
+        if let Some(ref events) = self.events {
+            events(&event);
+        }
+    }
+
+    fn dir_done(&self) {
+        if let Some(tally) = self.dry_run {
+            tally.set_message(&self.stats.to_string());
+        }
+    }
+}
+
+impl<'a> Context<'a> {
+    /// Records that the upload of `path` that got `cap` got each of
+    /// `mirrored` on the mirrors, in order.
+    fn record_mirrors(&self, path: &Path, cap: &str, mirrored: &[String]) {
+        for (mirror, mirror_cap) in self.mirrors.iter().zip(mirrored) {
+            self.ok_or_record(path, self.db.add_mirror_cap(mirror.client.node_url(), cap, mirror_cap));
+        }
+    }
+
+    /// Logs an error and keeps it as a failure of `path`, so that whatever
+    /// `res` was for gets left out.
+    fn ok_or_record<T, E>(&self, path: &Path, res: std::result::Result<T, E>) -> Option<T>
+    where
+        E: Into<Error>,
+    {
+        match res {
+            Ok(x) => Some(x),
+            Err(e) => {
+                let e = e.into();
+                if let ErrorKind::Interrupted = *e.kind() {
+                    return None;
+                }
+                log_chain(&e);
+                self.event(Event::Failed { path, error: &e });
+                self.stats.failures.record(path, e);
+                if self.fail_fast {
+                    self.interrupted.store(true, Ordering::SeqCst);
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Uploads anything but a directory, which `Walk` takes care of.
+fn upload_entry<'a>(
+    ctx: &'a Context<'a>,
+    path: PathBuf,
+    metadata: io::Result<fs::Metadata>,
+) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
+    if ctx.interrupted.load(Ordering::SeqCst) {
+        return Box::new(future::ok(Err(ErrorKind::Interrupted.into())));
+    }
+
+    if metadata.is_err() {
+        return Box::new(future::ok(
+            metadata
+                .map(|_| String::new())
+                .chain_err(|| ErrorKind::ReadMetadata(path.display().to_string())),
+        ));
+    }
+
+    let metadata = metadata.unwrap();
+
+    let file_type = metadata.file_type();
+    if file_type.is_symlink() {
+        return upload_symlink(ctx, path, &metadata);
+    }
+
+    if file_type.is_file() {
+        let size = metadata.len();
+        let (ctime, mtime) = file_times(&metadata);
+
+        let inode = match hardlink_id(&metadata) {
+            Some(inode) => inode,
+            None => return upload_regular_file(ctx, path, size, ctime, mtime),
+        };
+        if let Some(cap) = ctx.hardlinks.borrow().get(&inode).cloned() {
+            info!("Skipping '{}', a hardlink to a file that's done", path.display());
+            ctx.event(Event::Skipped { path: &path });
+            ctx.stats.file_skipped(size);
+            ctx.file_done(size);
+            if ctx.dry_run.is_none() {
+                let key = names::db_key(&path);
+                ctx.ok_or_record(&path, ctx.db.add_file(&cap, key, size as i64, ctime, mtime, None));
+            }
+            return Box::new(future::ok(Ok(cap)));
+        }
+        // Links found while the first one is still uploading are uploaded
+        // again, which gives the same cap.
+        return Box::new(upload_regular_file(ctx, path, size, ctime, mtime).inspect(move |res| {
+            if let Ok(ref cap) = *res {
+                ctx.hardlinks.borrow_mut().insert(inode, cap.clone());
+            }
+        }));
+    }
+
+    Box::new(future::ok(Err(ErrorKind::UnknownFile(path.display().to_string()).into())))
+}
+
+/// Uploads the regular file at `path`, unless the database already has a cap
+/// for it.
+fn upload_regular_file<'a>(
+    ctx: &'a Context<'a>,
+    path: PathBuf,
+    size: u64,
+    ctime: i64,
+    mtime: i64,
+) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
+    // The recorded mtime must still match, so a file that was changed
+    // since it was uploaded, or has a clock-skewed mtime, isn't missed.
+    if !ctx.no_reuse && ctx.newer_than.is_some_and(|since| mtime <= since) {
+        let known = ctx.db.known_cap(&names::db_key(&path), size as i64, mtime);
+        if let Some(cap) = known.filter(|cap| ctx.mirrored(cap)) {
+            info!("Skipping '{}', not modified since threshold{}", path.display(), ctx.reused(&cap));
+            ctx.event(Event::Skipped { path: &path });
+            ctx.stats.file_skipped(size);
+            ctx.file_done(size);
+            return Box::new(future::ok(Ok(cap)));
+        }
+    }
+
+    if let Some(cap) = ctx.known_file(&names::db_key(&path), size, ctime, mtime) {
+        info!("Skipping '{}'{}", path.display(), ctx.reused(&cap));
+        ctx.event(Event::Skipped { path: &path });
+        ctx.stats.file_skipped(size);
+        ctx.file_done(size);
+        return Box::new(future::ok(Ok(cap)));
+    }
+
+    if let Some(pool) = ctx.hasher {
+        let hashpath = path.clone();
+        return Box::new(pool.spawn_fn(move || hash_file(&hashpath)).then(move |res| {
+            let hash = match res {
+                Ok(hash) => hash,
+                Err(e) => {
+                    return Box::new(future::ok(Err(
+                        Error::with_chain(e, ErrorKind::FileOpen(path.display().to_string())),
+                    ))) as Box<Future<Item = _, Error = _>>
+                }
+            };
+            let known = if ctx.no_reuse {
+                None
+            } else {
+                ctx.db.check_content(&hash, size as i64).filter(|cap| ctx.mirrored(cap))
+            };
+            match known {
+                Some(cap) => {
+                    info!("Reusing contents for '{}'{}", path.display(), ctx.reused(&cap));
+                    ctx.event(Event::Skipped { path: &path });
+                    ctx.stats.file_skipped(size);
+                    ctx.file_done(size);
+                    if ctx.dry_run.is_none() {
+                        ctx.ok_or_record(&path, ctx.db.add_file(
+                            &cap,
+                            names::db_key(&path),
+                            size as i64,
+                            ctime,
+                            mtime,
+                            Some(hash),
+                        ));
+                    }
+                    Box::new(future::ok(Ok(cap)))
+                }
+                None => upload_file(ctx, path, size, ctime, mtime, Some(hash)),
+            }
+        }));
+    }
+
+    upload_file(ctx, path, size, ctime, mtime, None)
+}
+
+/// The device and inode of a file with more than one hardlink.
+#[cfg(unix)]
+fn hardlink_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    if metadata.nlink() > 1 {
+        Some((metadata.dev(), metadata.ino()))
+    } else {
+        None
+    }
+}
+
+#[cfg(not(unix))]
+fn hardlink_id(_metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+/// Whether the file at `path`, of `size` bytes, is likely to get smaller
+/// when compressed.
+fn worth_compressing(path: &Path, size: u64) -> bool {
+    if size < MIN_COMPRESS_SIZE {
+        return false;
+    }
+    match path.extension().and_then(|ext| ext.to_str()) {
+        Some(ext) => !COMPRESSED_EXTENSIONS.contains(&ext.to_lowercase().as_str()),
+        None => true,
+    }
+}
+
+/// Whether the symlink at `path` points to something inside `root`, a real
+/// path. Links that are broken or loop point nowhere.
+fn points_inside(path: &Path, root: &Path) -> bool {
+    fs::canonicalize(path).map(|real| real.starts_with(root)).unwrap_or(false)
+}
+
+/// Whether the file at `path` still has the size and times it was uploaded
+/// with.
+fn unchanged(ctx: &Context, path: &Path, size: u64, ctime: i64, mtime: i64) -> bool {
+    match ctx.metadata(path) {
+        Ok(ref metadata) => metadata.len() == size && file_times(metadata) == (ctime, mtime),
+        Err(_) => false,
+    }
+}
+
+/// The ctime and mtime recorded in the database, in seconds. The ctime is 0
+/// where the file system doesn't have one.
+fn file_times(metadata: &fs::Metadata) -> (i64, i64) {
+    let ctime = FileTime::from_creation_time(metadata)
+        .unwrap_or(FileTime::zero())
+        .seconds() as i64;
+    let mtime = FileTime::from_last_modification_time(metadata).seconds() as i64;
+    (ctime, mtime)
+}
+
+/// Uploads the target of the symlink at `path` as a small file, so it gets a
+/// cap like any other child. Restoring goes by the copy of the target that
+/// `Walk` puts in the child's metadata.
+fn upload_symlink<'a>(
+    ctx: &'a Context<'a>,
+    path: PathBuf,
+    metadata: &fs::Metadata,
+) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
+    let showpath = path.display().to_string();
+    let target = match fs::read_link(&path) {
+        Ok(target) => target,
+        Err(e) => return Box::new(future::ok(Err(Error::with_chain(e, ErrorKind::ReadLink(showpath))))),
+    };
+    // The prescan only counts regular files, so the overall bar isn't
+    // advanced for symlinks.
+    let size = metadata.len();
+    let (ctime, mtime) = file_times(metadata);
+    let key = names::db_key(&path);
+    let mirrorpath = path.clone();
+
+    if let Some(cap) = ctx.known_file(&key, size, ctime, mtime) {
+        info!("Skipping '{}'{}", showpath, ctx.reused(&cap));
+        ctx.event(Event::Skipped { path: &path });
+        ctx.stats.file_skipped(size);
+        ctx.file_done(0);
+        return Box::new(future::ok(Ok(cap)));
+    }
+
+    if ctx.dry_run.is_some() {
+        info!("Would upload symlink '{}'", showpath);
+        ctx.stats.file_uploaded(size);
+        ctx.file_done(0);
+        return Box::new(future::ok(Ok(String::from(DRY_RUN_FILECAP))));
+    }
+
+    info!("Uploading symlink '{}'", showpath);
+    ctx.event(Event::Started { path: &path, size });
+    let logpath = showpath.clone();
+    let (target, _) = names::child_name(target.as_os_str());
+    let data = target.into_bytes();
+    let uploads: Vec<_> = Some(ctx.client)
+        .into_iter()
+        .chain(ctx.mirror_clients())
+        .map(|client| client.upload_data(data.clone()))
+        .collect();
+    Box::new(
+        future::join_all(uploads)
+            .map(move |mut caps| {
+                let cap = caps.remove(0);
+                ctx.record_mirrors(&mirrorpath, &cap, &caps);
+                cap
+            })
+            .inspect(move |cap| {
+                info!("'{}' -> '{}'", &logpath, cap);
+                ctx.event(Event::Finished { path: &path, cap });
+                ctx.stats.file_uploaded(size);
+                ctx.file_done(0);
+                ctx.ok_or_record(&path, ctx.db.add_file(cap, key, size as i64, ctime, mtime, None));
+            })
+            .map_err(move |e| Error::with_chain(e, ErrorKind::FileUpload(showpath)))
+            .map(Ok),
+    )
+}
+
+/// Uploads a file that isn't on the grid yet, as far as the database knows.
+fn upload_file<'a>(
+    ctx: &'a Context<'a>,
+    path: PathBuf,
+    size: u64,
+    ctime: i64,
+    mtime: i64,
+    contenthash: Option<String>,
+) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
+    if ctx.dry_run.is_some() {
+        info!("Would upload file '{}'", path.display());
+        ctx.stats.file_uploaded(size);
+        ctx.file_done(size);
+        return Box::new(future::ok(Ok(String::from(DRY_RUN_FILECAP))));
+    }
+
+    let compression = ctx.compress.filter(|_| worth_compressing(&path, size));
+    let showpath = path.display().to_string();
+    info!("Uploading file '{}'", showpath);
+    let logpath = showpath.clone();
+    let key = names::db_key(&path);
+    let failpath = path.clone();
+    let mismatchpath = showpath.clone();
+    let readpath = showpath.clone();
+    let pb = Arc::new(ctx.bar(ProgressBar::new(size)));
+    pb.set_style(style());
+    pb.set_message(&elide::bar_message(&showpath, STYLE_RESERVED));
+    let pb2 = pb.clone();
+    let sent = ctx.sent.clone();
+    ctx.event(Event::Started { path: &path, size });
+    let events = ctx.events.clone();
+    let progresspath = path.clone();
+    let mirrorpath = path.clone();
+    Box::new(
+        ctx.client
+            .upload_file_to(&ctx.mirror_clients(), path, compression, move |n| {
+                pb2.inc(n as u64);
+                sent.fetch_add(n, Ordering::Relaxed);
+                if let Some(ref events) = events {
+                    events(&Event::Progress { path: &progresspath, bytes: n as u64 });
+                }
+            })
+            .map(move |(mut caps, read)| {
+                let cap = caps.remove(0);
+                ctx.record_mirrors(&mirrorpath, &cap, &caps);
+                (cap, read)
+            })
+            .from_err()
+            .and_then(move |(cap, read)| -> Box<Future<Item = Result<String>, Error = Error>> {
+                // Whatever was read is on the grid now, but isn't the file
+                // that was stat'ed, truncated or grown since.
+                if read != size {
+                    return Box::new(future::ok(Err(Error::from(ErrorKind::ReadMismatch(size, read))
+                        .chain_err(|| ErrorKind::FileUpload(readpath)))));
+                }
+                if !ctx.verify_uploads {
+                    return Box::new(future::ok(Ok(cap)));
+                }
+                // A short file on the grid is this file's failure, not one
+                // that should stop the whole backup like losing the node.
+                Box::new(ctx.client.stat_cap(&cap).into_future().flatten().from_err().map(
+                    move |stored| {
+                        if stored != size && compression.is_none() {
+                            return Err(Error::from(ErrorKind::SizeMismatch(size, stored))
+                                .chain_err(|| ErrorKind::FileUpload(mismatchpath)));
+                        }
+                        Ok(cap)
+                    },
+                ))
+            })
+            .map(move |res| {
+                // The walk looks this up once it links the file.
+                let cap = res?;
+                if let Some(compression) = compression {
+                    ctx.db.set_compression(&cap, compression.name())?;
+                }
+                Ok(cap)
+            })
+            .inspect(move |res| {
+                pb.set_style(finished_style());
+                pb.finish_and_clear();
+                let cap = match *res {
+                    Ok(ref cap) => cap,
+                    Err(_) => return,
+                };
+                info!("'{}' -> '{}'", &logpath, cap);
+                ctx.event(Event::Finished { path: &failpath, cap });
+                ctx.stats.file_uploaded(size);
+                ctx.file_done(size);
+                // The stat from before the upload would make the next run
+                // keep whatever was read halfway through a change.
+                if !unchanged(ctx, &failpath, size, ctime, mtime) {
+                    warn!("'{}' changed while it was uploaded, it'll be uploaded again next time", &logpath);
+                    return;
+                }
+                ctx.ok_or_record(&failpath, ctx.db.add_file(
+                    cap,
+                    key,
+                    size as i64,
+                    ctime,
+                    mtime,
+                    contenthash,
+                ));
+            })
+            .map_err(move |e| Error::with_chain(e, ErrorKind::FileUpload(showpath))),
+    )
+}
+
+/// Hex-encoded SHA-256 of the contents of the file at `path`.
+fn hash_file(path: &Path) -> io::Result<String> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Sha256::default();
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => return Ok(format!("{:x}", hasher.result())),
+            Ok(n) => hasher.input(&buf[..n]),
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn upload_dir<'a>(
+    ctx: &'a Context<'a>,
+    pb: ProgressBar,
+    dir: Dir,
+    path: String,
+) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
+    // Some children may have been skipped, so this isn't the real directory.
+    if ctx.interrupted.load(Ordering::SeqCst) {
+        return Box::new(future::ok(Err(ErrorKind::Interrupted.into())));
+    }
+
+    let hash = dir.hash() as i64;
+    // A mutable directory may have changed since, so it can't be reused.
+    let cached = if ctx.mutable_dirs || ctx.no_reuse {
+        None
+    } else {
+        ctx.db
+            .check_dir(hash, DIR_HASH_VERSION, ctx.dir_cache_ttl)
+            .filter(|cap| ctx.mirrored(cap))
+    };
+    match cached {
+        Some(cap) => {
+            info!("Reusing directory '{}'", path);
+            ctx.stats.dir_reused();
+            ctx.dir_done();
+            pb.finish_and_clear();
+            Box::new(future::ok(Ok(cap)))
+        }
+        None if ctx.dry_run.is_some() => {
+            info!("Would upload dir '{}'", path);
+            ctx.stats.dir_created();
+            ctx.dir_done();
+            Box::new(future::ok(Ok(String::from(DRY_RUN_DIRCAP))))
+        }
+        None => {
+            // Children are only reused if they're on every mirror, and the
+            // rest was uploaded to all of them.
+            let mirrored: Option<Vec<Dir>> = ctx.mirrors
+                .iter()
+                .map(|mirror| dir.map_caps(|cap| ctx.mirror_cap(mirror, cap)))
+                .collect();
+            let mirrored = match mirrored {
+                Some(mirrored) => mirrored,
+                None => return Box::new(future::ok(Err(Error::from("a child isn't on every mirror")
+                    .chain_err(|| "couldn't upload dir")))),
+            };
+            let uploads: Vec<_> = Some((ctx.client, &dir))
+                .into_iter()
+                .chain(ctx.mirror_clients().into_iter().zip(&mirrored))
+                .map(|(client, dir)| send_dir(ctx, client, dir))
+                .collect();
+            Box::new(
+                future::join_all(uploads)
+                    .inspect(move |caps| {
+                        let cap = &caps[0];
+                        ctx.stats.dir_created();
+                        if !ctx.mutable_dirs {
+                            ctx.ok_or_record(Path::new(&path), ctx.db.add_dir(hash, DIR_HASH_VERSION, cap));
+                        }
+                        ctx.record_mirrors(Path::new(&path), cap, &caps[1..]);
+                        pb.finish_and_clear();
+                        info!("'{}' -> '{}'", path, cap)
+                    })
+                    .map(|mut caps| Ok(caps.remove(0)))
+                    .map_err(|e| Error::with_chain(e, "couldn't upload dir")),
+            )
+        }
+    }
+}
+
+/// Uploads `dir` to the node of `client`, as a mutable directory if that's
+/// how they're uploaded.
+fn send_dir(
+    ctx: &Context,
+    client: &Tahoe,
+    dir: &Dir,
+) -> Box<Future<Item = String, Error = tahoe::errors::Error>> {
+    if ctx.mutable_dirs {
+        Box::new(client.upload_mutable_dir(dir).into_future().flatten())
+    } else {
+        Box::new(client.upload_dir(dir).into_future().flatten())
+    }
+}
+
+/// Logs `err` and everything that caused it as warnings, one per line.
+pub fn log_err<E>(err: E)
+where
+    E: Into<Error>,
+{
+    log_chain(&err.into())
+}
+
+/// Like `log_err`, for an error that's kept around.
+pub fn log_chain(err: &Error) {
+    for (i, e) in err.iter().enumerate() {
+        warn!("{}{}", " ".repeat(i), e)
+    }
+}
+
+/// Name of the file in the backup root that lists extra exclude globs.
+const IGNORE_FILE: &str = ".tahoebackupignore";
+
+fn parse_globs<'a, I: Iterator<Item = &'a str>>(iter: I) -> Result<Vec<Glob>> {
+    iter.map(|item| Glob::new(item).chain_err(|| ErrorKind::GlobParse(String::from(item))))
+        .collect()
+}
+
+fn build_globset<I: IntoIterator<Item = Glob>>(globs: I) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob);
+    }
+    builder.build().chain_err(|| "Failed to build globset")
+}
+
+/// Reads an ignore file: one glob per line, as for `--exclude`. Blank lines
+/// and lines starting with `#` are skipped.
+fn read_ignore_file(path: &Path) -> Result<Vec<Glob>> {
+    let logpath = path.to_string_lossy().into_owned();
+    let mut contents = String::new();
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut contents))
+        .chain_err(|| ErrorKind::FileOpen(logpath.clone()))?;
+    let mut globs = Vec::new();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        globs.push(Glob::new(line).chain_err(|| ErrorKind::IgnoreFileParse(logpath.clone(), i + 1))?);
+    }
+    Ok(globs)
+}
+
+/// Collects the exclude globs from the config and the ignore file. The
+/// ignore file defaults to `.tahoebackupignore` in the backup root, which may
+/// be missing.
+fn build_excludes(config: &BackupConfig, root: &Path) -> Result<Option<GlobSet>> {
+    let mut globs = parse_globs(config.excludes.iter().map(|item| item.as_str()))?;
+    match config.ignore_file {
+        Some(ref path) => globs.extend(read_ignore_file(path)?),
+        None => {
+            let path = root.join(IGNORE_FILE);
+            if path.is_file() {
+                globs.extend(read_ignore_file(&path)?);
+            }
+        }
+    }
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    build_globset(globs).map(Some)
+}
+
+/// The filter for the tree at `root`, which gets its own ignore file and
+/// device.
+fn build_filter(config: &BackupConfig, root: &Path) -> Result<Filter> {
+    Ok(Filter {
+        excludes: build_excludes(config, root)?,
+        includes: match config.includes {
+            Some(ref items) => Some(build_globset(parse_globs(items.iter().map(|item| item.as_str()))?)?),
+            None => None,
+        },
+        device: if config.one_file_system {
+            let metadata = fs::metadata(root)
+                .chain_err(|| ErrorKind::ReadMetadata(root.display().to_string()))?;
+            filter::device_of(&metadata)
+        } else {
+            None
+        },
+        excluded_mounts: if config.exclude_fs_types.is_empty() {
+            HashMap::new()
+        } else {
+            let mut fs_types = mounts::fs_types().chain_err(|| "Couldn't read the types of the mounted file systems")?;
+            fs_types.retain(|_, fs_type| config.exclude_fs_types.contains(fs_type));
+            fs_types
+        },
+        min_size: config.min_file_size,
+        max_size: config.max_file_size,
+        max_depth: config.max_depth,
+        exclude_caches: config.exclude_caches,
+        gitignore: config.respect_gitignore,
+    })
+}
+
+/// Names for the sources in the root of a backup of several paths: their
+/// file names, numbered from the second one on if they are the same. Whether
+/// each name was encoded is as for `names::child_name`.
+fn source_names(paths: &[PathBuf]) -> Vec<(String, bool)> {
+    let mut taken = HashSet::new();
+    paths
+        .iter()
+        .map(|path| {
+            let (name, encoded) = match path.file_name() {
+                Some(name) => names::child_name(name),
+                None => (String::from("root"), false),
+            };
+            let mut unique = name.clone();
+            let mut n = 1;
+            while !taken.insert(unique.clone()) {
+                n += 1;
+                unique = format!("{}-{}", name, n);
+            }
+            (unique, encoded)
+        })
+        .collect()
+}
+
+/// Uploads the directory holding every source of a backup of several paths.
+fn upload_sources<'a>(
+    ctx: &'a Context<'a>,
+    sources: &[(PathBuf, Filter)],
+    results: Vec<Result<String>>,
+) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
+    if ctx.interrupted.load(Ordering::SeqCst) {
+        return Box::new(future::ok(Err(ErrorKind::Interrupted.into())));
+    }
+    let paths: Vec<PathBuf> = sources.iter().map(|(path, _)| path.clone()).collect();
+    let dir: Dir = source_names(&paths)
+        .into_iter()
+        .zip(paths.iter())
+        .zip(results)
+        .filter_map(|(((name, encoded), path), res)| {
+            ctx.ok_or_record(path, res).map(|cap| {
+                let mut node = DirNode::new(cap, ctx.metadata(path).ok());
+                if encoded {
+                    node.insert_metadata(names::ENCODED_NAME_KEY, 1);
+                }
+                (name, node)
+            })
+        })
+        .collect();
+    upload_dir(ctx, ProgressBar::hidden(), dir, String::from("(all sources)"))
+}
+
+/// Counts what's read through it.
+struct Counted<R> {
+    inner: R,
+    count: Arc<AtomicUsize>,
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n, Ordering::Relaxed);
+        Ok(n)
+    }
+}
+
+/// Uploads everything on stdin as the file `name`, in a directory of its own
+/// that is the root of the archive. How much there is only shows once it's
+/// all been read, so its bar just counts up.
+fn upload_stdin<'a>(ctx: &'a Context<'a>, name: String) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
+    let path = PathBuf::from("-");
+    if ctx.dry_run.is_some() {
+        info!("Would upload stdin as '{}'", name);
+        ctx.stats.file_uploaded(0);
+        ctx.file_done(0);
+        let dir: Dir = vec![(name, DirNode::new(String::from(DRY_RUN_FILECAP), None))]
+            .into_iter()
+            .collect();
+        return upload_dir(ctx, ProgressBar::hidden(), dir, String::from("(stdin)"));
+    }
+
+    // There's no size to go by.
+    let compression = ctx.compress.filter(|_| worth_compressing(Path::new(&name), u64::MAX));
+    info!("Uploading stdin as '{}'", name);
+    let pb = Arc::new(ctx.bar(ProgressBar::new_spinner()));
+    pb.set_style(scanning_style());
+    pb.set_message(&name);
+    let pb2 = pb.clone();
+    let sent = ctx.sent.clone();
+    let read = Arc::new(AtomicUsize::new(0));
+    let stdin = Counted {
+        inner: io::stdin(),
+        count: read.clone(),
+    };
+    Box::new(
+        ctx.client
+            .upload_reader_to(&ctx.mirror_clients(), stdin, compression, move |n| {
+                pb2.inc(n as u64);
+                sent.fetch_add(n, Ordering::Relaxed);
+            })
+            .then(move |res| -> Box<Future<Item = Result<String>, Error = Error>> {
+                pb.finish_and_clear();
+                let cap = match res {
+                    Ok(mut caps) => {
+                        let cap = caps.remove(0);
+                        ctx.record_mirrors(&path, &cap, &caps);
+                        cap
+                    }
+                    Err(e) => return Box::new(future::ok(Err(Error::with_chain(e, "Couldn't upload stdin")))),
+                };
+                let size = read.load(Ordering::Relaxed) as u64;
+                ctx.stats.file_uploaded(size);
+                ctx.file_done(size);
+                ctx.event(Event::Finished { path: &path, cap: &cap });
+                let mut node = DirNode::new(cap, None);
+                if let Some(compression) = compression {
+                    node.insert_metadata(names::COMPRESSION_KEY, compression.name());
+                }
+                let dir: Dir = vec![(name, node)].into_iter().collect();
+                upload_dir(ctx, ProgressBar::hidden(), dir, String::from("(stdin)"))
+            }),
+    )
+}
+
+/// Another grid that gets every backup as well, linked below a target of its
+/// own.
+pub struct Mirror {
+    pub client: Tahoe,
+    /// The directory on that grid the archive is linked into, like
+    /// `BackupConfig::target`.
+    pub target: String,
+}
+
+/// What to back up, where to and how. Apart from `sources`, `target` and
+/// `database`, everything is off or unset by default.
+#[derive(Default)]
+pub struct BackupConfig {
+    /// The files and directories to back up. A single one is the root of the
+    /// archive, several get a directory holding each of them by name.
+    pub sources: Vec<PathBuf>,
+    /// Back up what's read from stdin instead of `sources`, as the only file
+    /// in the archive, of this name.
+    pub stdin_name: Option<String>,
+    /// The directory the archive is linked into, at `snapshot_name` and
+    /// `latest_link`.
+    pub target: String,
+    /// Where below `target` the archives go, as `/`-separated names, so
+    /// several machines can share one target. Made if it isn't there yet.
+    pub target_subdir: Option<String>,
+    /// Other grids to upload everything to in the same pass, reading each
+    /// file once. Each gets the same archive below its own target, and
+    /// subdirectory if there is one. The database keeps what every cap is on
+    /// each of them, and only reuses what all of them have.
+    pub mirrors: Vec<Mirror>,
+    /// A shell command to run once the archive is attached, given its cap and
+    /// those of the archives pruned after it as arguments.
+    pub post_archive_cmd: Option<String>,
+    /// Fail the backup if `post_archive_cmd` does, instead of only warning.
+    pub fail_on_post_archive_cmd: bool,
+    /// Where below `target` the archive goes, with the placeholders of
+    /// `snapshot::name`. Defaults to `Archives/{timestamp}`.
+    pub snapshot_name: Option<String>,
+    /// A `strftime` format for `{timestamp}`, instead of RFC 3339.
+    pub timestamp_format: Option<String>,
+    /// Let `timestamp_format` write a `/`, putting archives in directories
+    /// by time.
+    pub nested_timestamps: bool,
+    /// Fill in the time placeholders in the local time zone, not UTC.
+    pub local_time: bool,
+    /// Where below `target` the archive is linked as the latest one, usually
+    /// `Latest`. Without it, there's no such link.
+    pub latest_link: Option<String>,
+    /// Which of the earlier archives next to this one, named by the same
+    /// `snapshot_name`, to keep linked once it is. Without either rule, they
+    /// all are.
+    pub retention: retention::Policy,
+    /// The backup database, which remembers what's been uploaded before.
+    pub database: String,
+    /// How long to wait for the database while something else has it locked.
+    pub db_busy_timeout: Duration,
+    /// How often to checkpoint the database during the backup, so what's
+    /// recorded survives losing power, not just the process dying, once it's
+    /// at most about this old. Left to SQLite if unset.
+    pub checkpoint_interval: Option<Duration>,
+    /// Globs for the entries to leave out.
+    pub excludes: Vec<String>,
+    /// A file with more globs to leave out. Defaults to `.tahoebackupignore`
+    /// in each source, if there is one.
+    pub ignore_file: Option<PathBuf>,
+    /// If set, only files matching one of these globs are backed up, whether
+    /// or not they are also excluded.
+    pub includes: Option<Vec<String>>,
+    /// Skip everything on another file system than the source it's in.
+    pub one_file_system: bool,
+    /// Skip everything on mounts of these file system types, e.g. `tmpfs`.
+    pub exclude_fs_types: Vec<String>,
+    /// Skip files smaller than this many bytes.
+    pub min_file_size: Option<u64>,
+    /// Skip files larger than this many bytes.
+    pub max_file_size: Option<u64>,
+    /// Don't start unless the storage servers have this many bytes free
+    /// between them, on every grid that says how much they have.
+    pub min_free_space: Option<u64>,
+    /// Only go this many levels of directories down from each source. With
+    /// 0, only the files right in it are backed up.
+    pub max_depth: Option<usize>,
+    /// Leave out everything in directories tagged with a `CACHEDIR.TAG`
+    /// but the tag itself.
+    pub exclude_caches: bool,
+    /// Leave out what the `.gitignore` files in each source ignore, after
+    /// the excludes.
+    pub respect_gitignore: bool,
+    /// Only look at what would be uploaded, without sending anything to the
+    /// grid.
+    pub dry_run: bool,
+    /// Back up what symlinks point to, rather than skipping them. A source
+    /// that is itself a symlink is always backed up as what it points to,
+    /// whatever this is set to.
+    pub follow_symlinks: bool,
+    /// Only follow the symlinks that point somewhere inside the source
+    /// they're in, so nothing outside of it ends up in the archive. The
+    /// others are skipped, or stored with `store_symlinks`.
+    pub follow_internal_symlinks: bool,
+    /// Store the targets of symlinks, rather than skipping them.
+    pub store_symlinks: bool,
+    /// Files last modified at or before this time, in seconds since the
+    /// epoch, keep the cap recorded for them without being looked at again.
+    pub newer_than: Option<i64>,
+    /// As `newer_than`, with the time of the last run in the database.
+    pub since_last_backup: bool,
+    /// Tell whether a file changed since it was uploaded by its size and
+    /// mtime alone, for file systems whose ctimes come and go or change
+    /// without the contents.
+    pub ignore_ctime: bool,
+    /// Fail on FIFOs, sockets and devices instead of skipping them.
+    pub fail_on_special: bool,
+    /// Leave out the directories below the sources that can't be read for
+    /// lack of permission, as if they were excluded. Otherwise they fail,
+    /// and are missing from the archive like any other failure.
+    pub skip_unreadable: bool,
+    /// Log the cap that each file skipped as unchanged reuses, to check
+    /// against the grid.
+    pub list_skipped: bool,
+    /// Stop at the first failure, and fail with it.
+    pub fail_fast: bool,
+    /// Store extended attributes in the metadata of every child.
+    pub xattrs: bool,
+    /// Reuse the directories the interrupted run before this one finished.
+    /// Otherwise they're forgotten.
+    pub resume: bool,
+    /// Upload every file and directory again, as if the database were empty,
+    /// and record the caps they get. Files with several hardlinks are still
+    /// only uploaded once per run.
+    pub no_reuse: bool,
+    /// Read back the size of every uploaded file before recording it. Only
+    /// that they exist is checked for compressed files.
+    pub verify_uploads: bool,
+    /// Compress the contents of files before uploading them, except for
+    /// small ones and those that already are compressed by the looks of
+    /// their name. They get different caps than uncompressed uploads of the
+    /// same contents, so files that haven't changed since they were uploaded
+    /// without compression are kept as they are.
+    pub compress: Option<Compression>,
+    /// Hash files to find their contents elsewhere in the database before
+    /// uploading them.
+    pub dedup_by_content: bool,
+    /// How many directories are uploaded at once. As many as the client has
+    /// threads if unset.
+    pub dir_concurrency: Option<usize>,
+    /// How long a cached directory cap may be reused before it's uploaded
+    /// again. Forever if unset.
+    pub dir_cache_ttl: Option<Duration>,
+    /// Upload every directory as a new mutable MDMF directory instead of an
+    /// immutable one. Its contents can be changed later through the write
+    /// cap, by anyone who has it, so unchanged directories can't be reused
+    /// from earlier runs: each run uploads all of them again, and they aren't
+    /// recorded in the database. Immutable files are still reused.
+    pub mutable_dirs: bool,
+    /// Make sure the node uploads with the convergence secret in this file,
+    /// by comparing it with the one in `node_dir`.
+    pub convergence_file: Option<PathBuf>,
+    /// The node's own directory, for `convergence_file`.
+    pub node_dir: PathBuf,
+    /// Draw progress bars on stderr. Without them, the stats are logged every
+    /// now and then.
+    pub progress: bool,
+    /// Walk the tree in the background first, to know the total size.
+    pub prescan: bool,
+    /// Where to write the JSON report, or stdout if it's `-`. It's written
+    /// whether or not the backup succeeds.
+    pub report: Option<PathBuf>,
+    /// Gets an `Event` for everything that happens to each entry.
+    pub events: Option<EventSink>,
+    /// Set this, e.g. on Ctrl-C, to stop starting anything new. What's
+    /// already running gets to finish and be recorded, then the backup fails
+    /// with `ErrorKind::Interrupted`.
+    pub interrupted: Arc<AtomicBool>,
+}
+
+/// Fails unless the storage servers of `client`'s grid have `min_free` bytes
+/// free between them. A node that doesn't say how much they have is only
+/// warned about. `mirror` is the node URL, if it's a mirror's grid.
+fn check_free_space(core: &mut Core, client: &Tahoe, min_free: u64, mirror: Option<&str>) -> Result<()> {
+    let node_url = client.node_url();
+    match core.run(client.grid_available_bytes()) {
+        Ok(Some(available)) if available < min_free => {
+            let e = Error::from(ErrorKind::GridFull(available, min_free));
+            Err(match mirror {
+                Some(node_url) => Error::with_chain(e, ErrorKind::Mirror(String::from(node_url))),
+                None => e,
+            })
+        }
+        Ok(Some(available)) => {
+            info!("The grid of {} has {} bytes free", node_url, available);
+            Ok(())
+        }
+        Ok(None) => {
+            warn!("{} doesn't say how much space its grid has free, backing up anyway", node_url);
+            Ok(())
+        }
+        Err(e) => {
+            warn!("Couldn't get how much space the grid of {} has free, backing up anyway: {}", node_url, e);
+            Ok(())
+        }
+    }
+}
+
+/// What a backup that got to the end did.
+pub struct BackupReport {
+    /// The cap linked as `Latest`, unless it was a dry run or stopped by a
+    /// failure with `fail_fast`.
+    pub latest: Option<String>,
+    /// Whatever failed is in `stats.failures`, and missing from the archive.
+    pub stats: Stats,
+}
+
+/// Backs up everything in `config` with `client`, running on `core`. The
+/// caller sets both up, as the clients of `config.mirrors` run on the same
+/// `core`.
+pub fn backup(core: &mut Core, client: &Tahoe, config: &BackupConfig) -> Result<BackupReport> {
+    let mut sources = Vec::new();
+    // Sources are taken by their real paths, so a source that's a symlink is
+    // backed up as what it points to, and links inside it can be told to
+    // point into it or out of it.
+    for path in &config.sources {
+        let logpath = path.to_string_lossy().into_owned();
+        let path =
+            fs::canonicalize(path).chain_err(|| format!("Couldn't find path '{}'", logpath))?;
+        let filter = build_filter(config, &path)?;
+        sources.push((path, filter));
+    }
+    let below_subdir = |target: &str| match config.target_subdir {
+        Some(ref subdir) => format!("{}/{}", target, subdir),
+        None => target.to_owned(),
+    };
+    let target = below_subdir(&config.target);
+    let target = target.as_str();
+    let mirror_targets: Vec<String> = config.mirrors.iter().map(|mirror| below_subdir(&mirror.target)).collect();
+    let mirror_targets = &mirror_targets;
+    // With the node URL of each mirror, to say which one a target is on.
+    let grids: Vec<(&Tahoe, &str, &str, Option<&str>)> = Some((client, config.target.as_str(), target, None))
+        .into_iter()
+        .chain(config.mirrors.iter().zip(mirror_targets).map(|(mirror, target)| {
+            let node_url = Some(mirror.client.node_url());
+            (&mirror.client, mirror.target.as_str(), target.as_str(), node_url)
+        }))
+        .collect();
+    let target_err = |e, target: &str, mirror: Option<&str>| {
+        let e = Error::with_chain(e, ErrorKind::Target(String::from(target)));
+        match mirror {
+            Some(node_url) => Error::with_chain(e, ErrorKind::Mirror(String::from(node_url))),
+            None => e,
+        }
+    };
+    let db = BackupDB::with_options(&config.database, config.db_busy_timeout, client.threads() as u32)?;
+    let newer_than = if config.since_last_backup {
+        db.latest_upload().map(|time| {
+            time.duration_since(UNIX_EPOCH)
+                .map(|since| since.as_secs() as i64)
+                .unwrap_or(0)
+        })
+    } else {
+        config.newer_than
+    };
+    check_convergence(config)?;
+    // A bad name would only show once everything is uploaded.
+    let snapshot_name = config.snapshot_name.as_ref().map_or(snapshot::DEFAULT_NAME, |name| name.as_str());
+    let times = snapshot::TimeFormat {
+        timestamp: config.timestamp_format.clone(),
+        local: config.local_time,
+    };
+    times.check(config.nested_timestamps)?;
+    let name = snapshot::name(snapshot_name, Utc::now(), &times)?;
+    if let Some(ref latest) = config.latest_link {
+        if *latest == name || !snapshot::valid_link(latest) {
+            bail!(ErrorKind::SnapshotName(latest.clone()));
+        }
+    }
+    for &(_, _, target, mirror) in &grids {
+        if !is_writable_dir_cap(target) {
+            let e = Error::from("target must be a writable directory capability");
+            return Err(target_err(e, target, mirror));
+        }
+    }
+    // A dry run never attaches anything, so it doesn't need the nodes.
+    if !config.dry_run {
+        for &(client, base, target, mirror) in &grids {
+            // Other machines may keep their archives in the same target, so
+            // the subdirectory is only made if it isn't there yet.
+            if let Some(ref subdir) = config.target_subdir {
+                let mkdir = client.mkdir(base, subdir).into_future().flatten();
+                core.run(mkdir).map_err(|e| target_err(e.into(), target, mirror))?;
+            }
+            let check = client.check_writable_dir(target).into_future().flatten();
+            core.run(check).map_err(|e| target_err(e.into(), target, mirror))?;
+            if let Some(min_free) = config.min_free_space {
+                check_free_space(core, client, min_free, mirror)?;
+            }
+        }
+    }
+    // Directories are journaled as they're done, so an interrupted run can
+    // be resumed. Anything else starts over.
+    if !config.resume && !config.dry_run {
+        db.clear_journal()?;
+    }
+    let hasher = if config.dedup_by_content {
+        Some(CpuPool::new(client.threads()))
+    } else {
+        None
+    };
+    let started = Utc::now().to_rfc3339();
+    let stats = Stats::default();
+    let mp = if config.progress {
+        Some(Arc::new(MultiProgress::new()))
+    } else {
+        None
+    };
+    let add_bar = |bar: ProgressBar| match mp {
+        Some(ref mp) => {
+            let bar = mp.add(bar);
+            bar.enable_steady_tick(100);
+            bar
+        }
+        None => ProgressBar::hidden(),
+    };
+    let tally = if config.dry_run {
+        let tally = add_bar(ProgressBar::new_spinner());
+        tally.set_style(dir_style());
+        Some(tally)
+    } else {
+        None
+    };
+    let (overall, prescan) = if tally.is_none() && config.prescan && config.stdin_name.is_none() {
+        let overall = Arc::new(add_bar(ProgressBar::new_spinner()));
+        overall.set_style(scanning_style());
+        overall.set_message("total (still scanning)");
+        let bar = overall.clone();
+        let prescan = Prescan::start(
+            sources.clone(),
+            config.follow_symlinks,
+            config.follow_internal_symlinks,
+            move |totals| {
+                bar.set_length(totals.bytes);
+                bar.set_style(style());
+                bar.set_message(&format!("total ({} files)", totals.files));
+            },
+        );
+        (Some(overall), Some(prescan))
+    } else {
+        (None, None)
+    };
+    let throughput = Throughput::new();
+    let rate = if tally.is_none() && mp.is_some() {
+        let rate = add_bar(ProgressBar::new_spinner());
+        rate.set_style(dir_style());
+        rate.set_message("throughput");
+        Some(rate)
+    } else {
+        None
+    };
+    // With none at all the walk would never finish.
+    let dir_concurrency = config.dir_concurrency.unwrap_or_else(|| client.concurrency()).max(1);
+    let ctx = Context {
+        progress: mp.as_deref(),
+        client,
+        mirrors: &config.mirrors,
+        db: &db,
+        stats: &stats,
+        dry_run: tally.as_ref(),
+        overall: overall.as_deref(),
+        sent: throughput.counter(),
+        events: config.events.clone(),
+        dir_concurrency,
+        dir_cache_ttl: config.dir_cache_ttl,
+        mutable_dirs: config.mutable_dirs,
+        follow_symlinks: config.follow_symlinks,
+        follow_internal_symlinks: config.follow_internal_symlinks,
+        roots: sources.iter().map(|(path, _)| path.clone()).collect(),
+        store_symlinks: config.store_symlinks,
+        newer_than,
+        ignore_ctime: config.ignore_ctime,
+        fail_on_special: config.fail_on_special,
+        skip_unreadable: config.skip_unreadable,
+        list_skipped: config.list_skipped,
+        fail_fast: config.fail_fast,
+        hardlinks: RefCell::new(HashMap::new()),
+        xattrs: config.xattrs,
+        resume: config.resume,
+        no_reuse: config.no_reuse,
+        verify_uploads: config.verify_uploads,
+        compress: config.compress,
+        hasher: hasher.as_ref(),
+        interrupted: &config.interrupted,
+    };
+    let ctx = &ctx;
+    let sources = &sources;
+    // Sources are backed up one after the other. A single one is the root of
+    // the archive itself.
+    let walks: Box<Future<Item = Result<String>, Error = Error>> = match config.stdin_name {
+        Some(ref name) => upload_stdin(ctx, name.clone()),
+        None => Box::new(
+            stream::iter_ok(sources.iter())
+                .and_then(move |(path, filter)| {
+                    Walk::new(ctx, filter, path.clone(), ctx.metadata(path))
+                })
+                .collect()
+                .and_then(move |mut results| -> Box<Future<Item = Result<String>, Error = Error>> {
+                    if results.len() == 1 {
+                        return Box::new(future::ok(results.pop().unwrap()));
+                    }
+                    upload_sources(ctx, sources, results)
+                }),
+        ),
+    };
+    let work = walks.and_then(|res| {
+        res.map(|cap| -> Box<Future<Item = Option<String>, Error = Error>> {
+            if tally.is_some() {
+                return Box::new(future::ok(None));
+            }
+            let now = Utc::now();
+            let (name, (dir, series)) = match snapshot::name(snapshot_name, now, &times)
+                .and_then(|name| Ok((name, snapshot::series(snapshot_name, now, &times)?)))
+            {
+                Ok(named) => named,
+                Err(e) => return Box::new(future::err(e)),
+            };
+            let mut links = vec![name.clone()];
+            links.extend(config.latest_link.clone());
+            let shown: Vec<String> = links.iter().map(|link| format!("'{}'", link)).collect();
+            info!("Adding links {}", shown.join(" and "));
+            let mirror_links = links.clone();
+            Box::new(
+                attach_links(client, target, links, cap.clone())
+                    .and_then(move |_| attach_mirrors(ctx, mirror_targets, mirror_links, cap.clone()).map(|_| cap))
+                    .and_then(move |cap| {
+                        let files = ctx.stats.files_uploaded.get() + ctx.stats.files_skipped.get();
+                        let bytes = ctx.stats.bytes_uploaded.get() + ctx.stats.bytes_skipped.get();
+                        ctx.db.record_run(now.naive_utc(), &cap, files as i64, bytes as i64, &run_meta(client))?;
+                        ctx.db.clear_journal()?;
+                        Ok(cap)
+                    })
+                    .and_then(move |cap| -> Box<Future<Item = (String, Vec<String>), Error = Error>> {
+                        if !config.retention.is_set() {
+                            return Box::new(future::ok((cap, Vec::new())));
+                        }
+                        // The new archive is there either way, so failing to
+                        // prune the old ones doesn't fail the backup. Mirrors
+                        // are pruned by the same rules.
+                        let pruning = retention::Pruning {
+                            dir,
+                            series,
+                            current: name,
+                            latest: config.latest_link.clone(),
+                            policy: config.retention,
+                        };
+                        let prunings: Vec<_> = Some((client, target, String::new()))
+                            .into_iter()
+                            .chain(ctx.mirrors.iter().zip(mirror_targets).map(|(mirror, target)| {
+                                (&mirror.client, target.as_str(), format!(" on '{}'", mirror.client.node_url()))
+                            }))
+                            .map(|(client, target, grid)| {
+                                retention::prune(client, target, pruning.clone(), now).then(move |res| {
+                                    let pruned = match res {
+                                        Ok(pruned) => pruned,
+                                        Err(e) => {
+                                            let context = format!("Couldn't prune the old archives{}", grid);
+                                            log_chain(&Error::with_chain(e, context));
+                                            Vec::new()
+                                        }
+                                    };
+                                    if !pruned.is_empty() {
+                                        info!("Unlinked {} old archives{}", pruned.len(), grid);
+                                    }
+                                    Ok::<_, Error>(pruned)
+                                })
+                            })
+                            .collect();
+                        // What the command gets is what was pruned on the
+                        // main grid.
+                        Box::new(future::join_all(prunings).map(move |mut pruned| (cap, pruned.remove(0))))
+                    })
+                    .and_then(move |(cap, pruned)| {
+                        let command = match config.post_archive_cmd {
+                            Some(ref command) => command,
+                            None => return Ok(Some(cap)),
+                        };
+                        info!("Running '{}'", command);
+                        match hook::post_archive(command, &cap, &pruned) {
+                            Err(e) if config.fail_on_post_archive_cmd => Err(e),
+                            Err(e) => {
+                                log_chain(&e);
+                                Ok(Some(cap))
+                            }
+                            Ok(()) => Ok(Some(cap)),
+                        }
+                    }),
+            )
+        })
+    })
+        .flatten();
+    let work = match config.checkpoint_interval {
+        Some(interval) => {
+            let checkpoints = Interval::new(interval, &core.handle())?
+                .for_each(|_| {
+                    debug!("Checkpointing the database");
+                    if let Err(e) = db.checkpoint() {
+                        log_err(e);
+                    }
+                    Ok(())
+                })
+                .from_err()
+                .map(|_| None);
+            future::Either::A(work.select(checkpoints).map(|(res, _)| res).map_err(|(e, _)| e))
+        }
+        None => future::Either::B(work),
+    };
+    let drawer = mp.as_ref().map(|mp| {
+        let bar = mp.add(ProgressBar::hidden());
+        let mp = mp.clone();
+        (bar, thread::spawn(move || mp.join()))
+    });
+    // Without bars, the stats are logged every now and then instead.
+    let result = match (drawer.as_ref(), rate.as_ref()) {
+        (Some(_), Some(rate)) => {
+            let update = Interval::new(Duration::from_secs(THROUGHPUT_SECS), &core.handle())?
+                .for_each(|_| {
+                    rate.set_message(&format!("throughput: {}", throughput.sample()));
+                    Ok(())
+                })
+                .from_err()
+                .map(|_| None);
+            core.run(work.select(update).map(|(res, _)| res).map_err(|(e, _)| e))
+        }
+        (Some(_), None) => core.run(work),
+        (None, _) => {
+            let log_stats = Interval::new(Duration::from_secs(PROGRESS_LOG_SECS), &core.handle())?
+                .for_each(|_| {
+                    if tally.is_some() {
+                        info!("Progress: {}", stats);
+                    } else {
+                        info!("Progress: {}, sending {}", stats, throughput.sample());
+                    }
+                    Ok(())
+                })
+                .from_err()
+                .map(|_| None);
+            core.run(work.select(log_stats).map(|(res, _)| res).map_err(|(e, _)| e))
+        }
+    };
+    if let Some(prescan) = prescan {
+        stats.bytes_total.set(prescan.finish().map(|totals| totals.bytes));
+    }
+    if tally.is_none() {
+        stats.bytes_sent.set(throughput.sent());
+        stats.elapsed.set(Some(throughput.elapsed()));
+    }
+    // Every bar has to be finished for the drawing thread to return.
+    if let Some(ref overall) = overall {
+        overall.finish_and_clear();
+    }
+    if let Some(ref tally) = tally {
+        tally.finish_and_clear();
+    }
+    if let Some(ref rate) = rate {
+        rate.finish_and_clear();
+    }
+    if let Some((bar, drawer)) = drawer {
+        bar.finish();
+        drawer.join().ok();
+    }
+    if let Some(ref report) = config.report {
+        let latest = result.as_ref().ok().and_then(|cap| cap.as_deref());
+        let error = result.as_ref().err();
+        write_report(report, &stats.report(started, tally.is_some(), latest, error))?;
+    }
+    // A failure stops the backup like Ctrl-C does with `fail_fast`, but it's
+    // the failure that's reported.
+    if config.fail_fast && !stats.failures.is_empty() {
+        return Ok(BackupReport { latest: None, stats });
+    }
+    let latest = result?;
+    Ok(BackupReport { latest, stats })
+}
+
+/// Reads a convergence secret, ignoring surrounding whitespace.
+fn read_convergence(path: &Path) -> Result<String> {
+    let mut secret = String::new();
+    fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut secret))
+        .chain_err(|| ErrorKind::ConvergenceRead(path.display().to_string()))?;
+    Ok(secret.trim().to_owned())
+}
+
+/// Makes sure the node uploads with the secret in `convergence_file`.
+///
+/// The web API has no way to pass a convergence secret along with an upload,
+/// or to ask the node which one it uses: it's part of the node's own
+/// configuration, in `private/convergence` below its directory. So that's
+/// read and compared, which only works with access to the node's directory.
+fn check_convergence(config: &BackupConfig) -> Result<()> {
+    let wanted = match config.convergence_file {
+        Some(ref path) => read_convergence(path)?,
+        None => return Ok(()),
+    };
+    let node_path = config.node_dir.join("private/convergence");
+    if read_convergence(&node_path)? != wanted {
+        bail!(ErrorKind::ConvergenceMismatch(node_path.display().to_string()));
+    }
+    info!("The node uses the convergence secret from --convergence-file");
+    Ok(())
+}
+
+/// Links the archive `cap` at each of `links` below `target`. Every link is
+/// tried, however the others go, so the error can say which ones are missing.
+fn attach_links<'a>(
+    client: &'a Tahoe,
+    target: &str,
+    links: Vec<String>,
+    cap: String,
+) -> Box<Future<Item = (), Error = Error> + 'a> {
+    let attempts: Vec<_> = links
+        .iter()
+        .map(|link| {
+            let added = link.clone();
+            client
+                .attach(target, link, &cap)
+                .into_future()
+                .flatten()
+                .inspect(move |_| info!("Added link '{}'", added))
+                .then(Ok::<_, Error>)
+        })
+        .collect();
+    let join = future::join_all(attempts);
+    Box::new(join.and_then(move |results| {
+        let mut missing = Vec::new();
+        let mut first = None;
+        for (link, res) in links.iter().zip(results) {
+            if let Err(e) = res {
+                missing.push(format!("'{}'", link));
+                match first {
+                    None => first = Some(e),
+                    Some(_) => log_chain(&Error::with_chain(e, format!("Couldn't link '{}'", link))),
+                }
+            }
+        }
+        match first {
+            None => Ok(()),
+            Some(e) => Err(Error::with_chain(e, ErrorKind::Attach(cap, missing.join(" and ")))),
+        }
+    }))
+}
+
+/// Links the archive `cap` at each of `links` below the target of every
+/// mirror in `targets`, as the cap it has there.
+fn attach_mirrors<'a>(
+    ctx: &'a Context<'a>,
+    targets: &[String],
+    links: Vec<String>,
+    cap: String,
+) -> Box<Future<Item = (), Error = Error> + 'a> {
+    let attempts: Vec<_> = ctx.mirrors
+        .iter()
+        .zip(targets)
+        .map(|(mirror, target)| {
+            let node_url = String::from(mirror.client.node_url());
+            let attach: Box<Future<Item = (), Error = Error>> = match ctx.mirror_cap(mirror, &cap) {
+                Some(mirrored) => attach_links(&mirror.client, target, links.clone(), mirrored),
+                None => Box::new(future::err("the archive isn't on the mirror".into())),
+            };
+            attach.map_err(move |e| Error::with_chain(e, ErrorKind::Mirror(node_url)))
+        })
+        .collect();
+    Box::new(future::join_all(attempts).map(|_| ()))
+}
+
+/// What to record about the grid a run goes to, so its archive can later be
+/// looked for in the right place.
+fn run_meta(client: &Tahoe) -> RunMeta {
+    let encoding = client.encoding();
+    RunMeta {
+        node_url: String::from(client.node_url()),
+        shares_needed: encoding.needed.map(|k| k as i32),
+        shares_happy: encoding.happy.map(|happy| happy as i32),
+        shares_total: encoding.total.map(|n| n as i32),
+    }
+}
+
+/// Writes the JSON report to `path`, or to stdout if that is `-`.
+fn write_report(path: &Path, report: &Report) -> Result<()> {
+    if path == Path::new("-") {
+        let stdout = io::stdout();
+        let mut stdout = stdout.lock();
+        serde_json::to_writer_pretty(&mut stdout, report).chain_err(|| "Couldn't write report")?;
+        writeln!(stdout)?;
+        return Ok(());
+    }
+    let mut f = fs::File::create(path)
+        .chain_err(|| ErrorKind::FileOpen(path.to_string_lossy().into_owned()))?;
+    serde_json::to_writer_pretty(&mut f, report).chain_err(|| "Couldn't write report")?;
+    writeln!(f)?;
+    Ok(())
+}
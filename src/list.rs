@@ -0,0 +1,53 @@
+use futures::{future, Future, IntoFuture};
+
+use tahoe::client::{ChildInfo, NodeType, Tahoe};
+
+use errors::*;
+
+/// An archive found in a backup target.
+pub struct Entry {
+    pub name: String,
+    pub child: ChildInfo,
+}
+
+/// Reads the archives in `target`: `Latest` and everything under `Archives`,
+/// sorted by name.
+pub fn archives<'a>(client: &'a Tahoe, target: &str) -> Box<Future<Item = Vec<Entry>, Error = Error> + 'a> {
+    Box::new(
+        client
+            .read_dir(target)
+            .into_future()
+            .flatten()
+            .from_err()
+            .and_then(move |children| {
+                let mut archives = None;
+                let mut entries = Vec::new();
+                for (name, child) in children {
+                    if name == "Archives" && child.nodetype == NodeType::Dir {
+                        archives = child.uri().map(String::from);
+                    } else {
+                        entries.push(Entry { name, child });
+                    }
+                }
+                let more: Box<Future<Item = Vec<Entry>, Error = Error>> = match archives {
+                    Some(cap) => Box::new(client.read_dir(&cap).into_future().flatten().from_err().map(
+                        |children| {
+                            children
+                                .into_iter()
+                                .map(|(name, child)| Entry {
+                                    name: format!("Archives/{}", name),
+                                    child,
+                                })
+                                .collect()
+                        },
+                    )),
+                    None => Box::new(future::ok(Vec::new())),
+                };
+                more.map(move |more| {
+                    entries.extend(more);
+                    entries.sort_by(|a, b| a.name.cmp(&b.name));
+                    entries
+                })
+            }),
+    )
+}
@@ -1,12 +1,14 @@
+extern crate atty;
 extern crate backupdb;
 extern crate chrono;
+extern crate ctrlc;
 extern crate env_logger;
-extern crate filetime;
-extern crate futures;
-extern crate globset;
-extern crate indicatif;
+extern crate serde;
+extern crate serde_json;
 extern crate tahoe;
+extern crate tahoe_backup;
 extern crate tokio_core;
+extern crate toml;
 
 #[macro_use]
 extern crate log;
@@ -17,290 +19,795 @@ extern crate error_chain;
 #[macro_use]
 extern crate clap;
 
-mod errors {
-    use tahoe;
-    use backupdb;
-    error_chain!{
-        links {
-            Tahoe(tahoe::errors::Error, tahoe::errors::ErrorKind);
-            BackupDB(backupdb::errors::Error, backupdb::errors::ErrorKind);
-        }
-        foreign_links {
-            Io(::std::io::Error);
-        }
-        errors {
-            FileOpen(path: String) {
-                description("Couldn't open file"),
-                display("Couldn't open file: '{}'", path),
-            }
-            ReadMetadata(path: String) {
-                description("Couldn't read metadata"),
-                display("Couldn't read metadata: '{}'", path),
-            }
-            FileUpload(path: String) {
-                description("Couldn't upload file"),
-                display("Couldn't upload file: '{}'", path),
-            }
-            GlobParse(glob: String) {
-                description("Couldn't parse glob"),
-                display("Couldn't parse glob: '{}'", glob),
-            }
-            UnknownFile(path: String) {
-                description("Unexpected file"),
-                display("Unexpected file: '{}'", path),
-            }
-        }
-    }
-}
+#[macro_use]
+extern crate serde_derive;
 
-use std::{env, fs, io};
-use std::fs::File;
-use std::path::PathBuf;
+mod aliases;
+mod config;
+mod json_progress;
+
+use std::{cmp, env, fs, io, process};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
-use std::thread;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, SystemTime};
 
 use tokio_core::reactor::Core;
 
-use futures::{future, stream, Future, IntoFuture, Stream};
-
-use tahoe::client::{Dir, DirNode, Tahoe};
+use tahoe::client::{ChildInfo, Encoding, Options, Tahoe};
 
 use backupdb::BackupDB;
 
+use tahoe_backup::{diff, errors, import, leases, list, log_err, names, restore, retention, selftest,
+                   verify, BackupConfig, Mirror};
+use tahoe_backup::failures::Failures;
+
 use errors::*;
 
-use filetime::FileTime;
+use config::Config;
 
-use clap::Arg;
+use log::LevelFilter;
 
-use chrono::Utc;
+use clap::{AppSettings, Arg, ArgMatches, SubCommand};
 
-use globset::{Glob, GlobSet, GlobSetBuilder};
+use chrono::{DateTime, TimeZone, Utc};
 
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+/// Exit code when some files or directories failed, some caps aren't
+/// healthy, or some leases couldn't be renewed.
+const PARTIAL_EXIT_CODE: i32 = 1;
+/// Exit code when nothing could be done at all, including usage errors.
+const FATAL_EXIT_CODE: i32 = 2;
+/// Exit code after Ctrl-C, as if killed by SIGINT.
+const INTERRUPTED_EXIT_CODE: i32 = 130;
 
-fn ok_or_log<T, E>(res: std::result::Result<T, E>) -> Option<T>
-where
-    E: Into<Error>,
-{
-    match res {
-        Ok(x) => Some(x),
-        Err(e) => {
-            log_err(e);
-            None
+/// Prints a usage error and exits with `FATAL_EXIT_CODE`. Help and version
+/// output still exit successfully.
+fn usage_error(e: clap::Error) -> ! {
+    match e.kind {
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => e.exit(),
+        _ => {
+            eprintln!("{}", e.message);
+            process::exit(FATAL_EXIT_CODE);
         }
     }
 }
 
-fn style() -> ProgressStyle {
-    ProgressStyle::default_bar()
-        .template(
-            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {bytes}/{total_bytes} ({eta}) {wide_msg}",
-        )
-        .progress_chars("#>-")
+fn backup(core: &mut Core, client: &Tahoe, matches: &ArgMatches, config: &Config) -> Result<()> {
+    if matches.is_present("db-readonly") {
+        usage_error(clap::Error::with_description(
+            "A backup writes to the database, it can't be made with --db-readonly",
+            clap::ErrorKind::ArgumentConflict,
+        ));
+    }
+    let mut sources: Vec<PathBuf> = matches.values_of_os("path").unwrap().map(PathBuf::from).collect();
+    let target = sources.pop().unwrap();
+    let target = target
+        .to_str()
+        .ok_or_else(|| format!("Target isn't valid UTF-8: {}", target.to_string_lossy()))?;
+    let target = &resolve_target(matches, target)?;
+    let stdin_name = if sources.iter().any(|source| source.as_os_str() == "-") {
+        if sources.len() > 1 {
+            usage_error(clap::Error::with_description(
+                "'-' can't be backed up along with other paths",
+                clap::ErrorKind::ArgumentConflict,
+            ));
+        }
+        sources.clear();
+        match matches.value_of("name") {
+            Some(name) if restore::safe_name(name).is_some() => Some(String::from(name)),
+            Some(name) => bail!("Invalid name for stdin: '{}'", name),
+            None => usage_error(clap::Error::with_description(
+                "Backing up stdin needs --name",
+                clap::ErrorKind::MissingRequiredArgument,
+            )),
+        }
+    } else {
+        None
+    };
+    let target_subdir = match matches.value_of("target-subdir") {
+        Some(subdir) => {
+            let names: Vec<&str> = subdir.trim_matches('/').split('/').collect();
+            if names.iter().any(|name| restore::safe_name(name).is_none()) {
+                bail!("Invalid target subdirectory: '{}'", subdir);
+            }
+            Some(names.join("/"))
+        }
+        None => None,
+    };
+    let mirror_nodes: Vec<&str> = matches.values_of("mirror-node").map(|urls| urls.collect()).unwrap_or_default();
+    let mirror_targets: Vec<&str> = matches.values_of("mirror-target").map(|caps| caps.collect()).unwrap_or_default();
+    if mirror_nodes.len() != mirror_targets.len() {
+        usage_error(clap::Error::with_description(
+            "Every --mirror-node needs a --mirror-target",
+            clap::ErrorKind::WrongNumberOfValues,
+        ));
+    }
+    let mut mirrors = Vec::new();
+    for (node_url, target) in mirror_nodes.into_iter().zip(mirror_targets) {
+        mirrors.push(Mirror {
+            client: connect(core, matches, Some(node_url), None)?,
+            target: String::from(target),
+        });
+    }
+    let newer_than = match matches.value_of("newer-than") {
+        Some(value) => Some(
+            DateTime::parse_from_rfc3339(value)
+                .chain_err(|| ErrorKind::DateTimeParse(String::from(value)))?
+                .timestamp(),
+        ),
+        None => None,
+    };
+    let excludes = match (matches.values_of("exclude"), config.exclude.as_ref()) {
+        (Some(items), _) => items.map(String::from).collect(),
+        (None, Some(items)) => items.clone(),
+        (None, None) => Vec::new(),
+    };
+    let report = matches.value_of_os("report");
+    let events = if matches.value_of("progress-format") == Some("json") {
+        Some(json_progress::sink(progress_output(matches)?))
+    } else {
+        None
+    };
+    let interrupted = Arc::new(AtomicBool::new(false));
+    let flag = interrupted.clone();
+    ctrlc::set_handler(move || {
+        if flag.swap(true, Ordering::SeqCst) {
+            process::exit(INTERRUPTED_EXIT_CODE);
+        }
+        warn!("Interrupted, waiting for running uploads to finish. Press Ctrl-C again to quit now.");
+    }).chain_err(|| "Couldn't set up Ctrl-C handler")?;
+    let config = BackupConfig {
+        sources,
+        stdin_name,
+        target: String::from(target),
+        target_subdir,
+        mirrors,
+        post_archive_cmd: matches.value_of("post-archive-cmd").map(String::from),
+        fail_on_post_archive_cmd: matches.is_present("fail-on-post-archive-cmd"),
+        snapshot_name: matches.value_of("snapshot-name").map(String::from),
+        timestamp_format: matches.value_of("timestamp-format").map(String::from),
+        nested_timestamps: matches.is_present("nested-timestamps"),
+        local_time: matches.is_present("local-time"),
+        latest_link: match matches.value_of("latest-link").unwrap() {
+            "off" => None,
+            link => Some(String::from(link)),
+        },
+        retention: retention::Policy {
+            keep: optional_value(matches, "keep"),
+            keep_within: optional_value(matches, "keep-within").map(|Age(age)| age),
+        },
+        database: String::from(matches.value_of("database").unwrap()),
+        db_busy_timeout: db_busy_timeout(matches),
+        checkpoint_interval: optional_value(matches, "checkpoint-interval")
+            .map(|secs: u64| Duration::from_secs(secs.max(1))),
+        excludes,
+        ignore_file: matches.value_of_os("ignore-file").map(PathBuf::from),
+        includes: matches.values_of("include").map(|items| items.map(String::from).collect()),
+        one_file_system: matches.is_present("one-file-system"),
+        exclude_fs_types: matches
+            .values_of("exclude-fstype")
+            .map(|types| types.map(String::from).collect())
+            .unwrap_or_default(),
+        min_file_size: optional_value(matches, "min-file-size").map(|Size(bytes)| bytes),
+        max_file_size: optional_value(matches, "max-file-size").map(|Size(bytes)| bytes),
+        min_free_space: optional_value(matches, "min-free-space").map(|Size(bytes)| bytes),
+        max_depth: optional_value(matches, "max-depth"),
+        exclude_caches: matches.is_present("exclude-caches"),
+        respect_gitignore: matches.is_present("respect-gitignore"),
+        dry_run: matches.is_present("dry-run"),
+        follow_symlinks: matches.is_present("follow-symlinks"),
+        follow_internal_symlinks: matches.is_present("follow-internal-symlinks"),
+        store_symlinks: matches.is_present("store-symlinks"),
+        newer_than,
+        since_last_backup: matches.is_present("since-last-backup"),
+        ignore_ctime: matches.is_present("no-ctime"),
+        fail_on_special: matches.is_present("fail-on-special"),
+        skip_unreadable: matches.is_present("skip-unreadable"),
+        list_skipped: matches.is_present("list-skipped"),
+        fail_fast: matches.is_present("fail-fast"),
+        xattrs: matches.is_present("xattrs"),
+        resume: matches.is_present("resume"),
+        no_reuse: matches.is_present("no-reuse"),
+        verify_uploads: matches.is_present("verify-after-upload"),
+        dedup_by_content: matches.is_present("dedup-by-content"),
+        compress: optional_value(matches, "compress"),
+        dir_concurrency: optional_value(matches, "dir-concurrency"),
+        dir_cache_ttl: optional_value(matches, "dir-cache-ttl")
+            .map(|days: u64| Duration::from_secs(days * 24 * 60 * 60)),
+        mutable_dirs: matches.is_present("mutable-dirs"),
+        convergence_file: matches.value_of_os("convergence-file").map(PathBuf::from),
+        node_dir: PathBuf::from(matches.value_of_os("node-dir").unwrap()),
+        // Bars only make sense on a terminal, which indicatif checks as
+        // well, and by default stay out of the way of a report on stdout.
+        progress: events.is_none() && atty::is(atty::Stream::Stderr)
+            && (matches.is_present("progress")
+                || (!matches.is_present("no-progress") && report != Some("-".as_ref()))),
+        prescan: !matches.is_present("no-prescan"),
+        report: report.map(PathBuf::from),
+        events,
+        interrupted,
+    };
+    let done = tahoe_backup::backup(core, client, &config)?;
+    if config.dry_run {
+        if report != Some("-".as_ref()) {
+            println!("Dry run: {}", done.stats);
+        }
+    } else if !matches.is_present("quiet") {
+        eprintln!("{}", done.stats);
+    }
+    // Whatever failed is missing from the archive.
+    check_failures(&done.stats.failures)
 }
 
-fn dir_style() -> ProgressStyle {
-    ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {wide_msg}")
+/// Prints a summary of `failures` to stderr and fails with
+/// `ErrorKind::Incomplete`, if there are any.
+fn check_failures(failures: &Failures) -> Result<()> {
+    let lines = failures.lines();
+    if lines.is_empty() {
+        return Ok(());
+    }
+    eprintln!("{} files or directories failed:", lines.len());
+    for line in &lines {
+        eprintln!("  {}", line);
+    }
+    bail!(ErrorKind::Incomplete(lines.len()))
 }
 
-fn finished_style() -> ProgressStyle {
-    ProgressStyle::default_bar()
-        .template(
-            "{spinner:.green} [{elapsed_precise}] [{bar:40.cyan/blue}] {total_bytes} {wide_msg}",
-        )
-        .progress_chars("#>-")
+/// Where `--progress-format json` writes to: `--progress-fd`, or stderr.
+fn progress_output(matches: &ArgMatches) -> Result<Box<io::Write + Send>> {
+    match optional_value::<i32>(matches, "progress-fd") {
+        Some(fd) => progress_fd(fd),
+        None => Ok(Box::new(io::stderr())),
+    }
 }
 
-fn upload<'a>(
-    progress: &'a MultiProgress,
-    globset: &'a Option<GlobSet>,
-    client: &'a Tahoe,
-    db: &'a BackupDB,
-    path: String,
-    metadata: io::Result<fs::Metadata>,
-) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
-    if metadata.is_err() {
-        return Box::new(future::ok(
-            metadata
-                .map(|_| String::new())
-                .chain_err(|| ErrorKind::ReadMetadata(path.clone())),
-        ));
+#[cfg(unix)]
+fn progress_fd(fd: i32) -> Result<Box<io::Write + Send>> {
+    use std::fs::File;
+    use std::os::unix::io::FromRawFd;
+    // The standard ones are only reopened for the others' sake.
+    match fd {
+        1 => Ok(Box::new(io::stdout())),
+        2 => Ok(Box::new(io::stderr())),
+        fd if fd > 2 => Ok(Box::new(unsafe { File::from_raw_fd(fd) })),
+        fd => bail!("Can't write progress to fd {}", fd),
     }
+}
 
-    let metadata = metadata.unwrap();
+#[cfg(not(unix))]
+fn progress_fd(_fd: i32) -> Result<Box<io::Write + Send>> {
+    bail!("--progress-fd is only supported on Unix")
+}
 
-    let file_type = metadata.file_type();
-    if file_type.is_symlink() {
-        return Box::new(future::ok(Err("not following symlink".into())));
+/// Prints what the database has for a file, one `key: value` per line, `-`
+/// for what it doesn't have.
+fn lookup(matches: &ArgMatches) -> Result<()> {
+    let db = open_db(matches)?;
+    let path = PathBuf::from(matches.value_of_os("path").unwrap());
+    // Files are recorded by where they really are, but a file that's gone
+    // may still have a record.
+    let path = fs::canonicalize(&path).unwrap_or(path);
+    let key = names::db_key(&path);
+    let record = db.lookup_file(&key)
+        .ok_or_else(|| format!("No record of '{}' in the database", path.display()))?;
+    let show = |value: Option<String>| value.unwrap_or_else(|| String::from("-"));
+    println!("path: {}", key);
+    println!("cap: {}", record.filecap);
+    println!("size: {}", record.file.size);
+    println!("ctime: {}", record.file.ctime);
+    println!("mtime: {}", record.file.mtime);
+    println!(
+        "last_uploaded: {}",
+        show(record.last_uploaded.map(|time| DateTime::<Utc>::from_utc(time, Utc).to_rfc3339()))
+    );
+    println!("compression: {}", show(record.compression));
+    println!("contenthash: {}", show(record.file.contenthash));
+    Ok(())
+}
+
+fn prune(matches: &ArgMatches) -> Result<()> {
+    let db = open_db(matches)?;
+    let stats = db.prune(names::db_path, matches.is_present("caps"))?;
+    println!("Removed {} missing files and {} unused caps", stats.files, stats.caps);
+    Ok(())
+}
+
+fn verify(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    let db = open_db(matches)?;
+    let elsewhere = db.list_runs()?
+        .iter()
+        .filter_map(|run| run.meta())
+        .filter(|meta| meta.node_url != client.node_url())
+        .count();
+    if elsewhere > 0 {
+        warn!(
+            "{} backups in the database were made through another node than {}, their caps may not be on this grid",
+            elsewhere,
+            client.node_url()
+        );
+    }
+    let mut caps = db.file_caps(None)?;
+    caps.extend(db.dir_caps(None)?);
+    let tally = core.run(verify::verify(client, caps))?;
+    println!("{}", tally);
+    if tally.problems() > 0 {
+        bail!(ErrorKind::Unhealthy(tally.problems()));
     }
+    Ok(())
+}
 
-    if file_type.is_file() {
-        let size = metadata.len();
-        let ctime = FileTime::from_creation_time(&metadata)
-            .unwrap_or(FileTime::zero())
-            .seconds() as i64;
-        let mtime = FileTime::from_last_modification_time(&metadata).seconds() as i64;
+fn deep_check(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    let target = &resolve_target(matches, matches.value_of("target").unwrap())?;
+    let tally = core.run(verify::deep_check(client, target, matches.is_present("repair")))?;
+    println!("{}", tally);
+    if tally.unhealthy > 0 {
+        bail!(ErrorKind::Unhealthy(tally.unhealthy));
+    }
+    Ok(())
+}
 
-        if let Some(cap) = db.check_file(&path, size as i64, ctime, mtime) {
-            info!("Skipping '{}'", path);
-            return Box::new(future::ok(Ok(cap)));
-        }
+fn self_test(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    let target = &resolve_target(matches, matches.value_of("target").unwrap())?;
+    let test = core.run(selftest::self_test(client, target))?;
+    println!(
+        "Self-test passed: uploaded {} bytes as '{}', read them back, linked and unlinked them in {:.1}s",
+        test.bytes,
+        test.cap,
+        test.elapsed.as_secs() as f64 + f64::from(test.elapsed.subsec_nanos()) / 1e9
+    );
+    Ok(())
+}
 
-        let f = match File::open(&path).chain_err(|| ErrorKind::FileOpen(path.clone())) {
-            Ok(x) => x,
-            Err(e) => return Box::new(future::ok(Err(e))),
-        };
-        info!("Uploading file '{}'", &path);
-        let logpath = path.clone();
-        let pb = Arc::new(progress.add(ProgressBar::new(size)));
-        pb.set_style(style());
-        pb.set_message(&path);
-        let pb2 = pb.clone();
-        return Box::new(
-            client
-                .upload_file(f, move |n| pb2.inc(n as u64))
-                .inspect(move |cap| {
-                    pb.set_style(finished_style());
-                    pb.finish_and_clear();
-                    info!("'{}' -> '{}'", &logpath, cap);
-                    ok_or_log(db.add_file(&cap, logpath, size as i64, ctime, mtime));
-                    ()
-                })
-                .map_err(move |e| Error::with_chain(e, ErrorKind::FileUpload(path)))
-                .map(Ok),
-        );
+fn renew_leases(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    let db = open_db(matches)?;
+    let before = optional_value(matches, "only-older-than")
+        .map(|days: u64| SystemTime::now() - Duration::from_secs(days * 24 * 60 * 60));
+    let mut caps = db.file_caps(before)?;
+    caps.extend(db.dir_caps(before)?);
+    let tally = core.run(leases::renew(client, caps))?;
+    println!("Renewed {} leases, {} failed", tally.renewed, tally.failed);
+    if tally.failed > 0 {
+        bail!(ErrorKind::LeaseRenewal(tally.failed));
     }
+    Ok(())
+}
 
-    if file_type.is_dir() {
-        let files = fs::read_dir(path.clone());
-        if files.is_err() {
-            return Box::new(future::ok(
-                files
-                    .map(|_| String::new())
-                    .chain_err(|| "couldn't read dir"),
-            ));
+/// One of k/happy/N as recorded, `-` meaning the node's default.
+fn show_share(param: Option<i32>) -> String {
+    param.map_or_else(|| String::from("-"), |p| p.to_string())
+}
+
+fn list(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    if matches.is_present("local") {
+        let db = open_db(matches)?;
+        for run in db.list_runs()? {
+            let grid = match run.meta() {
+                Some(meta) => format!(
+                    "{} ({}/{}/{})",
+                    meta.node_url,
+                    show_share(meta.shares_needed),
+                    show_share(meta.shares_happy),
+                    show_share(meta.shares_total)
+                ),
+                None => String::from("-"),
+            };
+            println!(
+                "{}\t{} files\t{} bytes\t{}\t{}",
+                DateTime::<Utc>::from_utc(run.timestamp, Utc).to_rfc3339(),
+                run.file_count,
+                run.byte_count,
+                grid,
+                run.root_cap
+            );
+        }
+        return Ok(());
+    }
+    let target = &resolve_target(matches, matches.value_of("target").unwrap())?;
+    let entries = core.run(list::archives(client, target))?;
+    let long = matches.is_present("long");
+    for entry in &entries {
+        let size = match entry.child.size {
+            Some(size) => size.to_string(),
+            None => String::from("-"),
+        };
+        let cap = entry.child.uri().unwrap_or("-");
+        if long {
+            println!(
+                "{}\t{}\t{}\t{}\t{}",
+                entry.name,
+                size,
+                show_time(&entry.child, "ctime"),
+                show_time(&entry.child, "mtime"),
+                cap
+            );
+        } else {
+            println!("{}\t{}\t{}", entry.name, size, cap);
         }
+    }
+    Ok(())
+}
 
-        let files = files.unwrap();
-        let logpath = path.clone();
-        let pb = progress.add(ProgressBar::new_spinner());
-        pb.set_style(dir_style());
-        pb.set_message(&path);
-        pb.enable_steady_tick(100);
-        return Box::new(
-            stream::iter_ok(
-                files
-                    .filter_map(ok_or_log)
-                    .filter(move |entry| {
-                        if let &Some(ref globs) = globset {
-                            !globs.is_match(entry.path())
-                        } else {
-                            true
-                        }
-                    })
-                    .map(move |entry| {
-                        let path = entry.path().to_string_lossy().into_owned();
-                        upload(
-                            progress,
-                            globset,
-                            client,
-                            db,
-                            path.clone(),
-                            entry.metadata(),
-                        ).map(move |f| {
-                            f.map(|res| {
-                                (
-                                    entry
-                                        .path()
-                                        .file_name()
-                                        .unwrap()
-                                        .to_string_lossy()
-                                        .into_owned(),
-                                    DirNode::new(res, entry.metadata()),
-                                )
-                            })
-                        })
-                    }),
-            ).buffered(client.threads())
-                .filter_map(ok_or_log)
-                .collect()
-                .inspect(move |_| info!("Uploading dir '{}'", path))
-                .map(|v| v.iter().cloned().collect())
-                .and_then(move |dir| upload_dir(pb, client, db, dir, logpath)),
-        );
+/// The time recorded under `key` in the metadata of `child`, or `-`.
+fn show_time(child: &ChildInfo, key: &str) -> String {
+    match child.time(key) {
+        Some(secs) => Utc.timestamp(secs as i64, 0).to_rfc3339(),
+        None => String::from("-"),
     }
+}
 
-    Box::new(future::ok(Err(ErrorKind::UnknownFile(path).into())))
+fn restore(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    let source = &resolve_target(matches, matches.value_of("source").unwrap())?;
+    let dest = PathBuf::from(matches.value_of_os("dest").unwrap());
+    let failures = Failures::default();
+    let opts = restore::Options {
+        fail_fast: matches.is_present("fail-fast"),
+        xattrs: matches.is_present("xattrs"),
+    };
+    let result = core.run(restore::restore(client, &failures, opts, String::from(source), dest));
+    check_failures(&failures)?;
+    result?
 }
 
-fn upload_dir<'a>(
-    pb: ProgressBar,
-    client: &'a Tahoe,
-    db: &'a BackupDB,
-    dir: Dir,
-    path: String,
-) -> Box<Future<Item = Result<String>, Error = Error> + 'a> {
-    let hash = dir.hash() as i64;
-    match db.check_dir(hash) {
-        Some(cap) => {
-            info!("Reusing directory '{}'", path);
-            pb.finish_and_clear();
-            Box::new(future::ok(Ok(cap)))
+fn diff(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    let source = &resolve_target(matches, matches.value_of("source").unwrap())?;
+    let local = PathBuf::from(matches.value_of_os("local").unwrap());
+    let failures = Failures::default();
+    let changes = core.run(diff::diff(client, &failures, source, local, PathBuf::new()));
+    if let Ok(ref changes) = changes {
+        if matches.is_present("json") {
+            let json = serde_json::to_string_pretty(changes).chain_err(|| "Failed to serialize differences")?;
+            println!("{}", json);
+        } else {
+            for change in changes {
+                println!("{} {}", change.kind.prefix(), change.path);
+            }
         }
-        None => Box::new(
-            client
-                .upload_dir(&dir)
-                .into_future()
-                .flatten()
-                .inspect(move |cap| {
-                    ok_or_log(db.add_dir(hash, &cap));
-                    pb.finish_and_clear();
-                    info!("'{}' -> '{}'", path, cap)
-                })
-                .map(Ok)
-                .map_err(|e| Error::with_chain(e, "couldn't upload dir")),
-        ),
+    }
+    check_failures(&failures)?;
+    changes.map(|_| ())
+}
+
+fn import(core: &mut Core, client: &Tahoe, matches: &ArgMatches) -> Result<()> {
+    let db = open_db(matches)?;
+    let source = &resolve_target(matches, matches.value_of("source").unwrap())?;
+    let local = PathBuf::from(matches.value_of_os("local").unwrap());
+    // Files are recorded by where they really are.
+    let local = fs::canonicalize(&local).chain_err(|| format!("Couldn't find path '{}'", local.display()))?;
+    let failures = Failures::default();
+    let imported = core.run(import::import(client, &db, &failures, source, local))?;
+    println!("Imported {} files and {} directories", imported.files, imported.dirs);
+    check_failures(&failures)
+}
+
+/// `target` as a cap, looking it up in `--aliases-file` if it's an alias.
+fn resolve_target(matches: &ArgMatches, target: &str) -> Result<String> {
+    aliases::resolve(Path::new(matches.value_of_os("aliases-file").unwrap()), target)
+}
+
+fn db_busy_timeout(matches: &ArgMatches) -> Duration {
+    Duration::from_millis(value_t!(matches, "db-busy-timeout", u64).unwrap_or_else(|e| usage_error(e)))
+}
+
+fn threads(matches: &ArgMatches) -> usize {
+    matches.value_of("threads").unwrap().parse().unwrap_or(4)
+}
+
+/// A client for the node at `node_url`, uploading through `upload_url` if
+/// there is one, with everything else as `matches` says.
+fn connect(core: &Core, matches: &ArgMatches, node_url: Option<&str>, upload_url: Option<&str>) -> Result<Tahoe> {
+    let threads = threads(matches);
+    let encoding = Encoding {
+        needed: optional_value(matches, "shares-needed"),
+        happy: optional_value(matches, "shares-happy"),
+        total: optional_value(matches, "shares-total"),
+    };
+    Ok(Tahoe::new(
+        &core.handle(),
+        Options {
+            base: node_url.map(String::from),
+            upload_base: upload_url.map(String::from),
+            num_threads: optional_value(matches, "read-threads").unwrap_or(threads).max(1),
+            concurrency: optional_value(matches, "upload-concurrency").unwrap_or(threads).max(1),
+            max_connections: optional_value(matches, "max-connections"),
+            retries: matches.value_of("retries").unwrap().parse().unwrap_or(3),
+            timeout: optional_value(matches, "request-timeout").map(Duration::from_secs),
+            encoding,
+            socks5: socks5_proxy(matches),
+            user_agent: String::from(matches.value_of("user-agent").unwrap()),
+        },
+    )?)
+}
+
+fn open_db(matches: &ArgMatches) -> Result<BackupDB> {
+    let open = if matches.is_present("db-readonly") {
+        BackupDB::open_readonly
+    } else {
+        BackupDB::with_options
+    };
+    Ok(open(
+        matches.value_of("database").unwrap(),
+        db_busy_timeout(matches),
+        threads(matches) as u32,
+    )?)
+}
+
+/// Parses an optional numeric argument, exiting with a usage error if it's
+/// malformed.
+fn optional_value<T: FromStr>(matches: &ArgMatches, name: &str) -> Option<T> {
+    if matches.is_present(name) {
+        Some(value_t!(matches, name, T).unwrap_or_else(|e| usage_error(e)))
+    } else {
+        None
+    }
+}
+
+/// A number of bytes, optionally with a binary `K`, `M`, `G` or `T` suffix.
+struct Size(u64);
+
+impl FromStr for Size {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        let s = s.trim();
+        let (digits, shift) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+            Some('K') => (&s[..s.len() - 1], 10),
+            Some('M') => (&s[..s.len() - 1], 20),
+            Some('G') => (&s[..s.len() - 1], 30),
+            Some('T') => (&s[..s.len() - 1], 40),
+            _ => (s, 0),
+        };
+        let n: u64 = digits.parse().map_err(|_| ())?;
+        n.checked_mul(1 << shift).map(Size).ok_or(())
     }
 }
 
-fn log_err<E>(err: E)
-where
-    E: Into<Error>,
-{
-    for (i, e) in err.into().iter().enumerate() {
-        warn!("{}{}", " ".repeat(i), e)
+/// A length of time in days, or with an `h`, `d` or `w` suffix in hours, days
+/// or weeks.
+struct Age(Duration);
+
+impl FromStr for Age {
+    type Err = ();
+
+    fn from_str(s: &str) -> std::result::Result<Self, ()> {
+        let s = s.trim();
+        let (digits, hours) = match s.chars().last().map(|c| c.to_ascii_lowercase()) {
+            Some('h') => (&s[..s.len() - 1], 1),
+            Some('d') => (&s[..s.len() - 1], 24),
+            Some('w') => (&s[..s.len() - 1], 7 * 24),
+            _ => (s, 24),
+        };
+        let n: u64 = digits.parse().map_err(|_| ())?;
+        n.checked_mul(hours * 60 * 60)
+            .map(|secs| Age(Duration::from_secs(secs)))
+            .ok_or(())
+    }
+}
+
+/// The SOCKS5 proxy to connect through: `--socks5`, or else `ALL_PROXY` if
+/// it's a `socks5://` or `socks5h://` URL. Either way host names are resolved
+/// by the proxy.
+fn socks5_proxy(matches: &ArgMatches) -> Option<String> {
+    if let Some(proxy) = matches.value_of("socks5") {
+        return Some(String::from(proxy));
+    }
+    let proxy = env::var("ALL_PROXY").or_else(|_| env::var("all_proxy")).ok()?;
+    for scheme in &["socks5://", "socks5h://"] {
+        if let Some(rest) = proxy.strip_prefix(scheme) {
+            return Some(rest.trim_end_matches('/').to_owned());
+        }
     }
+    if !proxy.is_empty() {
+        warn!("Ignoring ALL_PROXY '{}', only SOCKS5 proxies are supported", proxy);
+    }
+    None
 }
 
-fn build_globset<'a, I: Iterator<Item = &'a str>>(iter: I) -> Result<GlobSet> {
-    let mut builder = GlobSetBuilder::new();
-    for item in iter {
-        builder.add(Glob::new(item).chain_err(|| ErrorKind::GlobParse(String::from(item)))?);
+/// Logs warnings and errors by default, only errors with `-q` and more with
+/// every `-v`. Other crates don't get more verbose than warnings, and
+/// `RUST_LOG` overrides all of it.
+fn init_logging(matches: &ArgMatches) {
+    let mut builder = env_logger::Builder::from_env(env_logger::Env::default());
+    if env::var_os("RUST_LOG").is_none() {
+        let level = if matches.is_present("quiet") {
+            LevelFilter::Error
+        } else {
+            match matches.occurrences_of("verbose") {
+                0 => LevelFilter::Warn,
+                1 => LevelFilter::Info,
+                2 => LevelFilter::Debug,
+                _ => LevelFilter::Trace,
+            }
+        };
+        builder.filter(None, cmp::min(level, LevelFilter::Warn));
+        for module in &["tahoe_backup", "tahoe", "backupdb"] {
+            builder.filter(Some(module), level);
+        }
     }
-    builder.build().chain_err(|| "Failed to build globset")
+    builder.init();
 }
 
 fn run() -> Result<()> {
-    env_logger::init();
-    let mut default_database = env::home_dir().unwrap_or_else(PathBuf::new);
-    default_database.push(".tahoe/private/rust-backupdb.sqlite");
-    let default_database = default_database.into_os_string();
+    let config = match config::default_path() {
+        Some(path) => Config::load(&path)?,
+        None => Config::default(),
+    };
+    let default_database = match config.database {
+        Some(ref path) => path.clone().into_os_string(),
+        None => {
+            let mut path = env::home_dir().unwrap_or_default();
+            path.push(".tahoe/private/rust-backupdb.sqlite");
+            path.into_os_string()
+        }
+    };
+    let default_threads = config.threads.unwrap_or(4).to_string();
+    let default_user_agent = format!("tahoe-backup/{}", crate_version!());
+    let mut default_node_dir = env::home_dir().unwrap_or_default();
+    default_node_dir.push(".tahoe");
+    let default_aliases = default_node_dir.join("private/aliases").into_os_string();
+    let default_node_dir = default_node_dir.into_os_string();
     let matches = app_from_crate!()
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(
+            Arg::with_name("quiet")
+                .short("q")
+                .long("quiet")
+                .conflicts_with("verbose")
+                .help("Only log errors, and leave out the summary at the end")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("verbose")
+                .short("v")
+                .long("verbose")
+                .multiple(true)
+                .help("Log what's being done; twice or three times for debugging (RUST_LOG overrides this)")
+                .global(true),
+        )
         .arg(
             Arg::with_name("threads")
                 .short("t")
                 .long("threads")
-                .help("Sets the amount of threads to use")
-                .default_value("4"),
+                .help(
+                    "Sets the amount of threads to use, which is how many files are read at once \
+                     and how many are uploaded at once, unless they're set apart",
+                )
+                .default_value(&default_threads)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("read-threads")
+                .long("read-threads")
+                .value_name("N")
+                .help("How many files to read from disk at once [default: --threads]")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("upload-concurrency")
+                .long("upload-concurrency")
+                .value_name("N")
+                .help("How many uploads, or other requests, to keep going at once [default: --threads]")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("retries")
+                .long("retries")
+                .help("How often to retry an upload after a transient error")
+                .default_value("3")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("max-connections")
+                .long("max-connections")
+                .value_name("N")
+                .help(
+                    "Open at most this many connections to the node, reused between requests. \
+                     Each upload keeps one while its file is read, so set this at least as high \
+                     as --upload-concurrency [default: no limit]",
+                )
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("request-timeout")
+                .long("request-timeout")
+                .value_name("SECS")
+                .help("Give up on an upload once it has made no progress for this long, then retry")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("fail-fast")
+                .long("fail-fast")
+                .help(
+                    "Stop at the first file or directory that fails, instead of carrying on \
+                     without it. Either way the exit status is 1 if anything failed, 2 if nothing could be backed up at all",
+                )
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("user-agent")
+                .long("user-agent")
+                .value_name("STRING")
+                .help("User-Agent sent with every request to the node")
+                .default_value(&default_user_agent)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("socks5")
+                .long("socks5")
+                .value_name("HOST:PORT")
+                .help(
+                    "Connect to the node through this SOCKS5 proxy, such as Tor's, which also \
+                     resolves its host name so .onion addresses work. The node URL must be \
+                     http:// [default: ALL_PROXY, if it's a socks5:// URL]",
+                )
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("node-url")
+                .long("node-url")
+                .value_name("URL")
+                .help("URL of the Tahoe node's web API, e.g. 'https://tahoe.example.com/' [default: 127.0.0.1:3456]")
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("upload-url")
+                .long("upload-url")
+                .value_name("URL")
+                .help(
+                    "URL of another node to upload files and directories through, e.g. one close to \
+                     the storage servers or with an upload helper. Reading and linking still go to \
+                     --node-url [default: the --node-url]",
+                )
+                .takes_value(true)
+                .global(true),
         )
         .arg(
             Arg::with_name("database")
                 .short("d")
                 .long("database")
                 .help("Location of the database file")
+                .env("TAHOE_BACKUP_DB")
                 .default_value_os(&default_database)
-                .takes_value(true),
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("aliases-file")
+                .long("aliases-file")
+                .value_name("PATH")
+                .help(
+                    "The Tahoe CLI's aliases, with a 'name: URI:DIR2:...' line for each. Any \
+                     target or source that isn't a cap is looked up here, as 'name:' or \
+                     'name:some/path' like the Tahoe CLI takes them",
+                )
+                .env("TAHOE_BACKUP_ALIASES")
+                .default_value_os(&default_aliases)
+                .takes_value(true)
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("db-busy-timeout")
+                .long("db-busy-timeout")
+                .value_name("MS")
+                .help("How long to wait for the database while another run has it locked")
+                .default_value("5000")
+                .global(true),
+        )
+        .arg(
+            Arg::with_name("db-readonly")
+                .long("db-readonly")
+                .help(
+                    "Only read the database, without migrating it or taking write locks, so \
+                     list --local, lookup or verify can run alongside a backup. Anything that \
+                     would write to it fails",
+                )
+                .global(true),
         )
         .arg(
             Arg::with_name("exclude")
@@ -311,63 +818,652 @@ fn run() -> Result<()> {
                 .takes_value(true),
         )
         .arg(
-            Arg::with_name("path")
-                .help("The folder to backup")
-                .required(true),
+            Arg::with_name("include")
+                .long("include")
+                .help(
+                    "Only back up files matching a glob pattern, even if they or a directory \
+                     above them match --exclude. Every directory is then gone into",
+                )
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("snapshot-name")
+                .long("snapshot-name")
+                .value_name("TEMPLATE")
+                .help(
+                    "Where to link each archive below the target. {hostname}, {date} and {time} (in \
+                     UTC unless --local-time) and {timestamp} are filled in [default: \
+                     Archives/{timestamp}]",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("timestamp-format")
+                .long("timestamp-format")
+                .value_name("FORMAT")
+                .help("A strftime format for {timestamp}, e.g. %Y-%m-%d_%H%M [default: RFC 3339]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("nested-timestamps")
+                .long("nested-timestamps")
+                .requires("timestamp-format")
+                .conflicts_with_all(&["keep", "keep-within"])
+                .help("Allow --timestamp-format to write '/', putting archives in directories by time"),
+        )
+        .arg(
+            Arg::with_name("local-time")
+                .long("local-time")
+                .help("Fill in {timestamp}, {date} and {time} in the local time zone instead of UTC"),
+        )
+        .arg(
+            Arg::with_name("target-subdir")
+                .long("target-subdir")
+                .value_name("PATH")
+                .help(
+                    "Link archives below this directory of the target instead, e.g. to keep each \
+                     machine's apart. It's made if it isn't there yet",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mirror-node")
+                .long("mirror-node")
+                .value_name("URL")
+                .help(
+                    "Also upload everything to the grid of this node, reading each file only once, \
+                     and link the archive below the matching --mirror-target there. Can be given \
+                     more than once",
+                )
+                .multiple(true)
+                .number_of_values(1)
+                .requires("mirror-target")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mirror-target")
+                .long("mirror-target")
+                .value_name("DIRCAP")
+                .help(
+                    "The directory to link archives in on each --mirror-node, in the same order. \
+                     Pruning applies there as well, and --target-subdir goes below it",
+                )
+                .multiple(true)
+                .number_of_values(1)
+                .requires("mirror-node")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("post-archive-cmd")
+                .long("post-archive-cmd")
+                .value_name("COMMAND")
+                .help(
+                    "Run this shell command once the archive is linked, with its cap as $1 and the \
+                     caps of the archives pruned after it as the rest of the arguments",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("fail-on-post-archive-cmd")
+                .long("fail-on-post-archive-cmd")
+                .requires("post-archive-cmd")
+                .help("Fail the backup if --post-archive-cmd fails, instead of only warning"),
+        )
+        .arg(
+            Arg::with_name("latest-link")
+                .long("latest-link")
+                .value_name("NAME")
+                .help("Where to link the latest archive below the target, or 'off' for nowhere")
+                .default_value("Latest"),
+        )
+        .arg(
+            Arg::with_name("name")
+                .long("name")
+                .value_name("NAME")
+                .help("What to name the file read from stdin when backing up '-', e.g. backup.tar")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keep")
+                .long("keep")
+                .value_name("N")
+                .help(
+                    "After the backup, unlink all but the newest N archives of the --snapshot-name \
+                     series. The latest link is never touched",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("keep-within")
+                .long("keep-within")
+                .value_name("DURATION")
+                .help(
+                    "After the backup, unlink the archives of the series linked longer ago than this, \
+                     in days or with an h, d or w suffix. With --keep, what either keeps is kept",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("ignore-file")
+                .long("ignore-file")
+                .value_name("PATH")
+                .help("Read more exclude globs from this file [default: <path>/.tahoebackupignore]")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("report")
+                .long("report")
+                .value_name("PATH")
+                .help("Write a JSON summary of the run to a file, or to stdout for '-'")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("progress")
+                .long("progress")
+                .overrides_with("no-progress")
+                .help("Show progress bars even with --report -, as long as stderr is a terminal"),
+        )
+        .arg(
+            Arg::with_name("no-progress")
+                .long("no-progress")
+                .overrides_with("progress")
+                .help("Log the stats once a minute instead of showing progress bars"),
+        )
+        .arg(
+            Arg::with_name("progress-format")
+                .long("progress-format")
+                .value_name("FORMAT")
+                .possible_values(&["bars", "json"])
+                .help(
+                    "With json, write a line of JSON for every file started, finished, skipped or \
+                     failed, and its progress a few times a second, instead of showing bars",
+                )
+                .default_value("bars"),
+        )
+        .arg(
+            Arg::with_name("progress-fd")
+                .long("progress-fd")
+                .value_name("FD")
+                .help("Write --progress-format json to this open file descriptor instead of stderr")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .short("n")
+                .long("dry-run")
+                .help("Only report what would be uploaded, without sending anything"),
+        )
+        .arg(
+            Arg::with_name("no-prescan")
+                .long("no-prescan")
+                .help("Don't scan the tree for its total size alongside the backup"),
+        )
+        .arg(
+            Arg::with_name("follow-symlinks")
+                .short("L")
+                .long("follow-symlinks")
+                .help(
+                    "Back up the files and folders symlinks point to. A <path> that is a \
+                     symlink is always backed up as what it points to",
+                ),
+        )
+        .arg(
+            Arg::with_name("follow-internal-symlinks")
+                .long("follow-internal-symlinks")
+                .conflicts_with("follow-symlinks")
+                .help(
+                    "Only follow symlinks that point inside the <path> they're in, skipping \
+                     or storing the rest as without --follow-symlinks",
+                ),
+        )
+        .arg(
+            Arg::with_name("store-symlinks")
+                .long("store-symlinks")
+                .conflicts_with("follow-symlinks")
+                .help("Back up symlinks as links, to be recreated on restore"),
+        )
+        .arg(
+            Arg::with_name("one-file-system")
+                .long("one-file-system")
+                .help("Skip everything on a different file system than <path>, like mount points"),
+        )
+        .arg(
+            Arg::with_name("exclude-fstype")
+                .long("exclude-fstype")
+                .value_name("TYPES")
+                .help("Skip everything on mounts of these file system types, e.g. tmpfs,nfs (Linux only)")
+                .takes_value(true)
+                .multiple(true)
+                .use_delimiter(true)
+                .number_of_values(1),
+        )
+        .arg(
+            Arg::with_name("max-file-size")
+                .long("max-file-size")
+                .value_name("SIZE")
+                .help("Skip files larger than this, e.g. 500M or 2G")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-free-space")
+                .long("min-free-space")
+                .value_name("SIZE")
+                .help(
+                    "Don't start unless the storage servers of the grid, and of every mirror, have \
+                     this much free between them, e.g. 50G. A node that doesn't say only gets a warning",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("min-file-size")
+                .long("min-file-size")
+                .value_name("SIZE")
+                .help("Skip files smaller than this, e.g. 4K")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("respect-gitignore")
+                .long("respect-gitignore")
+                .help("Also skip what .gitignore files in the tree ignore, as git would"),
+        )
+        .arg(
+            Arg::with_name("exclude-caches")
+                .long("exclude-caches")
+                .help("Skip the contents of directories with a CACHEDIR.TAG, except the tag itself"),
+        )
+        .arg(
+            Arg::with_name("max-depth")
+                .long("max-depth")
+                .value_name("N")
+                .help("Only back up this many levels of directories below <path>, 0 for just its files")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("newer-than")
+                .long("newer-than")
+                .value_name("DATETIME")
+                .help(
+                    "Keep the recorded caps of files not modified since this RFC 3339 time, \
+                     without checking them further",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("since-last-backup")
+                .long("since-last-backup")
+                .conflicts_with("newer-than")
+                .help("Like --newer-than, with the time of the latest upload in the database"),
+        )
+        .arg(
+            Arg::with_name("no-ctime")
+                .long("no-ctime")
+                .help("Tell whether files changed by their size and mtime only, ignoring the ctime"),
+        )
+        .arg(
+            Arg::with_name("fail-on-special")
+                .long("fail-on-special")
+                .help("Treat FIFOs, sockets and devices as errors instead of skipping them"),
+        )
+        .arg(
+            Arg::with_name("skip-unreadable")
+                .long("skip-unreadable")
+                .help("Leave out directories that can't be read for lack of permission, instead of failing"),
+        )
+        .arg(
+            Arg::with_name("list-skipped")
+                .long("list-skipped")
+                .help("Log the cap reused by each file that's skipped as unchanged (shown with -v)"),
+        )
+        .arg(
+            Arg::with_name("compress")
+                .long("compress")
+                .value_name("METHOD")
+                .possible_values(&["gzip", "zstd"])
+                .help(
+                    "Compress files before uploading them, except small ones and those with the \
+                     extension of a compressed format",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dedup-by-content")
+                .long("dedup-by-content")
+                .help("Hash changed files to find moved or copied ones that are already uploaded"),
+        )
+        .arg(
+            Arg::with_name("xattrs")
+                .long("xattrs")
+                .help("Back up extended attributes, at the cost of a few more system calls per file"),
+        )
+        .arg(
+            Arg::with_name("resume")
+                .long("resume")
+                .help(
+                    "Continue an interrupted backup, taking the directories it finished as they \
+                     were then instead of reading them again",
+                ),
+        )
+        .arg(
+            Arg::with_name("checkpoint-interval")
+                .long("checkpoint-interval")
+                .value_name("SECS")
+                .help(
+                    "Checkpoint the database this often, which syncs it to disk. Every file and \
+                     directory is recorded as soon as it's done, and already survives a crash of \
+                     tahoe-backup itself, but only what was synced survives losing power",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("no-reuse")
+                .long("no-reuse")
+                .alias("force")
+                .conflicts_with("resume")
+                .help(
+                    "Upload everything again instead of reusing what the database has, e.g. to \
+                     check the grid still takes it. Slow, and sends the whole tree",
+                ),
+        )
+        .arg(
+            Arg::with_name("verify-after-upload")
+                .long("verify-after-upload")
+                .help("Check that the grid has the right size for every uploaded file before recording it"),
+        )
+        .arg(
+            Arg::with_name("convergence-file")
+                .long("convergence-file")
+                .value_name("PATH")
+                .help(
+                    "Refuse to back up unless the node's convergence secret is the one in this \
+                     file, so identical files get identical caps on every machine sharing it. \
+                     Tahoe can't take the secret per upload; it has to be set in the node's \
+                     private/convergence, which is read from --node-dir",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("node-dir")
+                .long("node-dir")
+                .value_name("DIR")
+                .help("The Tahoe node's directory, for --convergence-file")
+                .default_value_os(&default_node_dir)
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dir-concurrency")
+                .long("dir-concurrency")
+                .value_name("N")
+                .help(
+                    "Upload this many directories at once, next to the files that --threads \
+                     allows [default: --threads]",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("dir-cache-ttl")
+                .long("dir-cache-ttl")
+                .value_name("DAYS")
+                .help("Upload directories again if their cached cap is older than this")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mutable-dirs")
+                .long("mutable-dirs")
+                .conflicts_with("dir-cache-ttl")
+                .help(
+                    "Upload directories as mutable MDMF directories, which can be changed later \
+                     but are uploaded again on every run",
+                ),
         )
         .arg(
-            Arg::with_name("target")
-                .help("The capability to upload into")
+            Arg::with_name("shares-needed")
+                .long("shares-needed")
+                .value_name("K")
+                .help("Shares needed to recover each uploaded file")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shares-happy")
+                .long("shares-happy")
+                .value_name("H")
+                .help("Servers that must receive shares for an upload to succeed")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("shares-total")
+                .long("shares-total")
+                .value_name("N")
+                .help("Shares to create for each uploaded file")
+                .takes_value(true),
+        )
+        // The target is the last of these values rather than an argument of
+        // its own, as clap gives the first path to the target when a
+        // positional that takes several values follows an option's value.
+        .arg(
+            Arg::with_name("path")
+                .help(
+                    "The folders to back up, followed by the capability to upload into, or an \
+                     alias from --aliases-file such as 'backups:'. \
+                     Several folders are put side by side in the archive, named after \
+                     their last component. '-' backs up stdin as a single file, see --name",
+                )
+                .value_names(&["path...", "target"])
+                .min_values(2)
                 .required(true),
         )
-        .get_matches();
-    let threads: usize = matches.value_of("threads").unwrap().parse().unwrap_or(4);
-    let path =
-        fs::canonicalize(matches.value_of_os("path").unwrap()).chain_err(|| "Couldn't find path")?;
-    let database = matches.value_of("database").unwrap();
-    let target = matches.value_of("target").unwrap();
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Restores a backed-up directory to disk")
+                .arg(
+                    Arg::with_name("source")
+                        .help("The directory capability to restore, e.g. 'URI:DIR2:.../Latest'")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("dest")
+                        .help("The folder to restore into")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("xattrs")
+                        .long("xattrs")
+                        .help("Restore extended attributes that were backed up with --xattrs"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("diff")
+                .about("Compares a backed-up directory with a local folder")
+                .after_help(
+                    "Prints a line for everything that differs: '+' for what's only in the \
+                     folder, '-' for what's only in the backup and 'M' for files whose type, \
+                     size or mtime, or symlinks whose target, changed.",
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("The directory capability to compare, e.g. 'URI:DIR2:.../Latest'")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("local")
+                        .help("The folder to compare it with")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("json")
+                        .long("json")
+                        .help("Print the differences as a JSON array"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Rebuilds the database from a backed-up directory")
+                .after_help(
+                    "Records the hash of every directory below SOURCE, and the cap of every \
+                     file whose local copy still has the size and mtime it was backed up with, \
+                     so the next backup can skip them. Use it after losing the database.",
+                )
+                .arg(
+                    Arg::with_name("source")
+                        .help("The directory capability to import, e.g. 'URI:DIR2:.../Latest'")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("local")
+                        .help("The folder it was backed up from")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("list")
+                .about("Lists the archives in a backup target")
+                .arg(
+                    Arg::with_name("target")
+                        .help("The directory capability backups are attached to")
+                        .required_unless("local"),
+                )
+                .arg(
+                    Arg::with_name("long")
+                        .short("l")
+                        .long("long")
+                        .help("Also show the recorded ctime and mtime"),
+                )
+                .arg(
+                    Arg::with_name("local")
+                        .long("local")
+                        .conflicts_with("long")
+                        .help("List the runs recorded in the database instead of asking the grid"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("verify")
+                .about("Checks that every cap in the database is still healthy on the grid"),
+        )
+        .subcommand(
+            SubCommand::with_name("deep-check")
+                .about("Has the node check a backed-up directory and everything below it")
+                .arg(
+                    Arg::with_name("target")
+                        .help("The directory capability to check, e.g. a backup target")
+                        .required(true),
+                )
+                .arg(
+                    Arg::with_name("repair")
+                        .long("repair")
+                        .help("Also repair whatever isn't healthy"),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("self-test")
+                .about(
+                    "Makes sure the node and a target work, by uploading a small file, reading it \
+                     back, and linking and unlinking it in the target",
+                )
+                .arg(
+                    Arg::with_name("target")
+                        .help("The directory capability to test, e.g. a backup target")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("renew-leases")
+                .about("Renews the leases on every cap in the database")
+                .arg(
+                    Arg::with_name("only-older-than")
+                        .long("only-older-than")
+                        .value_name("DAYS")
+                        .help("Only renew caps uploaded more than this many days ago")
+                        .takes_value(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("lookup")
+                .about("Shows what the database has recorded for a file, without uploading anything")
+                .arg(
+                    Arg::with_name("path")
+                        .help("The file to look up")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("prune")
+                .about("Removes database entries for local files that no longer exist")
+                .arg(
+                    Arg::with_name("caps")
+                        .long("caps")
+                        .help("Also remove caps that no local file refers to anymore"),
+                ),
+        )
+        .get_matches_safe()
+        .unwrap_or_else(|e| usage_error(e));
+    let (subcommand, submatches) = matches.subcommand();
+    let args = submatches.unwrap_or(&matches);
+    init_logging(args);
     let mut core = Core::new().unwrap();
-    let client = Tahoe::new(threads, &core.handle(), None).unwrap();
-    let db = BackupDB::new(database).unwrap();
-    let globset = match matches.values_of("exclude") {
-        Some(globs) => Some(build_globset(globs)?),
-        None => None,
-    };
-    let mp = Arc::new(MultiProgress::new());
-    let work = upload(
-        &mp,
-        &globset,
-        &client,
-        &db,
-        path.to_string_lossy().into_owned(),
-        fs::symlink_metadata(path),
-    ).and_then(|res| {
-        res.map(|cap| {
-            let datetime = format!("Archives/{}", Utc::now().to_rfc3339());
-            info!("Adding link 'Latest' and '{}'", datetime);
-            client
-                .attach(target, &datetime, &cap)
-                .unwrap()
-                .map_err(|e| Error::with_chain(e, "failed to attach archive"))
-                .inspect(|_| info!("Added Archives link"))
-                .join(
-                    client
-                        .attach(target, "Latest", &cap)
-                        .unwrap()
-                        .map_err(|e| Error::with_chain(e, "failed to attach archive"))
-                        .inspect(|_| info!("Added Latest link")),
-                )
-        })
-    })
-        .flatten();
-    let bar = mp.add(ProgressBar::hidden());
-    let mp2 = mp.clone();
-    thread::spawn(move || mp2.join());
-    core.run(work)?;
-    bar.finish();
-    Ok(())
+    let client = connect(
+        &core,
+        args,
+        args.value_of("node-url").or(config.node_url.as_deref()),
+        args.value_of("upload-url").or(config.upload_url.as_deref()),
+    )?;
+    match subcommand {
+        "list" => list(&mut core, &client, args),
+        "restore" => restore(&mut core, &client, args),
+        "diff" => diff(&mut core, &client, args),
+        "import" => import(&mut core, &client, args),
+        "prune" => prune(args),
+        "lookup" => lookup(args),
+        "verify" => verify(&mut core, &client, args),
+        "deep-check" => deep_check(&mut core, &client, args),
+        "self-test" => self_test(&mut core, &client, args),
+        "renew-leases" => renew_leases(&mut core, &client, args),
+        _ => backup(&mut core, &client, args, &config),
+    }
 }
 
 fn main() {
-    run().map_err(log_err).ok();
+    if let Err(e) = run() {
+        let code = match *e.kind() {
+            ErrorKind::Interrupted => INTERRUPTED_EXIT_CODE,
+            ErrorKind::Incomplete(_) | ErrorKind::Unhealthy(_) | ErrorKind::LeaseRenewal(_) => PARTIAL_EXIT_CODE,
+            _ => FATAL_EXIT_CODE,
+        };
+        log_err(e);
+        process::exit(code);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn size(s: &str) -> Option<u64> {
+        s.parse().ok().map(|Size(bytes)| bytes)
+    }
+
+    fn age(s: &str) -> Option<u64> {
+        s.parse().ok().map(|Age(age)| age.as_secs())
+    }
+
+    #[test]
+    fn sizes_take_binary_suffixes() {
+        assert_eq!(size("512"), Some(512));
+        assert_eq!(size("4k"), Some(4096));
+        assert_eq!(size(" 2M "), Some(2 << 20));
+        assert_eq!(size("1T"), Some(1 << 40));
+        assert_eq!(size("1.5G"), None);
+        assert_eq!(size("K"), None);
+        assert_eq!(size("20000000T"), None);
+    }
+
+    #[test]
+    fn ages_are_days_by_default() {
+        assert_eq!(age("3"), Some(3 * 24 * 60 * 60));
+        assert_eq!(age("6h"), Some(6 * 60 * 60));
+        assert_eq!(age("2D"), Some(2 * 24 * 60 * 60));
+        assert_eq!(age("1w"), Some(7 * 24 * 60 * 60));
+        assert_eq!(age("1m"), None);
+        assert_eq!(age("-1"), None);
+    }
 }
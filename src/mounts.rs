@@ -0,0 +1,72 @@
+use std::collections::HashMap;
+use std::io;
+
+/// The type of the file system mounted on each device, e.g. `tmpfs` or
+/// `nfs4`, by the device id files on it report.
+#[cfg(target_os = "linux")]
+pub fn fs_types() -> io::Result<HashMap<u64, String>> {
+    use std::fs::File;
+    use std::io::Read;
+
+    let mut mountinfo = String::new();
+    File::open("/proc/self/mountinfo")?.read_to_string(&mut mountinfo)?;
+    Ok(mountinfo.lines().filter_map(parse_mountinfo_line).collect())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn fs_types() -> io::Result<HashMap<u64, String>> {
+    Err(io::Error::new(
+        io::ErrorKind::Other,
+        "file system types are only known on Linux",
+    ))
+}
+
+/// The device and file system type of a line of `/proc/self/mountinfo`,
+/// e.g. `36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw`.
+#[cfg(target_os = "linux")]
+fn parse_mountinfo_line(line: &str) -> Option<(u64, String)> {
+    let mut fields = line.split(' ');
+    let device = fields.nth(2)?;
+    // The optional fields before the separator can be any in number.
+    let fs_type = fields.skip_while(|&field| field != "-").nth(1)?;
+    let mut numbers = device.splitn(2, ':');
+    let major = numbers.next()?.parse().ok()?;
+    let minor = numbers.next()?.parse().ok()?;
+    Some((makedev(major, minor), fs_type.to_owned()))
+}
+
+/// The device id glibc makes of `major` and `minor`, as `stat` reports it.
+#[cfg(target_os = "linux")]
+fn makedev(major: u64, minor: u64) -> u64 {
+    ((major & 0xffff_f000) << 32) | ((major & 0xfff) << 8) | ((minor & 0xffff_ff00) << 12) | (minor & 0xff)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn the_type_follows_the_separator() {
+        let line = "36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue";
+        assert_eq!(parse_mountinfo_line(line), Some((makedev(98, 0), String::from("ext3"))));
+    }
+
+    #[test]
+    fn there_can_be_no_optional_fields() {
+        let line = "25 1 0:22 / /tmp rw - tmpfs tmpfs rw";
+        assert_eq!(parse_mountinfo_line(line), Some((makedev(0, 22), String::from("tmpfs"))));
+    }
+
+    #[test]
+    fn malformed_lines_are_skipped() {
+        assert_eq!(parse_mountinfo_line(""), None);
+        assert_eq!(parse_mountinfo_line("36 35 98:0 /mnt1 /mnt2 rw"), None);
+        assert_eq!(parse_mountinfo_line("36 35 98 /mnt1 /mnt2 rw - ext3 /dev/root rw"), None);
+    }
+
+    #[test]
+    fn large_device_numbers_split_like_glibc() {
+        assert_eq!(makedev(259, 1), 0x10301);
+        assert_eq!(makedev(0x1000, 0x100), (1 << 44) | (1 << 20));
+    }
+}
@@ -0,0 +1,155 @@
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+
+/// Metadata key set on children whose name was percent-encoded.
+pub const ENCODED_NAME_KEY: &str = "percent_encoded_name";
+
+/// Metadata key holding the target of a symlink stored with
+/// `--store-symlinks`, encoded like child names are.
+pub const SYMLINK_TARGET_KEY: &str = "symlink_target";
+
+/// Metadata key set on symlinks whose target was percent-encoded.
+pub const ENCODED_TARGET_KEY: &str = "percent_encoded_symlink_target";
+
+/// Metadata key set on files whose contents were compressed with
+/// `--compress`, to the method: `gzip` or `zstd`.
+pub const COMPRESSION_KEY: &str = "compression";
+
+/// Metadata key holding the extended attributes stored with `--xattrs`, an
+/// object from attribute name to base64-encoded value.
+pub const XATTRS_KEY: &str = "xattrs";
+
+/// Encodes `bytes` as UTF-8, escaping `%` and every byte that isn't part of a
+/// valid UTF-8 sequence as `%XX`.
+fn percent_encode(mut bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    loop {
+        let (valid, rest) = match ::std::str::from_utf8(bytes) {
+            Ok(s) => (s, &[][..]),
+            Err(e) => {
+                let (valid, rest) = bytes.split_at(e.valid_up_to());
+                (::std::str::from_utf8(valid).unwrap(), rest)
+            }
+        };
+        for c in valid.chars() {
+            if c == '%' {
+                out.push_str("%25");
+            } else {
+                out.push(c);
+            }
+        }
+        match rest.split_first() {
+            Some((byte, rest)) => {
+                out.push_str(&format!("%{:02X}", byte));
+                bytes = rest;
+            }
+            None => return out,
+        }
+    }
+}
+
+fn percent_decode(s: &str) -> Option<Vec<u8>> {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = s.get(i + 1..i + 3)?;
+            out.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            out.push(bytes[i]);
+            i += 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(unix)]
+fn os_bytes(s: &OsStr) -> Option<&[u8]> {
+    use std::os::unix::ffi::OsStrExt;
+    Some(s.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn os_bytes(_s: &OsStr) -> Option<&[u8]> {
+    None
+}
+
+/// The key a local path is stored under in the database. The same as the
+/// path for valid UTF-8 without `%` in it.
+pub fn db_key(path: &Path) -> String {
+    match os_bytes(path.as_os_str()) {
+        Some(bytes) => percent_encode(bytes),
+        None => path.to_string_lossy().into_owned(),
+    }
+}
+
+/// The local path stored under `key`, the reverse of `db_key`. Keys that
+/// don't decode are taken as they are.
+pub fn db_path(key: &str) -> PathBuf {
+    match percent_decode(key).and_then(|bytes| decode_bytes(bytes, key)) {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(key),
+    }
+}
+
+/// The Tahoe child name for a local file name, and whether it had to be
+/// encoded. On Unix names are arbitrary bytes, and those that aren't UTF-8
+/// are percent-encoded rather than lossily converted, so that distinct files
+/// never get the same name and the original can be restored.
+pub fn child_name(name: &OsStr) -> (String, bool) {
+    if let Some(name) = name.to_str() {
+        return (String::from(name), false);
+    }
+    match os_bytes(name) {
+        Some(bytes) => (percent_encode(bytes), true),
+        None => (name.to_string_lossy().into_owned(), false),
+    }
+}
+
+/// Turns a child name encoded by `child_name` back into the local name.
+/// Elsewhere than on Unix the encoded name is kept as is.
+pub fn decode_child_name(name: &str) -> Option<OsString> {
+    decode_bytes(percent_decode(name)?, name)
+}
+
+#[cfg(unix)]
+fn decode_bytes(bytes: Vec<u8>, _name: &str) -> Option<OsString> {
+    use std::os::unix::ffi::OsStringExt;
+    Some(OsString::from_vec(bytes))
+}
+
+#[cfg(not(unix))]
+fn decode_bytes(_bytes: Vec<u8>, name: &str) -> Option<OsString> {
+    Some(OsString::from(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn valid_utf8_is_left_alone_but_for_percent() {
+        assert_eq!(percent_encode("café".as_bytes()), "café");
+        assert_eq!(percent_encode(b"100%"), "100%25");
+    }
+
+    #[test]
+    fn invalid_bytes_are_escaped() {
+        assert_eq!(percent_encode(b"a\xffb\xc3"), "a%FFb%C3");
+    }
+
+    #[test]
+    fn decoding_undoes_encoding() {
+        let bytes = b"50% \xfe\xffcaf\xc3\xa9";
+        assert_eq!(percent_decode(&percent_encode(bytes)), Some(bytes.to_vec()));
+    }
+
+    #[test]
+    fn truncated_or_bad_escapes_dont_decode() {
+        assert_eq!(percent_decode("a%4"), None);
+        assert_eq!(percent_decode("a%zz"), None);
+        assert_eq!(percent_decode("%41b"), Some(b"Ab".to_vec()));
+    }
+}
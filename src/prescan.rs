@@ -0,0 +1,152 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use filter::{Filter, CACHEDIR_TAG};
+use gitignore::Gitignores;
+
+use points_inside;
+
+/// File count and size of everything a backup will look at.
+#[derive(Clone, Copy, Default)]
+pub struct Totals {
+    pub files: u64,
+    pub bytes: u64,
+}
+
+/// A walk of the tree running in the background, so the upload doesn't have to
+/// wait for it. It only stats files, and can be cancelled at any time.
+pub struct Prescan {
+    cancel: Arc<AtomicBool>,
+    handle: thread::JoinHandle<Option<Totals>>,
+}
+
+impl Prescan {
+    /// Starts scanning each of `sources`, skipping what its filter doesn't
+    /// want and going through symlinks if `follow` is set, or only those
+    /// pointing inside the source with `follow_internal`. `done` is called
+    /// from the scanning thread once the totals are known.
+    pub fn start<F>(sources: Vec<(PathBuf, Filter)>, follow: bool, follow_internal: bool, done: F) -> Self
+    where
+        F: FnOnce(&Totals) + Send + 'static,
+    {
+        let cancel = Arc::new(AtomicBool::new(false));
+        let flag = cancel.clone();
+        let handle = thread::spawn(move || {
+            let mut totals = Totals::default();
+            for (path, filter) in sources {
+                let mut scan = Scan {
+                    filter,
+                    follow,
+                    follow_internal,
+                    root: path.clone(),
+                    cancel: flag.clone(),
+                    ancestors: Vec::new(),
+                    totals,
+                };
+                if !scan.scan(&path, 0, &Gitignores::default()) {
+                    return None;
+                }
+                totals = scan.totals;
+            }
+            done(&totals);
+            Some(totals)
+        });
+        Prescan { cancel, handle }
+    }
+
+    /// Stops the scan if it's still running. Returns the totals if it got to
+    /// finish.
+    pub fn finish(self) -> Option<Totals> {
+        self.cancel.store(true, Ordering::Relaxed);
+        self.handle.join().unwrap_or(None)
+    }
+}
+
+struct Scan {
+    filter: Filter,
+    follow: bool,
+    follow_internal: bool,
+    /// The source being scanned, as a real path.
+    root: PathBuf,
+    cancel: Arc<AtomicBool>,
+    /// Real paths of the directories above the current one, to catch symlink
+    /// loops.
+    ancestors: Vec<PathBuf>,
+    totals: Totals,
+}
+
+impl Scan {
+    /// Scans the entry at `path`, `depth` levels below the root, in a
+    /// directory with the `.gitignore` rules `gitignores`.
+    fn scan(&mut self, path: &Path, depth: usize, gitignores: &Gitignores) -> bool {
+        let root = depth == 0;
+        if self.cancel.load(Ordering::Relaxed) {
+            return false;
+        }
+        let metadata = match self.metadata(path) {
+            Ok(x) => x,
+            Err(_) => return true,
+        };
+        if !root && (!self.filter.wanted(path, metadata.is_dir()) || gitignores.ignores(path, metadata.is_dir())) {
+            return true;
+        }
+        if !root && (!self.filter.same_device(&metadata) || self.filter.excluded_fs_type(&metadata).is_some()) {
+            return true;
+        }
+        if !root && !self.filter.size_wanted(&metadata) {
+            return true;
+        }
+        if metadata.is_file() {
+            self.totals.files += 1;
+            self.totals.bytes += metadata.len();
+        } else if metadata.is_dir() {
+            if !self.filter.within_depth(depth) {
+                return true;
+            }
+            if self.follow || self.follow_internal {
+                match fs::canonicalize(path) {
+                    Ok(ref real) if !self.ancestors.contains(real) => {
+                        self.ancestors.push(real.clone())
+                    }
+                    _ => return true,
+                }
+            }
+            let finished = self.scan_dir(path, depth, gitignores);
+            if self.follow || self.follow_internal {
+                self.ancestors.pop();
+            }
+            return finished;
+        }
+        true
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<fs::Metadata> {
+        if self.follow {
+            return fs::metadata(path);
+        }
+        let metadata = fs::symlink_metadata(path)?;
+        if self.follow_internal && metadata.file_type().is_symlink() && points_inside(path, &self.root) {
+            return fs::metadata(path);
+        }
+        Ok(metadata)
+    }
+
+    fn scan_dir(&mut self, path: &Path, depth: usize, parent: &Gitignores) -> bool {
+        let gitignores = self.filter.gitignores(path, parent);
+        if self.filter.is_excluded_cache(path) {
+            return self.scan(&path.join(CACHEDIR_TAG), depth + 1, &gitignores);
+        }
+        if let Ok(entries) = fs::read_dir(path) {
+            for entry in entries.filter_map(|e| e.ok()) {
+                if !self.scan(&entry.path(), depth + 1, &gitignores) {
+                    return false;
+                }
+            }
+        }
+        true
+    }
+}
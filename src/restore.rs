@@ -0,0 +1,334 @@
+use std::ffi::OsString;
+use std::fs;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use futures::{future, stream, Future, IntoFuture, Stream};
+
+use filetime::{set_file_times, FileTime};
+
+use tahoe::client::{ChildInfo, NodeType, Tahoe};
+use tahoe::compress::{Compression, Decoder};
+use tahoe::limit::Limit;
+
+use errors::*;
+
+use failures::Failures;
+
+use {log_chain, names, xattrs};
+
+/// How to restore, the same for every child.
+#[derive(Clone, Copy)]
+pub struct Options {
+    /// Stop at the first failure with `ErrorKind::Aborted`.
+    pub fail_fast: bool,
+    /// Set the extended attributes stored with `--xattrs`.
+    pub xattrs: bool,
+}
+
+/// Suffix used for files that are still being downloaded, so an interrupted
+/// restore never leaves a truncated file under its real name.
+const PARTIAL_SUFFIX: &str = ".tahoe-partial";
+
+/// Checks that a child name from the grid is safe to use as a single path
+/// component below the restore destination.
+pub fn safe_name(name: &str) -> Option<&str> {
+    if name.is_empty() || name == "." || name == ".." || name.contains('/')
+        || name.contains('\0')
+    {
+        return None;
+    }
+    if cfg!(windows) && name.contains('\\') {
+        return None;
+    }
+    Some(name)
+}
+
+/// The local name for a child, undoing the encoding of names that weren't
+/// valid UTF-8 at backup time.
+pub fn local_name(name: &str, child: &ChildInfo) -> Option<OsString> {
+    if !child.metadata.contains_key(names::ENCODED_NAME_KEY) {
+        return safe_name(name).map(OsString::from);
+    }
+    let decoded = names::decode_child_name(name)?;
+    // Lossy conversion never introduces `/`, NUL or dots, so the decoded
+    // name is safe exactly when its lossy version is.
+    safe_name(&decoded.to_string_lossy())?;
+    Some(decoded)
+}
+
+fn restore_metadata(path: &Path, child: &ChildInfo, opts: Options) -> Result<()> {
+    if opts.xattrs {
+        xattrs::restore(path, child)?;
+    }
+    if let Some(mtime) = child.time("mtime") {
+        let mtime = FileTime::from_seconds_since_1970(mtime, 0);
+        set_file_times(path, mtime, mtime)
+            .chain_err(|| ErrorKind::RestoreMetadata(path.to_string_lossy().into_owned()))?;
+    }
+    restore_mode(path, child)
+}
+
+#[cfg(unix)]
+fn restore_mode(path: &Path, child: &ChildInfo) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    if let Some(mode) = child.unix_mode() {
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .chain_err(|| ErrorKind::RestoreMetadata(path.to_string_lossy().into_owned()))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn restore_mode(_path: &Path, _child: &ChildInfo) -> Result<()> {
+    Ok(())
+}
+
+/// Whether a previous (possibly interrupted) restore already wrote this file.
+fn already_restored(path: &Path, child: &ChildInfo) -> bool {
+    let meta = match fs::metadata(path) {
+        Ok(meta) => meta,
+        Err(_) => return false,
+    };
+    // The grid only knows the compressed size of a compressed file.
+    let compressed = is_compressed(child);
+    if !meta.is_file() || (!compressed && child.size != Some(meta.len())) {
+        return false;
+    }
+    match child.time("mtime") {
+        Some(mtime) => FileTime::from_last_modification_time(&meta).seconds() == mtime,
+        None => !compressed,
+    }
+}
+
+/// Whether `child` was compressed with `--compress`.
+pub fn is_compressed(child: &ChildInfo) -> bool {
+    child.metadata.contains_key(names::COMPRESSION_KEY)
+}
+
+/// How the contents of `child` were compressed, if they were.
+fn compression(child: &ChildInfo) -> Result<Option<Compression>> {
+    match child.metadata.get(names::COMPRESSION_KEY) {
+        Some(value) => {
+            let name = value.as_str().unwrap_or_default();
+            name.parse()
+                .map(Some)
+                .map_err(|_| ErrorKind::UnknownCompression(name.to_owned()).into())
+        }
+        None => Ok(None),
+    }
+}
+
+/// The target of a symlink stored with `--store-symlinks`, if `child` is one
+/// and the target decodes.
+pub fn symlink_target(child: &ChildInfo) -> Option<PathBuf> {
+    let target = child.metadata.get(names::SYMLINK_TARGET_KEY)?.as_str()?;
+    if child.metadata.contains_key(names::ENCODED_TARGET_KEY) {
+        names::decode_child_name(target).map(PathBuf::from)
+    } else {
+        Some(PathBuf::from(target))
+    }
+}
+
+/// Recreates a symlink stored with `--store-symlinks`. Its mtime and mode
+/// aren't restored, as setting them would go through the link.
+fn restore_symlink(path: &Path, child: &ChildInfo) -> Result<()> {
+    let logpath = path.to_string_lossy().into_owned();
+    let target = match symlink_target(child) {
+        Some(target) => target,
+        None => {
+            return Err(Error::from("invalid symlink target"))
+                .chain_err(|| ErrorKind::CreateSymlink(logpath))
+        }
+    };
+
+    if let Ok(existing) = fs::read_link(path) {
+        if existing == target {
+            info!("Skipping '{}'", logpath);
+            return Ok(());
+        }
+        fs::remove_file(path).chain_err(|| ErrorKind::CreateSymlink(logpath.clone()))?;
+    }
+    info!("Creating symlink '{}'", logpath);
+    symlink(&target, path).chain_err(|| ErrorKind::CreateSymlink(logpath))
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, path: &Path) -> Result<()> {
+    Ok(::std::os::unix::fs::symlink(target, path)?)
+}
+
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _path: &Path) -> Result<()> {
+    bail!("symlinks can only be restored on Unix")
+}
+
+fn restore_file<'a>(
+    client: &'a Tahoe,
+    limit: &Limit,
+    opts: Options,
+    path: PathBuf,
+    child: ChildInfo,
+) -> Box<Future<Item = Result<()>, Error = Error> + 'a> {
+    let logpath = path.to_string_lossy().into_owned();
+    if already_restored(&path, &child) {
+        info!("Skipping '{}'", logpath);
+        return Box::new(future::ok(Ok(())));
+    }
+
+    let cap = match child.uri() {
+        Some(cap) => cap.to_owned(),
+        None => return Box::new(future::ok(Err(ErrorKind::MissingCap(logpath).into()))),
+    };
+
+    let compression = match compression(&child) {
+        Ok(x) => x,
+        Err(e) => return Box::new(future::ok(Err(e))),
+    };
+
+    let mut partial = path.clone().into_os_string();
+    partial.push(PARTIAL_SUFFIX);
+    let partial = PathBuf::from(partial);
+    let cleanup = partial.clone();
+    // The file is only opened once there's a slot, so files waiting their
+    // turn don't hold a descriptor each.
+    let downloading = logpath.clone();
+    let download = limit.run(move || {
+        File::create(&partial)
+            .and_then(|f| Decoder::new(compression, f))
+            .chain_err(|| ErrorKind::FileOpen(downloading.clone()))
+            .into_future()
+            .and_then(move |f| {
+                info!("Downloading file '{}'", downloading);
+                client
+                    .download_file(&cap)
+                    .into_future()
+                    .flatten()
+                    .map_err(Error::from)
+                    .and_then(move |body| {
+                        body.map_err(|e| Error::with_chain(e, "failed to download file"))
+                            .fold(f, |mut f, chunk| f.write_all(&chunk).map(|_| f).map_err(Error::from))
+                    })
+            })
+            .map(move |f| (f, partial))
+    });
+    Box::new(
+        download
+            .and_then(move |(f, partial)| {
+                f.finish()?;
+                fs::rename(&partial, &path)?;
+                restore_metadata(&path, &child, opts)
+            })
+            .then(move |res| {
+                if res.is_err() {
+                    // Gone already if it was renamed before the failure.
+                    fs::remove_file(&cleanup).ok();
+                }
+                future::ok(res.chain_err(|| ErrorKind::FileDownload(logpath)))
+            }),
+    )
+}
+
+/// Recursively restores the directory `cap` into `path`. Children that fail
+/// are recorded in `failures` and skipped, or with `opts.fail_fast` stop the
+/// restore with `ErrorKind::Aborted`.
+pub fn restore<'a>(
+    client: &'a Tahoe,
+    failures: &'a Failures,
+    opts: Options,
+    cap: String,
+    path: PathBuf,
+) -> Box<Future<Item = Result<()>, Error = Error> + 'a> {
+    // Every level of the tree takes its downloads and listings from the same
+    // slots, so there are never more than `client.concurrency()` at once
+    // however deep it goes.
+    let limit = Limit::new(Some(client.concurrency()));
+    restore_dir(client, failures, &limit, opts, cap, path)
+}
+
+fn restore_dir<'a>(
+    client: &'a Tahoe,
+    failures: &'a Failures,
+    limit: &Limit,
+    opts: Options,
+    cap: String,
+    path: PathBuf,
+) -> Box<Future<Item = Result<()>, Error = Error> + 'a> {
+    let logpath = path.to_string_lossy().into_owned();
+    if let Err(e) = fs::create_dir_all(&path).chain_err(|| ErrorKind::CreateDir(logpath.clone())) {
+        return Box::new(future::ok(Err(e)));
+    }
+
+    // The slot is given back once the listing is in, before the children
+    // wait for slots of their own.
+    let dir = limit.run(move || {
+        client
+            .read_dir(&cap)
+            .into_future()
+            .flatten()
+            .map_err(|e| Error::with_chain(e, "couldn't read dir"))
+    });
+
+    info!("Restoring dir '{}'", logpath);
+    let limit = limit.clone();
+    Box::new(
+        dir.then(move |listing| -> Box<Future<Item = Result<()>, Error = Error> + 'a> {
+            let children = match listing {
+                Ok(children) => children,
+                Err(e) => return Box::new(future::ok(Err(e))),
+            };
+            Box::new(
+                stream::iter_ok(children)
+                    .map(move |(name, child)| -> Box<Future<Item = (PathBuf, Result<()>), Error = Error>> {
+                        let child_path = match local_name(&name, &child) {
+                            Some(local) => path.join(local),
+                            None => {
+                                let res = Err(ErrorKind::UnsafeName(name.clone()).into());
+                                return Box::new(future::ok((path.join(name), res)));
+                            }
+                        };
+                        let failpath = child_path.clone();
+                        let restored: Box<Future<Item = Result<()>, Error = Error>> = match child.nodetype {
+                            _ if child.metadata.contains_key(names::SYMLINK_TARGET_KEY) => {
+                                Box::new(future::ok(restore_symlink(&child_path, &child)))
+                            }
+                            NodeType::Dir => match child.uri() {
+                                Some(cap) => {
+                                    // Writing the children touches the directory mtime, so
+                                    // it is only reapplied once they are all done.
+                                    let cap = cap.to_owned();
+                                    Box::new(restore_dir(client, failures, &limit, opts, cap, child_path.clone()).map(
+                                        move |res| {
+                                            res.and_then(|_| restore_metadata(&child_path, &child, opts))
+                                        },
+                                    ))
+                                }
+                                None => Box::new(future::ok(Err(ErrorKind::MissingCap(
+                                    child_path.to_string_lossy().into_owned(),
+                                ).into()))),
+                            },
+                            NodeType::File => restore_file(client, &limit, opts, child_path, child),
+                            NodeType::Unknown => Box::new(future::ok(Err(ErrorKind::UnknownFile(
+                                child_path.to_string_lossy().into_owned(),
+                            ).into()))),
+                        };
+                        Box::new(restored.map(move |res| (failpath, res)))
+                    })
+                    .buffered(client.concurrency())
+                    .for_each(move |(path, res)| {
+                        if let Err(e) = res {
+                            log_chain(&e);
+                            failures.record(&path, e);
+                            if opts.fail_fast {
+                                bail!(ErrorKind::Aborted);
+                            }
+                        }
+                        Ok(())
+                    })
+                    .map(|_| Ok(())),
+            )
+        }),
+    )
+}
@@ -0,0 +1,215 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+
+use futures::{stream, Future, IntoFuture, Stream};
+
+use globset::GlobMatcher;
+
+use tahoe::client::{ChildInfo, NodeType, Tahoe};
+
+use errors::*;
+
+use log_chain;
+
+/// Which archives of a series to keep. An archive is kept if either rule
+/// wants it; the one just made always is.
+#[derive(Clone, Copy, Default)]
+pub struct Policy {
+    /// Keep this many of the newest.
+    pub keep: Option<usize>,
+    /// Keep those linked at most this long ago.
+    pub keep_within: Option<Duration>,
+}
+
+impl Policy {
+    pub fn is_set(&self) -> bool {
+        self.keep.is_some() || self.keep_within.is_some()
+    }
+}
+
+/// The archives to prune: those in `dir` matching `series`, apart from
+/// `current`, the one just made, and `latest`, the link to it.
+#[derive(Clone)]
+pub struct Pruning {
+    pub dir: String,
+    pub series: GlobMatcher,
+    pub current: String,
+    pub latest: Option<String>,
+    pub policy: Policy,
+}
+
+/// Where `name` in `dir` is below the target.
+fn link(dir: &str, name: &str) -> String {
+    if dir.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}/{}", dir, name)
+    }
+}
+
+/// The archives `policy` drops out of `entries`, the newest first.
+fn expired(
+    mut entries: Vec<(String, ChildInfo)>,
+    policy: Policy,
+    now: DateTime<Utc>,
+    current: &str,
+) -> Vec<String> {
+    // Names sort by time for the timestamped ones, which is the fallback for
+    // links without a time.
+    entries.sort_by_key(|(name, child)| Reverse((child.link_time(), name.clone())));
+    let now = now.timestamp().max(0) as u64;
+    entries
+        .into_iter()
+        .enumerate()
+        .filter(|&(i, (ref name, ref child))| {
+            let by_count = policy.keep.is_some_and(|keep| i < keep);
+            let by_age = match (policy.keep_within, child.link_time()) {
+                (Some(within), Some(linked)) => now.saturating_sub(linked) <= within.as_secs(),
+                // An archive of unknown age isn't known to be expired.
+                (Some(_), None) => true,
+                (None, _) => false,
+            };
+            name != current && !by_count && !by_age
+        })
+        .map(|(_, (name, _))| name)
+        .collect()
+}
+
+/// Unlinks the archives of `pruning` below `target` that its policy doesn't
+/// keep, and resolves to their caps. Only the links go, so the archives stay
+/// on the grid until their leases run out. An unlink that fails is logged
+/// and the archive left in place.
+pub fn prune<'a>(
+    client: &'a Tahoe,
+    target: &'a str,
+    pruning: Pruning,
+    now: DateTime<Utc>,
+) -> Box<Future<Item = Vec<String>, Error = Error> + 'a> {
+    let Pruning {
+        dir,
+        series,
+        current,
+        latest,
+        policy,
+    } = pruning;
+    let path = link(target, &dir);
+    Box::new(
+        client
+            .read_dir(&path)
+            .into_future()
+            .flatten()
+            .map_err(|e| Error::with_chain(e, "couldn't read the archives"))
+            .and_then(move |children| {
+                let archives: Vec<_> = children
+                    .into_iter()
+                    .filter(|(name, child)| {
+                        child.nodetype == NodeType::Dir && series.is_match(name)
+                            && latest.as_ref() != Some(&link(&dir, name))
+                    })
+                    .collect();
+                // The current archive is in `dir` too, named by the same
+                // template.
+                let current = current.rsplit('/').next().unwrap_or("");
+                let caps: HashMap<String, String> = archives
+                    .iter()
+                    .filter_map(|(name, child)| Some((name.clone(), child.uri()?.to_owned())))
+                    .collect();
+                let unlinks = expired(archives, policy, now, current).into_iter().map(move |name| {
+                    let link = link(&dir, &name);
+                    let cap = caps.get(&name).cloned();
+                    info!("Unlinking archive '{}'", link);
+                    client
+                        .unlink(target, &link)
+                        .into_future()
+                        .flatten()
+                        .then(move |res| match res {
+                            Ok(()) => Ok(cap),
+                            Err(e) => {
+                                log_chain(&Error::with_chain(e, format!("Couldn't unlink '{}'", link)));
+                                Ok(None)
+                            }
+                        })
+                });
+                stream::iter_ok(unlinks.collect::<Vec<_>>())
+                    .buffer_unordered(client.concurrency())
+                    .filter_map(|cap| cap)
+                    .collect()
+            }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    use serde_json;
+
+    const DAY: u64 = 24 * 60 * 60;
+
+    fn now() -> DateTime<Utc> {
+        Utc.timestamp(100 * DAY as i64, 0)
+    }
+
+    /// An archive linked `days` before `now`, or at an unknown time.
+    fn archive(name: &str, days: Option<u64>) -> (String, ChildInfo) {
+        let metadata = match days {
+            Some(days) => format!(r#"{{"tahoe": {{"linkcrtime": {}}}}}"#, (100 - days) * DAY),
+            None => String::from("{}"),
+        };
+        let json = format!(r#"{{"ro_uri": "URI:DIR2-CHK:abc", "metadata": {}}}"#, metadata);
+        let child = serde_json::from_str(&json).unwrap();
+        (String::from(name), child)
+    }
+
+    fn archives() -> Vec<(String, ChildInfo)> {
+        vec![
+            archive("a", Some(30)),
+            archive("b", Some(10)),
+            archive("c", Some(1)),
+            archive("d", Some(0)),
+        ]
+    }
+
+    #[test]
+    fn keep_counts_the_newest() {
+        let policy = Policy {
+            keep: Some(2),
+            keep_within: None,
+        };
+        assert_eq!(expired(archives(), policy, now(), "d"), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn keep_within_goes_by_link_time() {
+        let policy = Policy {
+            keep: None,
+            keep_within: Some(Duration::from_secs(10 * DAY)),
+        };
+        assert_eq!(expired(archives(), policy, now(), "d"), vec!["a"]);
+    }
+
+    #[test]
+    fn either_rule_keeps_an_archive() {
+        let policy = Policy {
+            keep: Some(1),
+            keep_within: Some(Duration::from_secs(2 * DAY)),
+        };
+        assert_eq!(expired(archives(), policy, now(), "d"), vec!["b", "a"]);
+    }
+
+    #[test]
+    fn the_current_archive_and_unknown_ages_are_kept() {
+        let policy = Policy {
+            keep: Some(0),
+            keep_within: Some(Duration::from_secs(DAY)),
+        };
+        let mut entries = archives();
+        entries.push(archive("unknown", None));
+        assert_eq!(expired(entries, policy, now(), "a"), vec!["b"]);
+    }
+}
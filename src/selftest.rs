@@ -0,0 +1,120 @@
+use std::io::Cursor;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use futures::{Future, IntoFuture, Stream};
+
+use tahoe::client::Tahoe;
+
+use errors::*;
+
+/// How big the test file is: enough for it to be stored in shares on the
+/// grid, rather than in a literal cap holding the contents itself.
+const SIZE: usize = 4096;
+
+/// What a self-test that passed did.
+pub struct SelfTest {
+    /// The cap of the test file, which stays on the grid until its leases
+    /// run out.
+    pub cap: String,
+    pub bytes: usize,
+    pub elapsed: Duration,
+}
+
+/// Contents no earlier test had, so the node can't skip the upload as one it
+/// already did.
+fn contents(stamp: u64) -> Vec<u8> {
+    let mut data = format!("tahoe-backup self-test {}\n", stamp).into_bytes();
+    let filler = (0..).map(|i: u64| (i.wrapping_mul(31) % 251) as u8);
+    let rest = SIZE - data.len();
+    data.extend(filler.take(rest));
+    data
+}
+
+/// Downloads everything `cap` has, which may be a path below a directory.
+fn download<'a>(client: &'a Tahoe, cap: &str, step: &'static str) -> Box<Future<Item = Vec<u8>, Error = Error> + 'a> {
+    Box::new(
+        client
+            .download_file(cap)
+            .into_future()
+            .flatten()
+            .and_then(|body| body.concat2().from_err())
+            .map(|chunk| chunk.to_vec())
+            .map_err(move |e| Error::with_chain(e, ErrorKind::SelfTest(step))),
+    )
+}
+
+/// Fails `step` unless `read` is what was uploaded.
+fn compare(read: &[u8], uploaded: &[u8], step: &'static str) -> Result<()> {
+    if read != uploaded {
+        let e = Error::from(format!("read back {} bytes that don't match the {} uploaded", read.len(), uploaded.len()));
+        return Err(Error::with_chain(e, ErrorKind::SelfTest(step)));
+    }
+    Ok(())
+}
+
+/// Makes sure the node and `target` work for backups, end to end: uploads a
+/// small file, reads it back, links it into `target`, reads it through the
+/// link and unlinks it again. Once it's linked, the link is removed whatever
+/// else fails.
+pub fn self_test<'a>(client: &'a Tahoe, target: &str) -> Box<Future<Item = SelfTest, Error = Error> + 'a> {
+    let started = Instant::now();
+    let stamp = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_secs() * 1_000_000_000 + u64::from(since.subsec_nanos()))
+        .unwrap_or(0);
+    let name = format!("tahoe-backup-self-test-{}", stamp);
+    let link = format!("{}/{}", target, name);
+    let data = contents(stamp);
+    let target = target.to_owned();
+    Box::new(
+        client
+            .check_writable_dir(&target)
+            .into_future()
+            .flatten()
+            .map_err(|e| Error::with_chain(e, ErrorKind::SelfTest("checking the target")))
+            .and_then(move |()| {
+                info!("The target is a writable directory");
+                client
+                    .upload_reader(Cursor::new(data.clone()), None, |_| ())
+                    .map_err(|e| Error::with_chain(e, ErrorKind::SelfTest("uploading")))
+                    .map(move |cap| (cap, data))
+            })
+            .and_then(move |(cap, data)| {
+                info!("Uploaded {} bytes as '{}'", data.len(), cap);
+                download(client, &cap, "reading it back").and_then(move |read| {
+                    compare(&read, &data, "reading it back")?;
+                    info!("Read it back");
+                    Ok((cap, data))
+                })
+            })
+            .and_then(move |(cap, data)| {
+                client
+                    .attach(&target, &name, &cap)
+                    .into_future()
+                    .flatten()
+                    .map_err(|e| Error::with_chain(e, ErrorKind::SelfTest("linking it")))
+                    .and_then(move |()| {
+                        info!("Linked it as '{}'", name);
+                        download(client, &link, "reading it through the link")
+                            .and_then(move |read| compare(&read, &data, "reading it through the link"))
+                            .then(move |read| {
+                                client
+                                    .unlink(&target, &name)
+                                    .into_future()
+                                    .flatten()
+                                    .map_err(|e| Error::with_chain(e, ErrorKind::SelfTest("unlinking it")))
+                                    .then(move |unlinked| {
+                                        read?;
+                                        unlinked?;
+                                        info!("Unlinked it");
+                                        Ok(SelfTest {
+                                            cap,
+                                            bytes: SIZE,
+                                            elapsed: started.elapsed(),
+                                        })
+                                    })
+                            })
+                    })
+            }),
+    )
+}
@@ -0,0 +1,246 @@
+use chrono::{DateTime, Local, Utc};
+use chrono::format::{Item, Pad, StrftimeItems};
+
+use globset::{GlobBuilder, GlobMatcher};
+
+use errors::*;
+
+/// Where each archive is linked below the target unless told otherwise.
+pub const DEFAULT_NAME: &str = "Archives/{timestamp}";
+
+/// How the time placeholders are written.
+#[derive(Clone, Default)]
+pub struct TimeFormat {
+    /// A `strftime` format for `{timestamp}`, instead of RFC 3339.
+    pub timestamp: Option<String>,
+    /// Write the time in the local time zone instead of UTC.
+    pub local: bool,
+}
+
+impl TimeFormat {
+    /// Fails on a timestamp format chrono doesn't know, or that writes a `/`
+    /// and so puts every archive in directories of its own, unless that's
+    /// `nested` on purpose.
+    pub fn check(&self, nested: bool) -> Result<()> {
+        let format = match self.timestamp {
+            Some(ref format) => format,
+            None => return Ok(()),
+        };
+        let invalid = || ErrorKind::TimestampFormat(format.clone());
+        if format.is_empty() || StrftimeItems::new(format).any(|item| item == Item::Error) {
+            bail!(invalid());
+        }
+        if !nested && self.format(Utc::now(), format).contains('/') {
+            return Err(Error::from("it makes a subdirectory for every archive, which needs --nested-timestamps")
+                .chain_err(invalid));
+        }
+        Ok(())
+    }
+
+    fn format(&self, now: DateTime<Utc>, format: &str) -> String {
+        if self.local {
+            now.with_timezone(&Local).format(format).to_string()
+        } else {
+            now.format(format).to_string()
+        }
+    }
+
+    fn timestamp(&self, now: DateTime<Utc>) -> String {
+        match (&self.timestamp, self.local) {
+            (Some(format), _) => self.format(now, format),
+            (None, true) => now.with_timezone(&Local).to_rfc3339(),
+            (None, false) => now.to_rfc3339(),
+        }
+    }
+
+    /// What `{timestamp}` stands for in `series`. Numbers are matched as
+    /// digits and literal text as itself, so other names in the same
+    /// directory are left alone as far as the format allows.
+    fn timestamp_glob(&self) -> String {
+        let format = match self.timestamp {
+            Some(ref format) => format,
+            None => return String::from("[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]T*"),
+        };
+        StrftimeItems::new(format)
+            .map(|item| match item {
+                Item::Literal(text) | Item::Space(text) => escape(text),
+                Item::OwnedLiteral(ref text) | Item::OwnedSpace(ref text) => escape(text),
+                Item::Numeric(_, Pad::Space) => String::from("*"),
+                Item::Numeric(..) => String::from("[0-9]*"),
+                Item::Fixed(_) | Item::Error => String::from("?*"),
+            })
+            .collect()
+    }
+}
+
+/// The name of this machine, or `localhost` if it can't be found out.
+#[cfg(unix)]
+fn hostname() -> String {
+    use std::ffi::CStr;
+    use libc;
+
+    let mut buf = [0 as libc::c_char; 256];
+    let ok = unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len() - 1) } == 0;
+    if ok {
+        let name = unsafe { CStr::from_ptr(buf.as_ptr()) };
+        if let Ok(name) = name.to_str() {
+            if !name.is_empty() {
+                return String::from(name);
+            }
+        }
+    }
+    String::from("localhost")
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    use std::env;
+
+    env::var("COMPUTERNAME").unwrap_or_else(|_| String::from("localhost"))
+}
+
+/// Fills in the placeholders of `template`, a path below the target:
+/// `{hostname}`, `{date}` and `{time}` (in UTC unless `times` is local)
+/// and `{timestamp}`, the RFC 3339 time unless `times` has a format of its
+/// own. Fails on unknown placeholders, and on empty, `.` or `..` components.
+pub fn name(template: &str, now: DateTime<Utc>, times: &TimeFormat) -> Result<String> {
+    let invalid = || ErrorKind::SnapshotName(String::from(template));
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let end = rest[start..].find('}').ok_or_else(invalid)? + start;
+        match &rest[start + 1..end] {
+            "hostname" => name.push_str(&hostname()),
+            "date" => name.push_str(&times.format(now, "%Y-%m-%d")),
+            "time" => name.push_str(&times.format(now, "%H:%M:%S")),
+            "timestamp" => name.push_str(&times.timestamp(now)),
+            _ => bail!(invalid()),
+        }
+        rest = &rest[end + 1..];
+    }
+    name.push_str(rest);
+    if !valid_link(&name) {
+        bail!(invalid());
+    }
+    Ok(name)
+}
+
+/// What the placeholders stand for in `series`, loose enough for any time
+/// but never anything like `Latest`.
+fn placeholder_glob(placeholder: &str) -> Option<&'static str> {
+    match placeholder {
+        "date" => Some("[0-9][0-9][0-9][0-9]-[0-9][0-9]-[0-9][0-9]"),
+        "time" => Some("[0-9][0-9]:[0-9][0-9]:[0-9][0-9]"),
+        _ => None,
+    }
+}
+
+/// The directory the archive named by `template` at `now` goes in, and a
+/// matcher for the names in there that `template` gives at any other time:
+/// the other archives of the same series.
+pub fn series(template: &str, now: DateTime<Utc>, times: &TimeFormat) -> Result<(String, GlobMatcher)> {
+    let invalid = || ErrorKind::SnapshotName(String::from(template));
+    let (dir, last) = match template.rfind('/') {
+        Some(slash) => (name(&template[..slash], now, times)?, &template[slash + 1..]),
+        None => (String::new(), template),
+    };
+    let mut glob = String::new();
+    let mut rest = last;
+    while let Some(start) = rest.find('{') {
+        glob.push_str(&escape(&rest[..start]));
+        let end = rest[start..].find('}').ok_or_else(invalid)? + start;
+        match &rest[start + 1..end] {
+            "hostname" => glob.push_str(&escape(&hostname())),
+            "timestamp" => glob.push_str(&times.timestamp_glob()),
+            placeholder => glob.push_str(placeholder_glob(placeholder).ok_or_else(invalid)?),
+        }
+        rest = &rest[end + 1..];
+    }
+    glob.push_str(&escape(rest));
+    let matcher = GlobBuilder::new(&glob)
+        .literal_separator(true)
+        .build()
+        .chain_err(invalid)?
+        .compile_matcher();
+    Ok((dir, matcher))
+}
+
+/// `literal` as a glob that matches just itself.
+fn escape(literal: &str) -> String {
+    literal
+        .chars()
+        .map(|c| match c {
+            '*' | '?' | '[' | ']' | '{' | '}' | '\\' => format!("[{}]", c),
+            _ => c.to_string(),
+        })
+        .collect()
+}
+
+/// Whether `link` is a path below the target, without empty, `.` or `..`
+/// components.
+pub fn valid_link(link: &str) -> bool {
+    !link.split('/').any(|part| part.is_empty() || part == "." || part == "..")
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use chrono::TimeZone;
+
+    fn noon() -> DateTime<Utc> {
+        Utc.ymd(2023, 1, 2).and_hms(12, 30, 5)
+    }
+
+    #[test]
+    fn placeholders_are_filled_in() {
+        let times = TimeFormat::default();
+        assert_eq!(name("Archives/{date}_{time}", noon(), &times).unwrap(), "Archives/2023-01-02_12:30:05");
+        assert_eq!(name(DEFAULT_NAME, noon(), &times).unwrap(), "Archives/2023-01-02T12:30:05+00:00");
+        let times = TimeFormat {
+            timestamp: Some(String::from("%Y%m%d")),
+            local: false,
+        };
+        assert_eq!(name("{timestamp}", noon(), &times).unwrap(), "20230102");
+    }
+
+    #[test]
+    fn bad_templates_are_refused() {
+        let times = TimeFormat::default();
+        for template in &["{bogus}", "Archives/{date", "Archives//x", "../x", "x/.", ""] {
+            assert!(name(template, noon(), &times).is_err(), "{}", template);
+        }
+    }
+
+    #[test]
+    fn series_matches_other_times_only() {
+        let times = TimeFormat::default();
+        let (dir, series) = series("Archives/{date}_{time}", noon(), &times).unwrap();
+        assert_eq!(dir, "Archives");
+        assert!(series.is_match("2022-12-31_23:59:59"));
+        assert!(!series.is_match("Latest"));
+        assert!(!series.is_match("2022-12-31_23:59:59.old"));
+    }
+
+    #[test]
+    fn series_of_a_strftime_format() {
+        let times = TimeFormat {
+            timestamp: Some(String::from("backup-%Y%m%d")),
+            local: false,
+        };
+        let (dir, series) = series("{timestamp}", noon(), &times).unwrap();
+        assert_eq!(dir, "");
+        assert!(series.is_match("backup-20221231"));
+        assert!(!series.is_match("other-20221231"));
+    }
+
+    #[test]
+    fn literal_glob_characters_are_escaped() {
+        let times = TimeFormat::default();
+        let (_, series) = series("[x]*{date}", noon(), &times).unwrap();
+        assert!(series.is_match("[x]*2022-12-31"));
+        assert!(!series.is_match("xx2022-12-31"));
+    }
+}
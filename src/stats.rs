@@ -0,0 +1,128 @@
+use std::cell::Cell;
+use std::fmt;
+use std::time::Duration;
+
+use chrono::Utc;
+
+use indicatif::{HumanBytes, HumanDuration};
+
+use errors::*;
+use failures::Failures;
+
+/// Running totals for a backup. Only ever touched from the reactor thread.
+#[derive(Default)]
+pub struct Stats {
+    pub files_uploaded: Cell<u64>,
+    pub files_skipped: Cell<u64>,
+    pub bytes_uploaded: Cell<u64>,
+    pub bytes_skipped: Cell<u64>,
+    /// Size of the whole tree, if the pre-scan got to finish.
+    pub bytes_total: Cell<Option<u64>>,
+    pub dirs_created: Cell<u64>,
+    pub dirs_reused: Cell<u64>,
+    /// What was actually sent to the grid, after compression and with
+    /// retries, and how long that took. Only set once a backup that isn't a
+    /// dry run is done.
+    pub bytes_sent: Cell<u64>,
+    pub elapsed: Cell<Option<Duration>>,
+    /// Files and directories that were left out because they failed.
+    pub failures: Failures,
+}
+
+/// The summary written by `--report`.
+#[derive(Serialize)]
+pub struct Report<'a> {
+    pub started: String,
+    pub finished: String,
+    pub dry_run: bool,
+    pub files_uploaded: u64,
+    pub files_skipped: u64,
+    pub bytes_uploaded: u64,
+    pub bytes_skipped: u64,
+    pub bytes_total: Option<u64>,
+    pub dirs_created: u64,
+    pub dirs_reused: u64,
+    pub bytes_sent: u64,
+    /// The cap linked as `Latest`, unless the backup failed or was a dry run.
+    pub latest: Option<&'a str>,
+    pub errors: Vec<String>,
+}
+
+fn inc(cell: &Cell<u64>, n: u64) {
+    cell.set(cell.get() + n)
+}
+
+impl Stats {
+    pub fn file_uploaded(&self, size: u64) {
+        inc(&self.files_uploaded, 1);
+        inc(&self.bytes_uploaded, size);
+    }
+
+    pub fn file_skipped(&self, size: u64) {
+        inc(&self.files_skipped, 1);
+        inc(&self.bytes_skipped, size);
+    }
+
+    pub fn dir_created(&self) {
+        inc(&self.dirs_created, 1);
+    }
+
+    pub fn dir_reused(&self) {
+        inc(&self.dirs_reused, 1);
+    }
+
+    /// The report, with `error` as the last of the errors if the backup as
+    /// a whole failed.
+    pub fn report<'a>(
+        &self,
+        started: String,
+        dry_run: bool,
+        latest: Option<&'a str>,
+        error: Option<&Error>,
+    ) -> Report<'a> {
+        let mut errors = self.failures.lines();
+        if let Some(error) = error {
+            let chain: Vec<String> = error.iter().map(|e| e.to_string()).collect();
+            errors.push(chain.join(": "));
+        }
+        Report {
+            started,
+            finished: Utc::now().to_rfc3339(),
+            dry_run,
+            files_uploaded: self.files_uploaded.get(),
+            files_skipped: self.files_skipped.get(),
+            bytes_uploaded: self.bytes_uploaded.get(),
+            bytes_skipped: self.bytes_skipped.get(),
+            bytes_total: self.bytes_total.get(),
+            dirs_created: self.dirs_created.get(),
+            dirs_reused: self.dirs_reused.get(),
+            bytes_sent: self.bytes_sent.get(),
+            latest,
+            errors,
+        }
+    }
+}
+
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} new files ({} bytes), {} skipped files, {} new directories, {} reused directories",
+            self.files_uploaded.get(),
+            self.bytes_uploaded.get(),
+            self.files_skipped.get(),
+            self.dirs_created.get(),
+            self.dirs_reused.get()
+        )?;
+        if let Some(total) = self.bytes_total.get() {
+            if total > 0 {
+                let percent = self.bytes_skipped.get() as f64 * 100.0 / total as f64;
+                write!(f, ", {:.1}% of {} bytes deduplicated", percent, total)?;
+            }
+        }
+        if let Some(elapsed) = self.elapsed.get() {
+            write!(f, ", {} sent in {}", HumanBytes(self.bytes_sent.get()), HumanDuration(elapsed))?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+use std::cell::Cell;
+use std::fmt;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use indicatif::HumanBytes;
+
+/// How fast a backup sends data to the grid. The upload threads add what
+/// they send to the counter, and it's sampled on the reactor thread.
+pub struct Throughput {
+    sent: Arc<AtomicUsize>,
+    started: Instant,
+    /// When the last sample was taken and what the count was then.
+    last: Cell<(Instant, usize)>,
+}
+
+/// Bytes per second since the previous sample, and since the start.
+#[derive(Clone, Copy)]
+pub struct Rates {
+    pub current: f64,
+    pub average: f64,
+}
+
+fn per_sec(bytes: usize, since: Instant, now: Instant) -> f64 {
+    let elapsed = now.duration_since(since);
+    let secs = elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+    if secs > 0.0 {
+        bytes as f64 / secs
+    } else {
+        0.0
+    }
+}
+
+impl Throughput {
+    pub fn new() -> Self {
+        let now = Instant::now();
+        Throughput {
+            sent: Arc::new(AtomicUsize::new(0)),
+            started: now,
+            last: Cell::new((now, 0)),
+        }
+    }
+
+    /// The counter to add every byte sent to, retries included.
+    pub fn counter(&self) -> Arc<AtomicUsize> {
+        self.sent.clone()
+    }
+
+    /// Everything sent so far.
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed) as u64
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    pub fn sample(&self) -> Rates {
+        let now = Instant::now();
+        let sent = self.sent.load(Ordering::Relaxed);
+        let (then, before) = self.last.replace((now, sent));
+        Rates {
+            current: per_sec(sent - before, then, now),
+            average: per_sec(sent, self.started, now),
+        }
+    }
+}
+
+impl fmt::Display for Rates {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}/s now, {}/s average",
+            HumanBytes(self.current as u64),
+            HumanBytes(self.average as u64)
+        )
+    }
+}
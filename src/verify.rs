@@ -0,0 +1,168 @@
+use std::fmt;
+
+use futures::{stream, Future, IntoFuture, Stream};
+
+use tahoe::client::{CapCheck, CheckResult, DeepCheckResult, Tahoe};
+use errors::*;
+
+/// How many caps turned out to be in what state.
+#[derive(Clone, Copy, Default)]
+pub struct Tally {
+    pub healthy: u64,
+    pub unhealthy: u64,
+    pub missing: u64,
+    /// Caps that couldn't be checked at all, e.g. because the node was down.
+    pub failed: u64,
+}
+
+impl Tally {
+    pub fn problems(&self) -> u64 {
+        self.unhealthy + self.missing + self.failed
+    }
+}
+
+impl fmt::Display for Tally {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} healthy, {} unhealthy, {} missing, {} couldn't be checked",
+            self.healthy, self.unhealthy, self.missing, self.failed
+        )
+    }
+}
+
+enum Health {
+    Healthy,
+    /// With the share counts, unless it's a literal cap that has none.
+    Unhealthy(Option<CheckResult>),
+    Missing,
+    Failed(Error),
+}
+
+fn check<'a>(client: &'a Tahoe, cap: String) -> Box<Future<Item = (String, Health), Error = Error> + 'a> {
+    Box::new(
+        client
+            .check_cap(&cap)
+            .into_future()
+            .flatten()
+            .then(move |res| {
+                let health = match res {
+                    Ok(CapCheck { results: Some(ref result), .. }) if result.shares_good == 0 => Health::Missing,
+                    Ok(CapCheck { healthy: false, results }) => Health::Unhealthy(results),
+                    Ok(_) => Health::Healthy,
+                    Err(ref e) if e.is_not_found() => Health::Missing,
+                    Err(e) => Health::Failed(e.into()),
+                };
+                Ok((cap, health))
+            }),
+    )
+}
+
+/// Checks all of `caps` on the grid, reporting every one that isn't healthy.
+pub fn verify<'a>(client: &'a Tahoe, caps: Vec<String>) -> Box<Future<Item = Tally, Error = Error> + 'a> {
+    Box::new(
+        stream::iter_ok(caps)
+            .map(move |cap| check(client, cap))
+            .buffer_unordered(client.concurrency())
+            .fold(Tally::default(), |mut tally, (cap, health)| {
+                match health {
+                    Health::Healthy => {
+                        info!("Healthy: '{}'", cap);
+                        tally.healthy += 1;
+                    }
+                    Health::Unhealthy(Some(result)) => {
+                        warn!(
+                            "Unhealthy: '{}' ({} of {} shares, {} needed)",
+                            cap, result.shares_good, result.shares_expected, result.shares_needed
+                        );
+                        tally.unhealthy += 1;
+                    }
+                    Health::Unhealthy(None) => {
+                        warn!("Unhealthy: '{}'", cap);
+                        tally.unhealthy += 1;
+                    }
+                    Health::Missing => {
+                        warn!("Missing: '{}'", cap);
+                        tally.missing += 1;
+                    }
+                    Health::Failed(e) => {
+                        warn!("Couldn't check '{}'", cap);
+                        ::log_err(e);
+                        tally.failed += 1;
+                    }
+                }
+                Ok::<_, Error>(tally)
+            }),
+    )
+}
+
+/// How many objects below a directory a deep check found in what state.
+#[derive(Clone, Copy, Default)]
+pub struct DeepTally {
+    pub healthy: u64,
+    /// Those still unhealthy after any repair.
+    pub unhealthy: u64,
+    pub repaired: u64,
+}
+
+impl fmt::Display for DeepTally {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} healthy, {} unhealthy, {} repaired",
+            self.healthy, self.unhealthy, self.repaired
+        )
+    }
+}
+
+/// Logs how `result` fared and counts it in `tally`.
+fn tally_deep(mut tally: DeepTally, cap: &str, result: DeepCheckResult) -> DeepTally {
+    let path = if result.path.is_empty() {
+        format!("'{}'", cap)
+    } else {
+        format!("'{}/{}'", cap, result.path.join("/"))
+    };
+    if result.repair_attempted {
+        if result.repair_successful {
+            info!("Repaired: {}", path);
+            tally.repaired += 1;
+        } else {
+            warn!("Couldn't repair {}", path);
+        }
+    }
+    match result.results {
+        _ if result.healthy => {
+            info!("Healthy: {}", path);
+            tally.healthy += 1;
+        }
+        Some(results) => {
+            warn!(
+                "Unhealthy: {} ({} of {} shares, {} needed)",
+                path, results.shares_good, results.shares_expected, results.shares_needed
+            );
+            tally.unhealthy += 1;
+        }
+        None => {
+            warn!("Unhealthy: {}", path);
+            tally.unhealthy += 1;
+        }
+    }
+    tally
+}
+
+/// Has the node check the directory `cap` and everything below it, and
+/// with `repair` repair what isn't healthy, reporting every object that
+/// still isn't. Unlike `verify`, there's only the one request.
+pub fn deep_check<'a>(client: &'a Tahoe, cap: &str, repair: bool) -> Box<Future<Item = DeepTally, Error = Error> + 'a> {
+    let root = cap.to_owned();
+    Box::new(
+        client
+            .deep_check(cap, repair)
+            .into_future()
+            .flatten_stream()
+            .from_err()
+            .fold(DeepTally::default(), move |tally, result| {
+                Ok::<_, Error>(tally_deep(tally, &root, result))
+            }),
+    )
+}
@@ -0,0 +1,534 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::Ordering;
+
+use futures::{Async, Future, Poll, Stream};
+use futures::stream::FuturesUnordered;
+
+use indicatif::ProgressBar;
+
+use tahoe::client::{Dir, DirNode};
+
+use errors::*;
+
+use events::Event;
+
+use filter::{Filter, CACHEDIR_TAG};
+use gitignore::Gitignores;
+
+use {dir_children_style, dir_style, elide, names, upload_dir, upload_entry, xattrs, Context, DIR_STYLE_RESERVED};
+
+/// Where a finished upload goes: child `index` of pending directory `dir`.
+/// `None` is the root of the backup.
+type Slot = Option<(usize, usize)>;
+
+/// How many entries of a directory are read at a time, so a huge one doesn't
+/// queue all of its files at once.
+const SCAN_BATCH: usize = 256;
+
+/// What's done with an entry: where it goes, its path, the metadata it was
+/// uploaded with, and its cap.
+type Upload<'a> = Box<Future<Item = (Slot, PathBuf, Option<fs::Metadata>, Result<String>), Error = Error> + 'a>;
+
+/// A directory that's waiting for its children before it can be uploaded.
+struct PendingDir {
+    path: PathBuf,
+    /// From when it was found, for its node in the parent.
+    metadata: fs::Metadata,
+    parent: Slot,
+    /// The real path, when following symlinks, to catch loops.
+    real: Option<PathBuf>,
+    /// Name and node of every child read so far, in directory order.
+    /// Children that failed, or haven't been uploaded yet, are `None`.
+    children: Vec<Option<(String, DirNode)>>,
+    /// Children that are queued or uploading.
+    remaining: usize,
+    /// The rest of the directory, while it's being read.
+    entries: Option<fs::ReadDir>,
+    /// Whether it's been read to the end, so it's ready once nothing
+    /// remains.
+    scanned: bool,
+    /// Whether nothing below this directory failed so far, so it can go in
+    /// the journal once it's uploaded.
+    complete: bool,
+    /// How many directories down from the root this is.
+    depth: usize,
+    /// The rules for its children, with `--respect-gitignore`.
+    gitignores: Gitignores,
+    /// Spins while the directory is read, then counts its children done.
+    pb: Option<ProgressBar>,
+}
+
+/// Uploads a tree bottom-up without recursing: directories are read from an
+/// explicit stack, their files queued for upload, and each directory is
+/// uploaded once all of its children have caps.
+///
+/// Files from the whole tree share one queue, so at most
+/// `ctx.client.concurrency()` are uploaded at a time wherever they are, next to
+/// up to `ctx.dir_concurrency` directories. Resolves to the cap of the root,
+/// like `upload_entry` does for a single file.
+///
+/// Every directory with nothing failed below it is journaled once it's
+/// uploaded. With `ctx.resume` those are taken as they were and not read
+/// again; the rest is walked as usual, so what changed there gets new caps
+/// the way it would in any run.
+pub struct Walk<'a> {
+    ctx: &'a Context<'a>,
+    filter: &'a Filter,
+    /// Pending directories by id. Ids are reused once a directory is done.
+    dirs: Vec<Option<PendingDir>>,
+    free: Vec<usize>,
+    /// Directories still to be read, depth-first.
+    to_scan: Vec<usize>,
+    files: VecDeque<(Slot, PathBuf, io::Result<fs::Metadata>)>,
+    file_uploads: FuturesUnordered<Upload<'a>>,
+    /// Directories whose children are all done.
+    ready: VecDeque<usize>,
+    dir_uploads: FuturesUnordered<Upload<'a>>,
+    root: Option<Result<String>>,
+}
+
+impl<'a> Walk<'a> {
+    pub fn new(
+        ctx: &'a Context<'a>,
+        filter: &'a Filter,
+        path: PathBuf,
+        metadata: io::Result<fs::Metadata>,
+    ) -> Self {
+        let mut walk = Walk {
+            ctx,
+            filter,
+            dirs: Vec::new(),
+            free: Vec::new(),
+            to_scan: Vec::new(),
+            files: VecDeque::new(),
+            file_uploads: FuturesUnordered::new(),
+            ready: VecDeque::new(),
+            dir_uploads: FuturesUnordered::new(),
+            root: None,
+        };
+        walk.add(None, path, metadata);
+        walk
+    }
+
+    /// Queues the entry at `path`, to be put in `slot` once it's uploaded.
+    fn add(&mut self, slot: Slot, path: PathBuf, metadata: io::Result<fs::Metadata>) {
+        let metadata = match metadata {
+            Ok(ref metadata) if metadata.is_dir() => metadata.clone(),
+            _ => {
+                self.files.push_back((slot, path, metadata));
+                return;
+            }
+        };
+
+        if self.ctx.resume {
+            let journaled = self.ctx.db.journaled_dir(&names::db_key(&path));
+            if let Some(cap) = journaled.filter(|cap| self.ctx.mirrored(cap)) {
+                info!("Reusing '{}' from the interrupted run", path.display());
+                self.ctx.stats.dir_reused();
+                self.ctx.dir_done();
+                return self.finish(slot, path, Some(metadata), Ok(cap));
+            }
+        }
+
+        // When following symlinks, even only those inside the source, a
+        // directory may be reached again from inside itself. The real paths
+        // of the directories above this one tell us when that happens.
+        let mut real = None;
+        if self.ctx.follow_symlinks || self.ctx.follow_internal_symlinks {
+            let logpath = path.to_string_lossy().into_owned();
+            match fs::canonicalize(&path) {
+                Ok(path) => real = Some(path),
+                Err(e) => {
+                    let err = Error::with_chain(e, ErrorKind::ReadMetadata(logpath));
+                    return self.finish(slot, path, None, Err(err));
+                }
+            }
+            if self.is_ancestor(slot, &real) {
+                return self.finish(slot, path, None, Err(ErrorKind::SymlinkLoop(logpath).into()));
+            }
+        }
+
+        let (depth, gitignores) = match slot {
+            Some((id, _)) => {
+                let parent = self.dirs[id].as_ref().unwrap();
+                (parent.depth + 1, self.filter.gitignores(&path, &parent.gitignores))
+            }
+            None => (0, self.filter.gitignores(&path, &Gitignores::default())),
+        };
+        let dir = PendingDir {
+            path,
+            metadata,
+            parent: slot,
+            depth,
+            gitignores,
+            real,
+            children: Vec::new(),
+            remaining: 0,
+            entries: None,
+            scanned: false,
+            complete: true,
+            pb: None,
+        };
+        let id = match self.free.pop() {
+            Some(id) => {
+                self.dirs[id] = Some(dir);
+                id
+            }
+            None => {
+                self.dirs.push(Some(dir));
+                self.dirs.len() - 1
+            }
+        };
+        self.to_scan.push(id);
+    }
+
+    /// Whether the directory owning `slot`, or any above it, has the real
+    /// path `real`.
+    fn is_ancestor(&self, mut slot: Slot, real: &Option<PathBuf>) -> bool {
+        while let Some((id, _)) = slot {
+            let dir = self.dirs[id].as_ref().unwrap();
+            if dir.real == *real {
+                return true;
+            }
+            slot = dir.parent;
+        }
+        false
+    }
+
+    /// Reads the next few entries of directory `id` and queues them. It goes
+    /// back on the stack until it's read to the end, below its subdirectories
+    /// so those are walked first.
+    fn scan(&mut self, id: usize) {
+        let ctx = self.ctx;
+        let (path, depth, gitignores, entries) = {
+            let dir = self.dirs[id].as_mut().unwrap();
+            (dir.path.clone(), dir.depth, dir.gitignores.clone(), dir.entries.take())
+        };
+        let mut entries = match entries {
+            Some(entries) => entries,
+            None if self.filter.is_excluded_cache(&path) => {
+                info!("Skipping the contents of cache directory '{}'", path.display());
+                let tag = path.join(CACHEDIR_TAG);
+                let metadata = ctx.metadata(&tag);
+                return self.queue(id, vec![(tag, metadata)], true);
+            }
+            None => match self.open(id, &path) {
+                Some(entries) => entries,
+                None => return,
+            },
+        };
+
+        let mut children = Vec::new();
+        let mut read = 0;
+        let mut exhausted = true;
+        for entry in entries.by_ref() {
+            read += 1;
+            if let Some(child) = ctx.ok_or_record(&path, entry).and_then(|entry| self.wanted(entry, depth, &gitignores)) {
+                children.push(child);
+            }
+            if read == SCAN_BATCH {
+                exhausted = false;
+                break;
+            }
+        }
+        if !exhausted {
+            self.dirs[id].as_mut().unwrap().entries = Some(entries);
+            self.to_scan.push(id);
+        }
+        self.queue(id, children, exhausted);
+    }
+
+    /// Adds `children` to directory `id`, which has been read to the end if
+    /// `exhausted` is set.
+    fn queue(&mut self, id: usize, children: Vec<(PathBuf, io::Result<fs::Metadata>)>, exhausted: bool) {
+        for (child, metadata) in children {
+            let index = {
+                let dir = self.dirs[id].as_mut().unwrap();
+                dir.children.push(None);
+                dir.remaining += 1;
+                dir.children.len() - 1
+            };
+            self.add(Some((id, index)), child, metadata);
+        }
+        // Only now, as children may have finished right away.
+        if exhausted {
+            let dir = self.dirs[id].as_mut().unwrap();
+            dir.scanned = true;
+            if let Some(ref pb) = dir.pb {
+                pb.set_length(dir.children.len() as u64);
+                pb.set_style(dir_children_style());
+            }
+            if dir.remaining == 0 {
+                self.ready.push_back(id);
+            }
+        }
+    }
+
+    /// Starts reading directory `id` at `path`. If that fails, it's done
+    /// with the error instead, or with `ctx.skip_unreadable` left out if it's
+    /// for lack of permission.
+    fn open(&mut self, id: usize, path: &Path) -> Option<fs::ReadDir> {
+        let ctx = self.ctx;
+        // A source that can't be read fails either way.
+        let parent = self.dirs[id].as_ref().unwrap().parent;
+        let entries = match (fs::read_dir(path), parent) {
+            (Err(ref e), Some(slot)) if ctx.skip_unreadable && e.kind() == io::ErrorKind::PermissionDenied => {
+                info!("Skipping '{}', it can't be read", path.display());
+                ctx.event(Event::Skipped { path });
+                self.remove(id);
+                self.skip(slot);
+                return None;
+            }
+            (res, _) => res.chain_err(|| "couldn't read dir"),
+        };
+        let entries = match entries {
+            Ok(x) => x,
+            Err(e) => {
+                let parent = self.remove(id).parent;
+                self.finish(parent, path.to_owned(), None, Err(e));
+                return None;
+            }
+        };
+
+        let pb = match (ctx.dry_run, ctx.progress) {
+            (None, Some(_)) => {
+                let pb = ctx.bar(ProgressBar::new_spinner());
+                pb.set_style(dir_style());
+                pb.set_message(&elide::bar_message(&path.to_string_lossy(), DIR_STYLE_RESERVED));
+                pb.enable_steady_tick(100);
+                pb
+            }
+            _ => ProgressBar::hidden(),
+        };
+        self.dirs[id].as_mut().unwrap().pb = Some(pb);
+        Some(entries)
+    }
+
+    /// The path and metadata of `entry`, in a directory `depth` levels down,
+    /// unless it's left out.
+    fn wanted(
+        &self,
+        entry: fs::DirEntry,
+        depth: usize,
+        gitignores: &Gitignores,
+    ) -> Option<(PathBuf, io::Result<fs::Metadata>)> {
+        let ctx = self.ctx;
+        let filter = self.filter;
+        let path = entry.path();
+        // Entries the filter leaves out by name alone aren't stat'ed at all.
+        let early = if filter.needs_type() { Some(ctx.metadata(&path)) } else { None };
+        let is_dir = early.as_ref().is_some_and(|m| m.as_ref().map(|m| m.is_dir()).unwrap_or(false));
+        if !filter.wanted(&path, is_dir) || gitignores.ignores(&path, is_dir) {
+            return None;
+        }
+        // With symlinks followed, this is the device of the target.
+        let metadata = early.unwrap_or_else(|| ctx.metadata(&path));
+        if let Ok(ref metadata) = metadata {
+            if !filter.same_device(metadata) {
+                info!("Skipping '{}', it's on another file system", path.display());
+                return None;
+            }
+            if let Some(fs_type) = filter.excluded_fs_type(metadata) {
+                info!("Skipping '{}', it's on a {} file system", path.display(), fs_type);
+                return None;
+            }
+            if metadata.is_dir() && !filter.within_depth(depth + 1) {
+                info!("Skipping '{}', it's deeper than the maximum depth", path.display());
+                return None;
+            }
+            if !filter.size_wanted(metadata) {
+                info!("Skipping '{}', it's {} bytes", path.display(), metadata.len());
+                return None;
+            }
+            if metadata.file_type().is_symlink() && !ctx.store_symlinks {
+                info!("Skipping symlink '{}'", path.display());
+                return None;
+            }
+            if !ctx.fail_on_special && is_special(&metadata.file_type()) {
+                info!("Skipping special file '{}'", path.display());
+                return None;
+            }
+        }
+        Some((path, metadata))
+    }
+
+    fn remove(&mut self, id: usize) -> PendingDir {
+        self.free.push(id);
+        self.dirs[id].take().unwrap()
+    }
+
+    /// Leaves child `index` out of pending directory `id`, without it
+    /// counting as a failure.
+    fn skip(&mut self, (id, _index): (usize, usize)) {
+        let dir = self.dirs[id].as_mut().unwrap();
+        dir.remaining -= 1;
+        if let Some(ref pb) = dir.pb {
+            pb.inc(1);
+        }
+        if dir.remaining == 0 && dir.scanned {
+            self.ready.push_back(id);
+        }
+    }
+
+    /// Records the result of uploading the entry at `path` in its parent,
+    /// along with the metadata found for it.
+    fn finish(&mut self, slot: Slot, path: PathBuf, metadata: Option<fs::Metadata>, res: Result<String>) {
+        let (id, index) = match slot {
+            Some(x) => x,
+            None => {
+                self.root = Some(res);
+                return;
+            }
+        };
+        let ctx = self.ctx;
+        let node = ctx.ok_or_record(&path, res.and_then(|cap| child_node(ctx, &path, metadata, cap)));
+        let dir = self.dirs[id].as_mut().unwrap();
+        dir.complete &= node.is_some();
+        dir.children[index] = node;
+        dir.remaining -= 1;
+        if let Some(ref pb) = dir.pb {
+            pb.inc(1);
+        }
+        if dir.remaining == 0 && dir.scanned {
+            self.ready.push_back(id);
+        }
+    }
+
+    fn start_file(&mut self, slot: Slot, path: PathBuf, metadata: io::Result<fs::Metadata>) {
+        let found = metadata.as_ref().ok().cloned();
+        self.file_uploads.push(Box::new(
+            upload_entry(self.ctx, path.clone(), metadata).map(move |res| (slot, path, found, res)),
+        ));
+    }
+
+    fn start_dir(&mut self, id: usize) {
+        let pending = self.remove(id);
+        let path = pending.path;
+        let metadata = pending.metadata;
+        let parent = pending.parent;
+        let complete = pending.complete;
+        if let (false, Some((id, _))) = (complete, parent) {
+            self.dirs[id].as_mut().unwrap().complete = false;
+        }
+        let logpath = path.to_string_lossy().into_owned();
+        info!("Uploading dir '{}'", logpath);
+        let dir: Dir = pending.children.into_iter().flatten().collect();
+        let pb = pending.pb.unwrap_or_else(ProgressBar::hidden);
+        pb.set_style(dir_style());
+        let ctx = self.ctx;
+        self.dir_uploads.push(Box::new(upload_dir(ctx, pb, dir, logpath).map(move |res| {
+            // Only what's really on the grid may be skipped on resume.
+            if let (true, None, Ok(ref cap)) = (complete, ctx.dry_run, &res) {
+                ctx.ok_or_record(&path, ctx.db.journal_dir(&names::db_key(&path), cap));
+            }
+            (parent, path, Some(metadata), res)
+        })));
+    }
+
+    fn interrupted(&self) -> bool {
+        self.ctx.interrupted.load(Ordering::SeqCst)
+    }
+}
+
+/// The name and node for the entry at `path` in its parent directory, with
+/// the `metadata` it was found with.
+fn child_node(ctx: &Context, path: &Path, metadata: Option<fs::Metadata>, cap: String) -> Result<(String, DirNode)> {
+    let (name, encoded) = names::child_name(path.file_name().unwrap());
+    let is_symlink = metadata.as_ref().is_some_and(|m| m.file_type().is_symlink());
+    // Whatever uploaded the cap recorded how, this run or an earlier one.
+    let compression = match metadata {
+        Some(ref metadata) if metadata.is_file() => ctx.db.cap_compression(&cap),
+        _ => None,
+    };
+    let mut node = DirNode::new(cap, metadata);
+    if encoded {
+        node.insert_metadata(names::ENCODED_NAME_KEY, 1);
+    }
+    if let Some(compression) = compression {
+        node.insert_metadata(names::COMPRESSION_KEY, compression);
+    }
+    if is_symlink {
+        let target = fs::read_link(path)
+            .chain_err(|| ErrorKind::ReadLink(path.to_string_lossy().into_owned()))?;
+        let (target, encoded) = names::child_name(target.as_os_str());
+        node.insert_metadata(names::SYMLINK_TARGET_KEY, target);
+        if encoded {
+            node.insert_metadata(names::ENCODED_TARGET_KEY, 1);
+        }
+    }
+    if ctx.xattrs {
+        if let Some(attrs) = xattrs::read(path)? {
+            node.insert_metadata(names::XATTRS_KEY, attrs);
+        }
+    }
+    Ok((name, node))
+}
+
+/// FIFOs, sockets and devices, which have no contents to back up.
+#[cfg(unix)]
+fn is_special(file_type: &fs::FileType) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    file_type.is_fifo() || file_type.is_socket() || file_type.is_block_device()
+        || file_type.is_char_device()
+}
+
+#[cfg(not(unix))]
+fn is_special(_file_type: &fs::FileType) -> bool {
+    false
+}
+
+impl<'a> Future for Walk<'a> {
+    type Item = Result<String>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        loop {
+            if let Some(root) = self.root.take() {
+                return Ok(Async::Ready(root));
+            }
+
+            // Nothing new is started after Ctrl-C, and a directory with
+            // skipped children mustn't be uploaded.
+            let interrupted = self.interrupted();
+            if interrupted && self.file_uploads.is_empty() && self.dir_uploads.is_empty() {
+                return Ok(Async::Ready(Err(ErrorKind::Interrupted.into())));
+            }
+
+            let mut progress = false;
+            while !interrupted && self.dir_uploads.len() < self.ctx.dir_concurrency {
+                match self.ready.pop_front() {
+                    Some(id) => self.start_dir(id),
+                    None => break,
+                }
+            }
+            // Directories are only read once the files found so far have
+            // been started, so the queue stays short.
+            while !interrupted && self.file_uploads.len() < self.ctx.client.concurrency() {
+                if let Some((slot, path, metadata)) = self.files.pop_front() {
+                    self.start_file(slot, path, metadata);
+                } else if let Some(id) = self.to_scan.pop() {
+                    self.scan(id);
+                    progress = true;
+                } else {
+                    break;
+                }
+            }
+
+            if let Async::Ready(Some((slot, path, metadata, res))) = self.file_uploads.poll()? {
+                self.finish(slot, path, metadata, res);
+                progress = true;
+            }
+            if let Async::Ready(Some((slot, path, metadata, res))) = self.dir_uploads.poll()? {
+                self.finish(slot, path, metadata, res);
+                progress = true;
+            }
+
+            if !progress {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
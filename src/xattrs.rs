@@ -0,0 +1,83 @@
+use std::path::Path;
+
+use serde_json::Value;
+
+use tahoe::client::ChildInfo;
+
+use errors::*;
+
+/// The extended attributes of `path`, as stored under `names::XATTRS_KEY`.
+/// `None` if there are none, or the file system doesn't support them.
+#[cfg(unix)]
+pub fn read(path: &Path) -> Result<Option<Value>> {
+    use base64;
+    use libc;
+    use serde_json::Map;
+    use xattr;
+
+    let logpath = path.to_string_lossy().into_owned();
+    let names = match xattr::list(path) {
+        Ok(names) => names,
+        Err(ref e) if e.raw_os_error() == Some(libc::ENOTSUP) => return Ok(None),
+        Err(e) => return Err(Error::with_chain(e, ErrorKind::ReadXattrs(logpath))),
+    };
+    let mut attrs = Map::new();
+    for name in names {
+        let key = match name.to_str() {
+            Some(key) => key.to_owned(),
+            None => {
+                warn!("Skipping extended attribute {:?} of '{}', it isn't UTF-8", name, logpath);
+                continue;
+            }
+        };
+        // Attributes can go away while we're reading them.
+        match xattr::get(path, &name) {
+            Ok(Some(value)) => {
+                attrs.insert(key, Value::String(base64::encode(&value)));
+            }
+            Ok(None) => {}
+            Err(e) => return Err(Error::with_chain(e, ErrorKind::ReadXattrs(logpath))),
+        }
+    }
+
+    if attrs.is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(Value::Object(attrs)))
+    }
+}
+
+#[cfg(not(unix))]
+pub fn read(_path: &Path) -> Result<Option<Value>> {
+    Ok(None)
+}
+
+/// Sets the extended attributes stored for `child` on `path`.
+#[cfg(unix)]
+pub fn restore(path: &Path, child: &ChildInfo) -> Result<()> {
+    use base64;
+    use xattr;
+
+    use names;
+
+    let attrs = match child.metadata.get(names::XATTRS_KEY).and_then(|v| v.as_object()) {
+        Some(attrs) => attrs,
+        None => return Ok(()),
+    };
+    let logpath = path.to_string_lossy().into_owned();
+    for (name, value) in attrs {
+        let value = value
+            .as_str()
+            .ok_or_else(|| Error::from("not a string"))
+            .and_then(|value| base64::decode(value).chain_err(|| "invalid base64"))
+            .chain_err(|| ErrorKind::RestoreXattr(logpath.clone(), name.clone()))?;
+        xattr::set(path, name, &value)
+            .chain_err(|| ErrorKind::RestoreXattr(logpath.clone(), name.clone()))?;
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn restore(_path: &Path, _child: &ChildInfo) -> Result<()> {
+    Ok(())
+}
@@ -1,19 +1,37 @@
+use std::any::Any;
+use std::mem;
+use std::panic::{self, AssertUnwindSafe};
 use std::str::FromStr;
 use std::io;
 use std::io::Read;
+use std::net::ToSocketAddrs;
 use std::fs::{File, Metadata};
 use std::collections::HashMap;
-use std::time::UNIX_EPOCH;
+use std::path::Path;
+use std::rc::Rc;
+use std::cell::RefCell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use std::result;
 use std::iter::FromIterator;
 use std::hash::Hasher;
 
 use tokio_core::reactor;
 
-use hyper::client::{Client, HttpConnector, Request};
-use hyper::{Body, Chunk, Method, Uri};
+use hyper;
+use hyper::client::{Client, FutureResponse, Request, Response};
 
-use futures::{Future, Sink, Stream};
+use hyper_tls::HttpsConnector;
+use hyper::{Body, Chunk, Method, StatusCode, Uri};
+use hyper::header::{ContentType, UserAgent};
+
+use futures::{future, stream, Future, IntoFuture, Sink, Stream};
+use futures::future::Loop;
+use futures::sync::{mpsc, oneshot};
+
+use url::Url;
+use url::percent_encoding::{utf8_percent_encode, EncodeSet, PATH_SEGMENT_ENCODE_SET};
 
 use serde::{Serialize, Serializer};
 use serde::ser::SerializeMap;
@@ -23,43 +41,323 @@ use threadpool::ThreadPool;
 
 use seahash::SeaHasher;
 
+use rand;
+
 use errors::*;
+use compress::Compression;
+use connect::{Connector, Socks5};
+use limit::Limit;
+use timeout::{Activity, Idle};
+
+/// Characters escaped in a single path segment of a request URI. On top of
+/// the usual path segment set this covers `:` and `+`, which appear in caps and
+/// timestamps and which Tahoe decodes again on its end.
+#[derive(Clone, Copy)]
+struct SegmentEncodeSet;
+
+impl EncodeSet for SegmentEncodeSet {
+    fn contains(&self, byte: u8) -> bool {
+        PATH_SEGMENT_ENCODE_SET.contains(byte) || byte == b':' || byte == b'+'
+    }
+}
+
+fn encode_segment(segment: &str) -> String {
+    utf8_percent_encode(segment, SegmentEncodeSet).to_string()
+}
+
+/// Whether `cap`, or the cap that a `cap/path` starts from, is a directory
+/// write cap, going by its prefix. Read caps (`-RO`) and immutable ones
+/// (`-CHK`, `-LIT`) can never be written to, and neither can files.
+pub fn is_writable_dir_cap(cap: &str) -> bool {
+    let cap = cap.split('/').next().unwrap_or("");
+    cap.starts_with("URI:DIR2:") || cap.starts_with("URI:DIR2-MDMF:")
+}
+
+/// Whether `cap` is a literal file or directory cap, which holds its contents
+/// itself and so has no shares, and no leases, on any server.
+pub fn is_literal_cap(cap: &str) -> bool {
+    cap.starts_with("URI:LIT:") || cap.starts_with("URI:DIR2-LIT:")
+}
+
+/// Encodes each `/`-separated component of `path`, keeping the separators.
+fn encode_path(path: &str) -> String {
+    path.split('/')
+        .map(encode_segment)
+        .collect::<Vec<_>>()
+        .join("/")
+}
 
+/// A child as sent to `t=mkdir-immutable`. The metadata follows Tahoe's
+/// layout: `ctime` and `mtime` of the file at the top level, next to our own
+/// keys, and the link times in the `tahoe` namespace.
+///
+/// Mutable directories are linked by their write cap, which only a mutable
+/// parent can hold.
 #[derive(Serialize, Clone)]
 pub struct DirNodeInner {
-    ro_uri: String,
-    metadata: HashMap<String, u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    ro_uri: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    rw_uri: Option<String>,
+    metadata: HashMap<String, serde_json::Value>,
 }
 
-#[derive(Serialize, Clone)]
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
 pub enum NodeType {
     #[serde(rename = "dirnode")]
     Dir,
     #[serde(rename = "filenode")]
     File,
+    #[serde(rename = "unknown")]
+    Unknown,
 }
 
-pub struct Dir {
-    inner: Vec<(String, DirNode)>,
-    hasher: SeaHasher,
+fn unknown_node() -> NodeType {
+    NodeType::Unknown
 }
 
-impl Dir {
-    fn new() -> Self {
-        Dir {
-            inner: Vec::new(),
-            hasher: SeaHasher::new(),
+/// A child entry as returned by Tahoe's `?t=json` directory listing.
+#[derive(Deserialize, Clone, Debug)]
+pub struct ChildInfo {
+    #[serde(skip_deserializing, default = "unknown_node")]
+    pub nodetype: NodeType,
+    pub ro_uri: Option<String>,
+    pub rw_uri: Option<String>,
+    pub size: Option<u64>,
+    #[serde(default)]
+    pub metadata: HashMap<String, serde_json::Value>,
+}
+
+impl ChildInfo {
+    /// The most privileged cap we know for this child.
+    pub fn uri(&self) -> Option<&str> {
+        self.rw_uri.as_ref().or(self.ro_uri.as_ref()).map(|s| s.as_str())
+    }
+
+    /// Reads a timestamp stored in the child metadata. Tahoe itself stores
+    /// these as floats, while we write integers.
+    pub fn time(&self, key: &str) -> Option<u64> {
+        self.metadata
+            .get(key)
+            .and_then(|v| v.as_f64())
+            .map(|t| t as u64)
+    }
+
+    /// When the child was linked into its directory, as Tahoe records it.
+    pub fn link_time(&self) -> Option<u64> {
+        self.metadata
+            .get("tahoe")
+            .and_then(|tahoe| tahoe.get("linkcrtime"))
+            .and_then(|v| v.as_f64())
+            .map(|t| t as u64)
+    }
+
+    /// The permission bits recorded at backup time, on backups made on Unix.
+    pub fn unix_mode(&self) -> Option<u32> {
+        self.metadata
+            .get("unix_mode")
+            .and_then(|v| v.as_u64())
+            .map(|mode| mode as u32)
+    }
+}
+
+/// The outcome of checking a cap, from Tahoe's `?t=check` results.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct CheckResult {
+    pub healthy: bool,
+    #[serde(rename = "count-shares-good")]
+    pub shares_good: u32,
+    #[serde(rename = "count-shares-needed")]
+    pub shares_needed: u32,
+    #[serde(rename = "count-shares-expected")]
+    pub shares_expected: u32,
+}
+
+/// How a single cap fared in a check.
+#[derive(Clone, Copy, Debug)]
+pub struct CapCheck {
+    pub healthy: bool,
+    /// The share counts. Small files and directories kept in their cap
+    /// aren't on any server, so they have none.
+    pub results: Option<CheckResult>,
+}
+
+impl CapCheck {
+    /// Reads the `results` object of a check. Only `healthy` is always there.
+    fn from_results(results: serde_json::Value) -> Self {
+        CapCheck {
+            healthy: results.get("healthy").and_then(|v| v.as_bool()).unwrap_or(false),
+            results: serde_json::from_value(results).ok(),
         }
     }
+}
+
+#[derive(Deserialize)]
+struct CheckOutput {
+    results: serde_json::Value,
+}
+
+/// How one object below a directory fared in a deep check.
+#[derive(Clone, Debug)]
+pub struct DeepCheckResult {
+    /// The names leading to it from the checked directory, none for that
+    /// directory itself.
+    pub path: Vec<String>,
+    /// After the repair, if there was one.
+    pub healthy: bool,
+    /// The share counts, after the repair if there was one. Small files kept
+    /// in their cap aren't on any server, so they have none.
+    pub results: Option<CheckResult>,
+    pub repair_attempted: bool,
+    pub repair_successful: bool,
+}
 
-    fn push(&mut self, value: (String, DirNode)) {
-        self.hasher.write(value.0.as_bytes());
-        self.hasher.write(value.1.uri().as_bytes());
-        self.inner.push(value)
+/// A line of `t=stream-deep-check` output. The last one, of type `stats`,
+/// has none of these.
+#[derive(Deserialize)]
+struct DeepCheckLine {
+    #[serde(rename = "type")]
+    kind: String,
+    #[serde(default)]
+    path: Vec<String>,
+    #[serde(rename = "check-results")]
+    check: Option<CheckResults>,
+    #[serde(rename = "check-and-repair-results")]
+    repair: Option<RepairResults>,
+}
+
+#[derive(Deserialize)]
+struct CheckResults {
+    results: serde_json::Value,
+}
+
+#[derive(Deserialize)]
+struct RepairResults {
+    #[serde(rename = "repair-attempted")]
+    attempted: bool,
+    #[serde(rename = "repair-successful")]
+    successful: bool,
+    #[serde(rename = "post-repair-results")]
+    post: CheckResults,
+}
+
+impl DeepCheckLine {
+    fn into_result(self) -> Option<DeepCheckResult> {
+        let (check, attempted, successful) = match (self.check, self.repair) {
+            (_, Some(repair)) => (repair.post, repair.attempted, repair.successful),
+            (Some(check), None) => (check, false, false),
+            (None, None) => return None,
+        };
+        let check = CapCheck::from_results(check.results);
+        Some(DeepCheckResult {
+            path: self.path,
+            healthy: check.healthy,
+            results: check.results,
+            repair_attempted: attempted,
+            repair_successful: successful,
+        })
     }
+}
+
+/// Parses a line of `t=stream-deep-check` output, `None` being one that's
+/// not about an object. Tahoe stops with a line starting `ERROR:` when it
+/// can't go on, e.g. because a directory is unrecoverable.
+fn parse_deep_check_line(line: &[u8]) -> Result<Option<DeepCheckResult>> {
+    if line.starts_with(b"ERROR:") {
+        bail!("{}", String::from_utf8_lossy(line).trim());
+    }
+    let line: DeepCheckLine = serde_json::from_slice(line).chain_err(|| "Failed to parse deep-check results")?;
+    if line.kind == "stats" {
+        return Ok(None);
+    }
+    Ok(line.into_result())
+}
+
+#[derive(Deserialize)]
+struct DirInfo {
+    /// Only there if the cap the directory was looked up by can write to it.
+    rw_uri: Option<String>,
+    #[serde(default)]
+    children: HashMap<String, (NodeType, ChildInfo)>,
+}
+
+#[derive(Deserialize)]
+struct FileInfo {
+    size: Option<u64>,
+}
+
+/// What the node's welcome page, `/?t=json`, says about the storage servers
+/// it's connected to. Nodes before Tahoe 1.13 don't have it.
+#[derive(Deserialize)]
+struct Welcome {
+    #[serde(default)]
+    servers: Vec<ServerInfo>,
+}
 
+#[derive(Deserialize)]
+struct ServerInfo {
+    /// Only there once the server has announced it.
+    available_space: Option<u64>,
+}
+
+/// What `Dir::hash` is computed with. It goes up whenever that changes, so
+/// the hashes recorded before aren't mistaken for today's.
+pub const DIR_HASH_VERSION: i32 = 2;
+
+/// The children of a directory about to be uploaded, sorted by name so
+/// neither its hash nor its cap depends on the order they were read in.
+pub struct Dir {
+    inner: Vec<(String, DirNode)>,
+}
+
+impl Dir {
+    /// A hash of the names, caps and metadata of the children, version
+    /// `DIR_HASH_VERSION`. The link times Tahoe keeps in the `tahoe`
+    /// namespace are left out, as they're new every time, so a directory
+    /// gets a new hash when a child changes or just its times or owner do.
     pub fn hash(&self) -> u64 {
-        self.hasher.finish()
+        // Each field goes in after its length, so no two ways of splitting the
+        // same bytes into fields hash alike.
+        fn field(hasher: &mut SeaHasher, bytes: &[u8]) {
+            hasher.write_u64(bytes.len() as u64);
+            hasher.write(bytes);
+        }
+        let mut hasher = SeaHasher::new();
+        for (name, node) in &self.inner {
+            field(&mut hasher, name.as_bytes());
+            field(&mut hasher, node.uri().as_bytes());
+            let mut metadata: Vec<_> = node.1.metadata.iter().filter(|&(key, _)| key != "tahoe").collect();
+            metadata.sort_by(|a, b| a.0.cmp(b.0));
+            hasher.write_u64(metadata.len() as u64);
+            for (key, value) in metadata {
+                field(&mut hasher, key.as_bytes());
+                field(&mut hasher, value.to_string().as_bytes());
+            }
+        }
+        hasher.finish()
+    }
+
+    /// The same directory with every child's cap replaced by what `map`
+    /// makes of it, e.g. the cap the same file has on another grid. `None`
+    /// if there's nothing for one of them.
+    pub fn map_caps<F>(&self, mut map: F) -> Option<Dir>
+    where
+        F: FnMut(&str) -> Option<String>,
+    {
+        let inner = self.inner
+            .iter()
+            .map(|(name, node)| {
+                let mut node = node.clone();
+                let cap = map(node.uri())?;
+                // A write cap stays one, and a read cap a read cap.
+                match node.1.rw_uri {
+                    Some(ref mut uri) => *uri = cap,
+                    None => node.1.ro_uri = Some(cap),
+                }
+                Some((name.clone(), node))
+            })
+            .collect::<Option<_>>()?;
+        Some(Dir { inner })
     }
 }
 
@@ -69,7 +367,7 @@ impl Serialize for Dir {
         S: Serializer,
     {
         let mut map = serializer.serialize_map(Some(self.inner.len()))?;
-        for &(ref k, ref v) in &self.inner {
+        for (k, v) in &self.inner {
             map.serialize_entry(&k, &v)?;
         }
         map.end()
@@ -78,186 +376,1122 @@ impl Serialize for Dir {
 
 impl FromIterator<(String, DirNode)> for Dir {
     fn from_iter<I: IntoIterator<Item = (String, DirNode)>>(iter: I) -> Self {
-        let mut dir = Dir::new();
-        for item in iter {
-            dir.push(item);
-        }
-
-        dir
+        let mut inner: Vec<_> = iter.into_iter().collect();
+        inner.sort_by(|a, b| a.0.cmp(&b.0));
+        Dir { inner }
     }
 }
 
+#[cfg(unix)]
+fn insert_owner(metadata: &mut HashMap<String, serde_json::Value>, meta: &Metadata) {
+    use std::os::unix::fs::MetadataExt;
+
+    metadata.insert(String::from("unix_mode"), (meta.mode() & 0o7777).into());
+    metadata.insert(String::from("uid"), meta.uid().into());
+    metadata.insert(String::from("gid"), meta.gid().into());
+}
+
+#[cfg(not(unix))]
+fn insert_owner(_metadata: &mut HashMap<String, serde_json::Value>, _meta: &Metadata) {}
+
+/// Seconds since the epoch, as the float Tahoe uses for timestamps.
+fn seconds(time: SystemTime) -> Option<serde_json::Value> {
+    let since = time.duration_since(UNIX_EPOCH).ok()?;
+    let secs = since.as_secs() as f64 + f64::from(since.subsec_nanos()) / 1e9;
+    Some(secs.into())
+}
+
 #[derive(Serialize, Clone)]
 pub struct DirNode(NodeType, DirNodeInner);
 
 impl DirNode {
-    pub fn new(ro_uri: String, meta: io::Result<Metadata>) -> Self {
+    /// The node for `ro_uri`, with the times and owner from `meta`, which is
+    /// what the walk found for it.
+    pub fn new(ro_uri: String, meta: Option<Metadata>) -> Self {
         let nodetype = if ro_uri.starts_with("URI:DIR") {
             NodeType::Dir
         } else {
             NodeType::File
         };
         let mut metadata = HashMap::new();
-        if let Ok(meta) = meta {
-            if let Ok(created) = meta.created() {
-                if let Ok(ctime) = created.duration_since(UNIX_EPOCH) {
-                    metadata.insert(String::from("ctime"), ctime.as_secs());
-                }
+        if let Some(meta) = meta {
+            if let Some(ctime) = meta.created().ok().and_then(seconds) {
+                metadata.insert(String::from("ctime"), ctime);
             }
-            if let Ok(modified) = meta.modified() {
-                if let Ok(mtime) = modified.duration_since(UNIX_EPOCH) {
-                    metadata.insert(String::from("mtime"), mtime.as_secs());
-                }
+            if let Some(mtime) = meta.modified().ok().and_then(seconds) {
+                metadata.insert(String::from("mtime"), mtime);
             }
+            insert_owner(&mut metadata, &meta);
         }
-        DirNode(nodetype, DirNodeInner { ro_uri, metadata })
+        // The link is made when the directory is uploaded.
+        if let Some(now) = seconds(SystemTime::now()) {
+            let mut tahoe = serde_json::Map::new();
+            tahoe.insert(String::from("linkcrtime"), now.clone());
+            tahoe.insert(String::from("linkmotime"), now);
+            metadata.insert(String::from("tahoe"), tahoe.into());
+        }
+        // Anything else is already read-only.
+        let (ro_uri, rw_uri) = if ro_uri.starts_with("URI:DIR2:") || ro_uri.starts_with("URI:DIR2-MDMF:") {
+            (None, Some(ro_uri))
+        } else {
+            (Some(ro_uri), None)
+        };
+        DirNode(nodetype, DirNodeInner { ro_uri, rw_uri, metadata })
+    }
+
+    /// The node `child` was listed as, to hash a directory that's already on
+    /// the grid. `None` for a child without a cap.
+    pub fn from_child(child: &ChildInfo) -> Option<Self> {
+        child.uri()?;
+        let inner = DirNodeInner {
+            ro_uri: child.ro_uri.clone(),
+            rw_uri: child.rw_uri.clone(),
+            metadata: child.metadata.clone(),
+        };
+        Some(DirNode(child.nodetype, inner))
+    }
+
+    /// Adds an entry to the metadata stored along with the child.
+    pub fn insert_metadata<V: Into<serde_json::Value>>(&mut self, key: &str, value: V) {
+        self.1.metadata.insert(String::from(key), value.into());
     }
 
     fn uri(&self) -> &str {
-        &self.1.ro_uri
+        self.1.rw_uri.as_ref().or(self.1.ro_uri.as_ref()).unwrap()
+    }
+}
+
+/// Erasure coding parameters for uploads. Anything left unset uses the
+/// node's configured default.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Encoding {
+    /// Shares needed to recover a file (`k`).
+    pub needed: Option<u32>,
+    /// Servers that must hold shares for an upload to succeed.
+    pub happy: Option<u32>,
+    /// Shares created in total (`N`).
+    pub total: Option<u32>,
+}
+
+impl Encoding {
+    fn validate(&self) -> Result<()> {
+        let params = [self.needed, self.happy, self.total];
+        let given: Vec<u32> = params.iter().filter_map(|&p| p).collect();
+        if given.contains(&0) || given.windows(2).any(|w| w[0] > w[1]) {
+            bail!(ErrorKind::Encoding(*self));
+        }
+        Ok(())
+    }
+
+    fn query(&self) -> String {
+        let params = [("k", self.needed), ("happy", self.happy), ("n", self.total)];
+        params
+            .iter()
+            .filter_map(|&(key, value)| value.map(|value| format!("&{}={}", key, value)))
+            .collect()
     }
 }
 
 #[derive(Clone)]
 pub struct Tahoe {
-    client: Client<HttpConnector>,
+    client: Client<Connector>,
+    handle: reactor::Handle,
     pool: ThreadPool,
+    concurrency: usize,
     base: String,
+    node_url: String,
+    encoding: Encoding,
     file_uri: Uri,
     dir_uri: Uri,
+    mutable_dir_uri: Uri,
+    /// The welcome page, for what the node knows of the storage servers.
+    welcome_uri: Uri,
+    retries: u32,
+    timeout: Option<Duration>,
+    limit: Limit,
+    user_agent: UserAgent,
+}
+
+/// A request that says who's making it, for the node's logs.
+fn new_request(method: Method, uri: Uri, user_agent: &UserAgent) -> Request {
+    let mut request = Request::new(method, uri);
+    request.headers_mut().set(user_agent.clone());
+    request
+}
+
+/// Sends `request`, logging what it's for at debug level.
+fn send(client: &Client<Connector>, request: Request) -> FutureResponse {
+    debug!("{} {}", request.method(), redact(request.uri()));
+    client.request(request)
+}
+
+/// How much of a cap in a request URI is logged, after its type.
+const REDACTED_CAP_CHARS: usize = 4;
+
+/// `uri` with every cap in its path cut down to its type and the start of
+/// its key, as caps are secrets that shouldn't end up in logs.
+fn redact(uri: &Uri) -> String {
+    let path = uri.path()
+        .split('/')
+        .map(|segment| {
+            let parts: Vec<_> = segment.splitn(3, "%3A").collect();
+            if parts.len() < 3 || parts[0] != "URI" {
+                return segment.to_owned();
+            }
+            let key: String = parts[2].chars().take(REDACTED_CAP_CHARS).collect();
+            format!("URI:{}:{}...", parts[1], key)
+        })
+        .collect::<Vec<_>>()
+        .join("/");
+    match (uri.scheme(), uri.authority(), uri.query()) {
+        (Some(scheme), Some(authority), query) => format!(
+            "{}://{}{}{}",
+            scheme,
+            authority,
+            path,
+            query.map_or_else(String::new, |query| format!("?{}", query))
+        ),
+        _ => path,
+    }
+}
+
+/// Fails with `NodeUnreachable` if the connection to the node couldn't be
+/// made at all, or else with `error` as it is.
+fn send_err(error: hyper::Error) -> Error {
+    let unreachable = match error {
+        hyper::Error::Io(ref e) => matches!(
+            e.kind(),
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::AddrNotAvailable | io::ErrorKind::NotConnected
+        ),
+        _ => false,
+    };
+    if unreachable {
+        Error::with_chain(error, ErrorKind::NodeUnreachable)
+    } else {
+        error.into()
+    }
+}
+
+/// The error for an unsuccessful `status`, for the statuses that say
+/// something more specific than the code itself.
+fn status_err(status: StatusCode) -> ErrorKind {
+    match status {
+        StatusCode::NotFound | StatusCode::Gone => ErrorKind::CapNotFound,
+        StatusCode::ServiceUnavailable => ErrorKind::ServerOverloaded,
+        status => ErrorKind::Tahoe(status),
+    }
+}
+
+/// How much of the body of an error response is kept.
+const ERROR_BODY_LIMIT: usize = 4096;
+
+/// Passes on a successful response. Otherwise fails with its status, caused
+/// by the first line of its body, where Tahoe says what went wrong. The rest
+/// of that, often a traceback, is logged at debug level.
+fn check_status(res: Response) -> Box<Future<Item = Response, Error = Error>> {
+    let status = res.status();
+    if status.is_success() {
+        return Box::new(future::ok(res));
+    }
+    Box::new(res.body().concat2().then(move |body| {
+        let body = match body {
+            Ok(ref body) => String::from_utf8_lossy(&body[..body.len().min(ERROR_BODY_LIMIT)])
+                .trim()
+                .to_owned(),
+            Err(_) => String::new(),
+        };
+        debug!("Tahoe returned {}: {}", status, body);
+        match body.lines().next() {
+            Some(line) => Err(Error::with_chain(Error::from(line), status_err(status))),
+            None => Err(status_err(status).into()),
+        }
+    }))
+}
+
+/// How long an idle connection is kept around for the next request.
+const KEEP_ALIVE_SECS: u64 = 90;
+
+/// Delay before retry number `attempt` (starting at 0): 1s, 2s, 4s, ... plus
+/// up to 50% random jitter so parallel uploads don't retry in lockstep.
+fn backoff(attempt: u32) -> Duration {
+    let base = 1000u64 << attempt.min(10);
+    let jitter = rand::random::<u64>() % (base / 2 + 1);
+    Duration::from_millis(base + jitter)
+}
+
+/// Parses a node URL as `Options::base` takes it, a bare `host:port` meaning
+/// plain HTTP.
+fn parse_base(base: &str) -> Result<Url> {
+    let url = if base.contains("://") {
+        Url::parse(base)
+    } else {
+        Url::parse(&format!("http://{}", base))
+    }.chain_err(|| ErrorKind::NodeUrl(String::from(base)))?;
+    if url.scheme() != "http" && url.scheme() != "https" {
+        bail!(ErrorKind::NodeUrl(String::from(base)));
+    }
+    Ok(url)
+}
+
+/// How `Tahoe::new` sets up a client. `Options::default()` talks to a
+/// local node on its default port.
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The node, either a full `http://` or `https://` URL (possibly with a
+    /// path prefix) or just a `host:port`. Defaults to `127.0.0.1:3456`.
+    pub base: Option<String>,
+    /// The node files and directories are uploaded to, if not `base`. That
+    /// lets a node that's close to the storage servers, or one that uses an
+    /// upload helper, take the uploads, while `base` is the front end that
+    /// serves the web API for reading and linking.
+    pub upload_base: Option<String>,
+    /// How many files are read from disk at once.
+    pub num_threads: usize,
+    /// How many uploads and other requests callers keep going at once.
+    pub concurrency: usize,
+    /// Caps the requests in flight, and so the number of sockets.
+    /// Connections are kept alive and reused between requests. An upload
+    /// holds its connection for as long as its file is being read, so fewer
+    /// connections than threads leaves threads idle.
+    pub max_connections: Option<usize>,
+    /// How many times a request that fails with a transient error is tried
+    /// again.
+    pub retries: u32,
+    /// Uploads and attaches fail once they make no progress for this long.
+    pub timeout: Option<Duration>,
+    /// The erasure coding files and directories are uploaded with.
+    pub encoding: Encoding,
+    /// A `HOST:PORT` SOCKS5 proxy every connection goes through, which also
+    /// resolves the node's host name. The node URLs have to be `http://`
+    /// then, as TLS isn't done through the proxy; an onion service is
+    /// encrypted either way.
+    pub socks5: Option<String>,
+    /// Sent as the `User-Agent` of every request.
+    pub user_agent: String,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            base: None,
+            upload_base: None,
+            num_threads: 4,
+            concurrency: 4,
+            max_connections: None,
+            retries: 3,
+            timeout: None,
+            encoding: Encoding::default(),
+            socks5: None,
+            user_agent: format!("tahoe/{}", env!("CARGO_PKG_VERSION")),
+        }
+    }
 }
 
 impl Tahoe {
-    pub fn new(num_threads: usize, handle: &reactor::Handle, base: Option<&str>) -> Result<Self> {
+    /// Creates a client as `options` says, running its requests on `handle`.
+    pub fn new(handle: &reactor::Handle, options: Options) -> Result<Self> {
+        let Options {
+            base,
+            upload_base,
+            num_threads,
+            concurrency,
+            max_connections,
+            retries,
+            timeout,
+            encoding,
+            socks5,
+            user_agent,
+        } = options;
+        encoding.validate()?;
         let pool = ThreadPool::new(num_threads);
-        let base = base.unwrap_or("127.0.0.1:3456");
-        let base_str = &format!("http://{}/uri", base);
-        let file_uri = Uri::from_str(base_str).chain_err(|| "failed to parse base")?;
-        let dir_uri = Uri::from_str(&format!("{}?t=mkdir-immutable", base_str))
+        let base_url = parse_base(base.as_ref().map_or("127.0.0.1:3456", String::as_str))?;
+        let upload_url = match upload_base {
+            Some(ref upload_base) => parse_base(upload_base)?,
+            None => base_url.clone(),
+        };
+        let base_str = &format!("{}/uri", base_url.as_str().trim_end_matches('/'));
+        let upload_str = &format!("{}/uri", upload_url.as_str().trim_end_matches('/'));
+        let query = encoding.query();
+        let file_uri = if query.is_empty() {
+            Uri::from_str(upload_str)
+        } else {
+            Uri::from_str(&format!("{}?{}", upload_str, &query[1..]))
+        }.chain_err(|| "failed to parse base")?;
+        let dir_uri = Uri::from_str(&format!("{}?t=mkdir-immutable{}", upload_str, query))
             .chain_err(|| "failed to add mkdir")?;
-        let client = Client::new(handle);
+        // `t=mkdir` ignores the body, so the children go along this way.
+        let mutable_dir_uri = Uri::from_str(&format!("{}?t=mkdir-with-children&format=MDMF", upload_str))
+            .chain_err(|| "failed to add mkdir")?;
+        let welcome_uri = Uri::from_str(&format!("{}/?t=json", base_url.as_str().trim_end_matches('/')))
+            .chain_err(|| "failed to parse base")?;
+        let connector = match socks5 {
+            Some(ref proxy) => {
+                if base_url.scheme() != "http" || upload_url.scheme() != "http" {
+                    bail!(ErrorKind::ProxyTls);
+                }
+                let addr = proxy
+                    .to_socket_addrs()
+                    .ok()
+                    .and_then(|mut addrs| addrs.next())
+                    .ok_or_else(|| ErrorKind::Proxy(proxy.clone()))?;
+                info!("Connecting through SOCKS5 proxy {}", addr);
+                Connector::Socks5(Socks5::new(addr, handle))
+            }
+            None => Connector::Direct(
+                HttpsConnector::new(num_threads, handle).chain_err(|| "failed to set up TLS")?,
+            ),
+        };
+        let client = Client::configure()
+            .connector(connector)
+            .keep_alive(true)
+            .keep_alive_timeout(Some(Duration::from_secs(KEEP_ALIVE_SECS)))
+            .build(handle);
 
-        info!("Connecting to {} with {} threads", base_str, num_threads);
+        info!(
+            "Connecting to {} with {} threads, {} requests at once",
+            base_str, num_threads, concurrency
+        );
+        if upload_url != base_url {
+            info!("Uploading through {}", upload_str);
+        }
         Ok(Tahoe {
             client,
+            handle: handle.clone(),
             pool,
+            concurrency,
             base: base_str.clone(),
+            node_url: String::from(base_url.as_str().trim_end_matches('/')),
+            encoding,
             file_uri,
             dir_uri,
+            mutable_dir_uri,
+            welcome_uri,
+            retries,
+            timeout,
+            limit: Limit::new(max_connections),
+            user_agent: UserAgent::new(user_agent),
         })
     }
 
+    /// Runs the future produced by `attempt`, recreating and rerunning it with
+    /// exponential backoff as long as it fails with a transient error and
+    /// `self.retries` hasn't been exhausted.
+    fn retry<F, R>(&self, attempt: F) -> impl Future<Item = R::Item, Error = Error>
+    where
+        F: Fn() -> R + 'static,
+        R: IntoFuture<Error = Error> + 'static,
+    {
+        let handle = self.handle.clone();
+        let retries = self.retries;
+        future::loop_fn(0, move |n| {
+            let handle = handle.clone();
+            attempt().into_future().then(
+                move |res| -> Box<Future<Item = Loop<R::Item, u32>, Error = Error>> {
+                    match res {
+                        Ok(x) => Box::new(future::ok(Loop::Break(x))),
+                        Err(ref e) if n < retries && e.is_transient() => {
+                            let delay = backoff(n);
+                            warn!(
+                                "{}, retrying in {}s ({}/{})",
+                                e,
+                                delay.as_secs(),
+                                n + 1,
+                                retries
+                            );
+                            Box::new(
+                                reactor::Timeout::new(delay, &handle)
+                                    .into_future()
+                                    .flatten()
+                                    .map_err(|e| Error::with_chain(e, "failed to wait for retry"))
+                                    .map(move |_| Loop::Continue(n + 1)),
+                            )
+                        }
+                        Err(e) => Box::new(future::err(e)),
+                    }
+                },
+            )
+        })
+    }
+
+    /// How many files are read from disk at once.
     pub fn threads(&self) -> usize {
         self.pool.max_count()
     }
 
+    /// How many uploads, and requests of any kind, to keep going at once.
+    pub fn concurrency(&self) -> usize {
+        self.concurrency
+    }
+
+    /// The URL of the node, as given.
+    pub fn node_url(&self) -> &str {
+        &self.node_url
+    }
+
+    /// The encoding parameters uploads ask for.
+    pub fn encoding(&self) -> Encoding {
+        self.encoding
+    }
+
+    /// Links `filecap` at `path` below the directory `dircap`, replacing
+    /// whatever was there. That's the same every time, so it's retried like
+    /// any other request.
     pub fn attach(
         &self,
         dircap: &str,
         path: &str,
         filecap: &str,
     ) -> Result<impl Future<Item = (), Error = Error>> {
-        let body: Body = String::from(filecap).into();
-        let uri = Uri::from_str(&format!("{}/{}/{}?t=uri", self.base, dircap, path))
+        let filecap = String::from(filecap);
+        let uri = Uri::from_str(&format!(
+            "{}/{}/{}?t=uri",
+            self.base,
+            encode_path(dircap),
+            encode_path(path)
+        ))
             .chain_err(|| "failed to form url")?;
 
-        let mut request = Request::new(Method::Put, uri);
-        request.set_body(body);
+        let client = self.client.clone();
+        let limit = self.limit.clone();
+        let user_agent = self.user_agent.clone();
+        let handle = self.handle.clone();
+        let timeout = self.timeout;
+        Ok(self.retry(move || {
+            let mut request = new_request(Method::Put, uri.clone(), &user_agent);
+            request.headers_mut().set(ContentType::plaintext());
+            request.set_body(Body::from(filecap.clone()));
+            let client = client.clone();
+            let handle = handle.clone();
+            limit.run(move || {
+                let attach = send(&client, request)
+                    .map_err(send_err)
+                    .and_then(check_status)
+                    .map(|_| ());
+                Idle::new(attach, timeout, Activity::new(), &handle)
+            })
+        }))
+    }
+
+    /// Makes sure there's a directory at `path` below `dircap`, making it and
+    /// any missing directories on the way as mutable ones. One that's there
+    /// already is left as it is. Yields the write cap of the directory.
+    pub fn mkdir(&self, dircap: &str, path: &str) -> Result<impl Future<Item = String, Error = Error>> {
+        let uri = Uri::from_str(&format!(
+            "{}/{}/{}?t=mkdir",
+            self.base,
+            encode_path(dircap),
+            encode_path(path)
+        ))
+            .chain_err(|| "failed to form url")?;
+
+        let client = self.client.clone();
+        let limit = self.limit.clone();
+        let user_agent = self.user_agent.clone();
+        Ok(self.retry(move || {
+            let request = new_request(Method::Post, uri.clone(), &user_agent);
+            let client = client.clone();
+            limit.run(move || {
+                send(&client, request)
+                    .map_err(send_err)
+                    .and_then(check_status)
+                    .and_then(|res| res.body().concat2().map_err(upload_err))
+                    .and_then(|b| String::from_utf8(b.to_vec()).map_err(upload_err))
+            })
+        }))
+    }
+
+    /// Removes the link at `path` below the directory `dircap`. Whatever it
+    /// pointed to stays on the grid until its leases run out.
+    pub fn unlink(&self, dircap: &str, path: &str) -> Result<impl Future<Item = (), Error = Error>> {
+        let uri = Uri::from_str(&format!(
+            "{}/{}/{}",
+            self.base,
+            encode_path(dircap),
+            encode_path(path)
+        ))
+            .chain_err(|| "failed to form url")?;
+
+        let client = self.client.clone();
+        let limit = self.limit.clone();
+        let user_agent = self.user_agent.clone();
+        Ok(self.retry(move || {
+            let request = new_request(Method::Delete, uri.clone(), &user_agent);
+            let client = client.clone();
+            limit.run(move || {
+                send(&client, request)
+                    .map_err(send_err)
+                    .and_then(check_status)
+                    .map(|_| ())
+            })
+        }))
+    }
+
+    /// Lists the children of the directory `cap`, with their caps, sizes and
+    /// metadata.
+    pub fn read_dir(
+        &self,
+        cap: &str,
+    ) -> Result<impl Future<Item = Vec<(String, ChildInfo)>, Error = Error>> {
+        let uri = Uri::from_str(&format!("{}/{}?t=json", self.base, encode_path(cap)))
+            .chain_err(|| "failed to form url")?;
 
-        Ok(self.client
-            .request(request)
-            .map_err(upload_err)
-            .and_then(|res| {
-                if res.status().is_success() {
-                    Ok(())
-                } else {
-                    bail!(ErrorKind::Tahoe(res.status()))
+        let request = new_request(Method::Get, uri, &self.user_agent);
+        let client = self.client.clone();
+        let listing = self.limit.run(move || {
+            send(&client, request)
+                .map_err(send_err)
+                .and_then(check_status)
+                .and_then(|res| res.body().concat2().map_err(Error::from))
+        });
+        Ok(listing.and_then(|b: Chunk| {
+                let (nodetype, dir): (NodeType, DirInfo) = serde_json::from_slice(&b)
+                    .chain_err(|| "Failed to parse directory listing")?;
+                if nodetype != NodeType::Dir {
+                    bail!(ErrorKind::NotADirectory);
                 }
+                Ok(dir.children
+                    .into_iter()
+                    .map(|(name, (nodetype, mut child))| {
+                        child.nodetype = nodetype;
+                        (name, child)
+                    })
+                    .collect())
             }))
     }
 
+    /// Makes sure the node is reachable and `cap` is a directory that can be
+    /// written to, so a backup can't get all the way to attaching before it
+    /// finds out.
+    pub fn check_writable_dir(&self, cap: &str) -> Result<impl Future<Item = (), Error = Error>> {
+        let uri = Uri::from_str(&format!("{}/{}?t=json", self.base, encode_path(cap)))
+            .chain_err(|| "failed to form url")?;
+
+        let client = self.client.clone();
+        let limit = self.limit.clone();
+        let user_agent = self.user_agent.clone();
+        let stat = self.retry(move || {
+            let request = new_request(Method::Get, uri.clone(), &user_agent);
+            let client = client.clone();
+            limit.run(move || {
+                send(&client, request)
+                    .map_err(send_err)
+                    .and_then(check_status)
+                    .and_then(|res| res.body().concat2().map_err(Error::from))
+            })
+        });
+        Ok(stat.and_then(|b: Chunk| {
+            let (nodetype, dir): (NodeType, DirInfo) =
+                serde_json::from_slice(&b).chain_err(|| "Failed to parse directory info")?;
+            if nodetype != NodeType::Dir {
+                bail!(ErrorKind::NotADirectory);
+            }
+            if dir.rw_uri.is_none() {
+                bail!(ErrorKind::ReadOnlyCap);
+            }
+            Ok(())
+        }))
+    }
+
+    /// The size of the file `cap`, as stored on the grid.
+    pub fn stat_cap(&self, cap: &str) -> Result<impl Future<Item = u64, Error = Error>> {
+        let uri = Uri::from_str(&format!("{}/{}?t=json", self.base, encode_path(cap)))
+            .chain_err(|| "failed to form url")?;
+
+        let client = self.client.clone();
+        let limit = self.limit.clone();
+        let user_agent = self.user_agent.clone();
+        let stat = self.retry(move || {
+            let request = new_request(Method::Get, uri.clone(), &user_agent);
+            let client = client.clone();
+            limit.run(move || {
+                send(&client, request)
+                    .map_err(send_err)
+                    .and_then(check_status)
+                    .and_then(|res| res.body().concat2().map_err(Error::from))
+            })
+        });
+        Ok(stat.and_then(|b: Chunk| {
+            let (nodetype, file): (NodeType, FileInfo) =
+                serde_json::from_slice(&b).chain_err(|| "Failed to parse file info")?;
+            if nodetype != NodeType::File {
+                bail!("not a file");
+            }
+            file.size.ok_or_else(|| "no size in file info".into())
+        }))
+    }
+
+    /// How many bytes the storage servers the node is connected to have free
+    /// between them, before the shares of a file are spread over them. `None`
+    /// if the node doesn't say, as nodes before Tahoe 1.13 and servers that
+    /// haven't announced it don't.
+    pub fn grid_available_bytes(&self) -> impl Future<Item = Option<u64>, Error = Error> {
+        let uri = self.welcome_uri.clone();
+        let client = self.client.clone();
+        let limit = self.limit.clone();
+        let user_agent = self.user_agent.clone();
+        let welcome = self.retry(move || {
+            let request = new_request(Method::Get, uri.clone(), &user_agent);
+            let client = client.clone();
+            limit.run(move || {
+                send(&client, request)
+                    .map_err(send_err)
+                    .and_then(check_status)
+                    .and_then(|res| res.body().concat2().map_err(Error::from))
+            })
+        });
+        welcome.map(|b: Chunk| {
+            let welcome: Welcome = serde_json::from_slice(&b).ok()?;
+            let spaces: Vec<u64> = welcome.servers.iter().filter_map(|server| server.available_space).collect();
+            if spaces.is_empty() {
+                None
+            } else {
+                Some(spaces.iter().sum())
+            }
+        })
+    }
+
+    /// Asks the node to check the health of the file or directory `cap`.
+    pub fn check_cap(&self, cap: &str) -> Result<impl Future<Item = CapCheck, Error = Error>> {
+        self.check(cap, false)
+    }
+
+    /// Renews the lease on every share of `cap`, so storage servers don't
+    /// garbage collect it.
+    pub fn renew_lease(&self, cap: &str) -> Result<impl Future<Item = (), Error = Error>> {
+        Ok(self.check(cap, true)?.map(|_| ()))
+    }
+
+    fn check(
+        &self,
+        cap: &str,
+        add_lease: bool,
+    ) -> Result<impl Future<Item = CapCheck, Error = Error>> {
+        let uri = Uri::from_str(&format!(
+            "{}/{}?t=check&output=JSON{}",
+            self.base,
+            encode_path(cap),
+            if add_lease { "&add-lease=true" } else { "" }
+        )).chain_err(|| "failed to form url")?;
+
+        let request = new_request(Method::Post, uri, &self.user_agent);
+        let client = self.client.clone();
+        let check = self.limit.run(move || {
+            send(&client, request)
+                .map_err(send_err)
+                .and_then(check_status)
+                .and_then(|res| res.body().concat2().map_err(Error::from))
+        });
+        Ok(check.and_then(|b: Chunk| {
+                let output: CheckOutput =
+                    serde_json::from_slice(&b).chain_err(|| "Failed to parse check results")?;
+                Ok(CapCheck::from_results(output.results))
+            }))
+    }
+
+    /// Checks `cap` and everything reachable from it on the node's side,
+    /// yielding a result for each object as the node gets to it. With
+    /// `repair`, whatever isn't healthy is repaired as well.
+    pub fn deep_check(
+        &self,
+        cap: &str,
+        repair: bool,
+    ) -> Result<impl Stream<Item = DeepCheckResult, Error = Error>> {
+        let uri = Uri::from_str(&format!(
+            "{}/{}?t=stream-deep-check{}",
+            self.base,
+            encode_path(cap),
+            if repair { "&repair=true" } else { "" }
+        )).chain_err(|| "failed to form url")?;
+
+        let request = new_request(Method::Post, uri, &self.user_agent);
+        let client = self.client.clone();
+        let response = self.limit.run(move || {
+            send(&client, request)
+                .map_err(send_err)
+                .and_then(check_status)
+        });
+        Ok(response
+            .map(|res| {
+                // Lines can be split over chunks, so the start of one is kept
+                // until the rest of it comes in.
+                let partial = Rc::new(RefCell::new(Vec::new()));
+                let rest = partial.clone();
+                let lines = res.body().map_err(Error::from).map(move |chunk| {
+                    let mut partial = partial.borrow_mut();
+                    partial.extend_from_slice(&chunk);
+                    let end = partial.iter().rposition(|&b| b == b'\n').map_or(0, |i| i + 1);
+                    let lines: Vec<Vec<u8>> = partial.drain(..end).collect::<Vec<u8>>()
+                        .split(|&b| b == b'\n')
+                        .map(|line| line.to_vec())
+                        .collect();
+                    stream::iter_ok::<_, Error>(lines)
+                });
+                let last = future::lazy(move || Ok(stream::iter_ok(vec![rest.replace(Vec::new())])));
+                lines
+                    .chain(last.into_stream())
+                    .flatten()
+                    .filter(|line| !line.iter().all(|b| b.is_ascii_whitespace()))
+                    .and_then(|line| parse_deep_check_line(&line))
+                    .filter_map(|result| result)
+            })
+            .flatten_stream())
+    }
+
+    /// Starts downloading `cap`. Only waiting for the response counts
+    /// towards `max_connections`, not reading the body.
+    pub fn download_file(&self, cap: &str) -> Result<impl Future<Item = Body, Error = Error>> {
+        let uri = Uri::from_str(&format!("{}/{}", self.base, encode_path(cap)))
+            .chain_err(|| "failed to form url")?;
+
+        let request = new_request(Method::Get, uri, &self.user_agent);
+        let client = self.client.clone();
+        Ok(self.limit.run(move || {
+            send(&client, request)
+                .map_err(send_err)
+                .and_then(check_status)
+                .map(|res| res.body())
+        }))
+    }
+
     pub fn upload_dir(&self, dir: &Dir) -> Result<impl Future<Item = String, Error = Error>> {
-        let body: Body = serde_json::to_vec(dir)
-            .chain_err(|| "Failed to serialize directory")?
-            .into();
+        let body = serde_json::to_vec(dir).chain_err(|| "Failed to serialize directory")?;
+        Ok(self.upload_body(Method::Post, self.dir_uri.clone(), ContentType::json(), body))
+    }
 
-        let mut request = Request::new(Method::Post, self.dir_uri.clone());
-        request.set_body(body);
+    /// Uploads `dir` as a new mutable MDMF directory, resolving to its write
+    /// cap. Unlike an immutable one, it's a new directory every time.
+    pub fn upload_mutable_dir(&self, dir: &Dir) -> Result<impl Future<Item = String, Error = Error>> {
+        let body = serde_json::to_vec(dir).chain_err(|| "Failed to serialize directory")?;
+        Ok(self.upload_body(Method::Post, self.mutable_dir_uri.clone(), ContentType::json(), body))
+    }
 
-        Ok(self.client
-            .request(request)
-            .map_err(upload_err)
-            .and_then(|res| {
-                if res.status().is_success() {
-                    Ok(res)
-                } else {
-                    bail!(ErrorKind::Tahoe(res.status()))
-                }
+    /// Uploads `data` as an immutable file.
+    pub fn upload_data(&self, data: Vec<u8>) -> impl Future<Item = String, Error = Error> {
+        self.upload_body(Method::Put, self.file_uri.clone(), ContentType::octet_stream(), data)
+    }
+
+    /// Sends `body` to `uri`, resolving to the cap the node responds with.
+    fn upload_body(
+        &self,
+        method: Method,
+        uri: Uri,
+        content_type: ContentType,
+        body: Vec<u8>,
+    ) -> impl Future<Item = String, Error = Error> {
+        let client = self.client.clone();
+        let handle = self.handle.clone();
+        let timeout = self.timeout;
+        let limit = self.limit.clone();
+        let user_agent = self.user_agent.clone();
+
+        self.retry(move || {
+            let mut request = new_request(method.clone(), uri.clone(), &user_agent);
+            request.headers_mut().set(content_type.clone());
+            request.set_body(body.clone());
+
+            let client = client.clone();
+            let handle = handle.clone();
+            limit.run(move || {
+                let upload = send(&client, request)
+                    .map_err(send_err)
+                    .and_then(check_status)
+                    .and_then(|res| res.body().concat2().map_err(upload_err))
+                    .and_then(|b| String::from_utf8(b.to_vec()).map_err(upload_err));
+                Idle::new(upload, timeout, Activity::new(), &handle)
             })
-            .and_then(|res| res.body().concat2().map_err(upload_err))
-            .and_then(|b| String::from_utf8(b.to_vec()).map_err(upload_err))) // TODO: Don't clone here
+        })
     }
 
-    pub fn upload_file<F: Fn(usize) -> ()>(
+    /// Uploads the file at `path`. The file is reopened for every attempt, as
+    /// each attempt consumes it while streaming the request body. The timeout
+    /// starts over whenever another chunk of the file is sent.
+    ///
+    /// With `compression`, the contents are compressed as they're read, on
+    /// the reading thread, and `progress` counts compressed bytes.
+    ///
+    /// Along with the cap comes how many bytes of the file the reading
+    /// thread got, before any compression, for the caller to check against
+    /// the size it expects.
+    pub fn upload_file<P, F>(
         &self,
-        mut file: File,
+        path: P,
+        compression: Option<Compression>,
+        progress: F,
+    ) -> impl Future<Item = (String, u64), Error = Error>
+    where
+        P: AsRef<Path> + 'static,
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        self.upload_file_to(&[], path, compression, progress)
+            .map(|(mut caps, read)| (caps.remove(0), read))
+    }
+
+    /// Uploads the file at `path` like `upload_file`, while reading it only
+    /// once, to this node and every one of `mirrors` at the same time. The
+    /// caps come in that order. A failure on any of them fails the attempt,
+    /// and a retry uploads to all of them again.
+    ///
+    /// Only this node's connection limit and timeout apply.
+    pub fn upload_file_to<P, F>(
+        &self,
+        mirrors: &[&Tahoe],
+        path: P,
+        compression: Option<Compression>,
+        progress: F,
+    ) -> impl Future<Item = (Vec<String>, u64), Error = Error>
+    where
+        P: AsRef<Path> + 'static,
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let targets = self.upload_targets(mirrors);
+        let pool = self.pool.clone();
+        let progress = Arc::new(progress);
+        let handle = self.handle.clone();
+        let timeout = self.timeout;
+        let limit = self.limit.clone();
+        let path = Rc::new(path);
+
+        self.retry(move || {
+            let targets = targets.clone();
+            let pool = pool.clone();
+            let progress = progress.clone();
+            let handle = handle.clone();
+            let path = path.clone();
+            // The file is only opened, and the reader started, once there's a
+            // connection for it, so waiting uploads don't tie up threads.
+            limit.run(move || {
+                let activity = Activity::new();
+                let requests = file_requests(targets);
+                let read = Arc::new(AtomicUsize::new(0));
+                let file = File::open((*path).as_ref())
+                    .and_then(|file| encode(Counting { inner: file, read: read.clone() }, compression));
+                let upload = send_file(requests, &pool, file, progress, activity.clone())
+                    .map(move |caps| (caps, read.load(Ordering::SeqCst) as u64));
+                Idle::new(upload, timeout, activity, &handle)
+            })
+        })
+    }
+
+    /// Uploads everything `reader` has as an immutable file, like
+    /// `upload_file`. As a pipe can only be read once, there's only the one
+    /// attempt.
+    pub fn upload_reader<R, F>(
+        &self,
+        reader: R,
+        compression: Option<Compression>,
         progress: F,
     ) -> impl Future<Item = String, Error = Error>
     where
-        F: Send + 'static,
+        R: Read + Send + 'static,
+        F: Fn(usize) + Send + Sync + 'static,
     {
+        self.upload_reader_to(&[], reader, compression, progress)
+            .map(|mut caps| caps.remove(0))
+    }
+
+    /// Uploads everything `reader` has to this node and every one of
+    /// `mirrors`, like `upload_file_to`, in a single attempt.
+    pub fn upload_reader_to<R, F>(
+        &self,
+        mirrors: &[&Tahoe],
+        reader: R,
+        compression: Option<Compression>,
+        progress: F,
+    ) -> impl Future<Item = Vec<String>, Error = Error>
+    where
+        R: Read + Send + 'static,
+        F: Fn(usize) + Send + Sync + 'static,
+    {
+        let targets = self.upload_targets(mirrors);
+        let pool = self.pool.clone();
+        let handle = self.handle.clone();
+        let timeout = self.timeout;
+        self.limit.run(move || {
+            let activity = Activity::new();
+            let upload = send_file(
+                file_requests(targets),
+                &pool,
+                encode(reader, compression),
+                Arc::new(progress),
+                activity.clone(),
+            );
+            Idle::new(upload, timeout, activity, &handle)
+        })
+    }
+
+    /// Where a file goes on this node and each of `mirrors`.
+    fn upload_targets(&self, mirrors: &[&Tahoe]) -> Vec<(Client<Connector>, Uri, UserAgent)> {
+        Some(self)
+            .into_iter()
+            .chain(mirrors.iter().cloned())
+            .map(|node| (node.client.clone(), node.file_uri.clone(), node.user_agent.clone()))
+            .collect()
+    }
+}
+
+/// A request to upload a file to each of `targets`.
+fn file_requests(targets: Vec<(Client<Connector>, Uri, UserAgent)>) -> Vec<(Client<Connector>, Request)> {
+    targets
+        .into_iter()
+        .map(|(client, uri, user_agent)| (client, new_request(Method::Put, uri, &user_agent)))
+        .collect()
+}
+
+/// Counts the bytes read through it into `read`.
+struct Counting<R> {
+    inner: R,
+    read: Arc<AtomicUsize>,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read.fetch_add(n, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+/// `reader`, compressed with `compression` if there is one.
+fn encode<R: Read + Send + 'static>(reader: R, compression: Option<Compression>) -> io::Result<Box<Read + Send>> {
+    match compression {
+        Some(compression) => compression.encoder(reader),
+        None => Ok(Box::new(reader)),
+    }
+}
+
+/// A single attempt at uploading what `file` reads with each of `requests`,
+/// touching `activity` whenever a chunk was sent. The file is read once, and
+/// every chunk goes to all of them before the next one is read. Resolves to
+/// the cap each of them got, in order.
+fn send_file<F>(
+    requests: Vec<(Client<Connector>, Request)>,
+    pool: &ThreadPool,
+    file: io::Result<Box<Read + Send>>,
+    progress: Arc<F>,
+    activity: Activity,
+) -> Box<Future<Item = Vec<String>, Error = Error>>
+where
+    F: Fn(usize) + Send + Sync + 'static,
+{
+    let file = match file {
+        Ok(f) => f,
+        Err(e) => return Box::new(future::err(e.into())),
+    };
+    let mut senders = Vec::new();
+    let mut responses = Vec::new();
+    for (client, mut request) in requests {
         let (tx, body) = Body::pair();
-        let mut request = Request::new(Method::Put, self.file_uri.clone());
+        request.headers_mut().set(ContentType::octet_stream());
         request.set_body(body);
+        senders.push(tx);
+        responses.push(
+            send(&client, request)
+                .map_err(send_err)
+                .and_then(check_status)
+                .and_then(|res| {
+                    res.body()
+                        .concat2()
+                        .map_err(|e| Error::with_chain(e, "Failed to read response"))
+                })
+                .and_then(|b| {
+                    String::from_utf8(b.to_vec())
+                        .map_err(|e| Error::with_chain(e, "Failed to parse response into string"))
+                }),
+        );
+    }
 
-        self.pool.execute(move || {
-            let mut tx_body = tx;
-            let mut buf = [0u8; 1024];
-
-            loop {
-                match file.read(&mut buf) {
-                    Err(_) => {
-                        break;
-                    }
-                    Ok(0) => {
-                        tx_body.close().expect("panic closing");
-                        break;
-                    }
-                    Ok(n) => {
-                        let chunk: Chunk = buf[0..n].to_vec().into();
-                        match tx_body.send(Ok(chunk)).wait() {
-                            Ok(t) => {
-                                (progress)(n);
-                                tx_body = t;
-                            }
-                            Err(_) => {
-                                break;
-                            }
-                        };
-                    }
+    // Closing the body ends the upload as if the file was complete, so
+    // a read error has to abort it instead. The reader also reports
+    // back separately, so a failed read can never produce a cap.
+    let (done_tx, done_rx) = oneshot::channel::<io::Result<()>>();
+    pool.execute(move || {
+        let mut tx_bodies = senders;
+        // Unwinding would drop the bodies, which ends them just like closing
+        // them does, and the pool would carry on without a word. So a panic
+        // while reading, compressing or reporting progress fails the upload
+        // like a read error.
+        let pumped = panic::catch_unwind(AssertUnwindSafe(|| pump(file, &mut tx_bodies, &*progress, &activity)))
+            .unwrap_or_else(|panic| {
+                let message = format!("file reader panicked: {}", panic_message(&*panic));
+                Err(io::Error::other(message))
+            });
+        match pumped {
+            Ok(true) => {
+                for tx_body in &mut tx_bodies {
+                    let _ = tx_body.close();
                 }
+                let _ = done_tx.send(Ok(()));
             }
-        });
+            // Whatever stopped that upload is what the attempt fails with.
+            Ok(false) => {
+                let _ = done_tx.send(Ok(()));
+                abort(tx_bodies, io::ErrorKind::BrokenPipe, "another upload of the file stopped");
+            }
+            Err(e) => {
+                let kind = e.kind();
+                let _ = done_tx.send(Err(e));
+                abort(tx_bodies, kind, "failed to read file");
+            }
+        }
+    });
+    let read = done_rx.then(|res| match res {
+        Ok(Ok(())) => Ok(()),
+        Ok(Err(e)) => Err(Error::with_chain(e, "Failed to read file")),
+        Err(_) => Err("File reader stopped unexpectedly".into()),
+    });
+
+    Box::new(read.join(future::join_all(responses)).map(|(_, caps)| caps))
+}
+
+type BodySender = mpsc::Sender<result::Result<Chunk, hyper::Error>>;
 
-        self.client
-            .request(request)
-            .map_err(upload_err)
-            .and_then(|res| {
-                if res.status().is_success() {
-                    Ok(res)
-                } else {
-                    bail!(ErrorKind::Tahoe(res.status()))
+/// Sends what `file` reads to each of `tx_bodies`, touching `activity` and
+/// reporting `progress` as every chunk is sent to all of them. True once
+/// it's all been read, false if one of the uploads stopped first. Either
+/// way, `tx_bodies` is left with the ones that are still open.
+fn pump<F>(mut file: Box<Read + Send>, tx_bodies: &mut Vec<BodySender>, progress: &F, activity: &Activity) -> io::Result<bool>
+where
+    F: Fn(usize),
+{
+    let mut buf = [0u8; 1024];
+    loop {
+        let n = match file.read(&mut buf) {
+            Err(ref e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+            Ok(0) => return Ok(true),
+            Ok(n) => n,
+        };
+        let mut senders = mem::take(tx_bodies).into_iter();
+        while let Some(tx_body) = senders.next() {
+            let chunk: Chunk = buf[0..n].to_vec().into();
+            match tx_body.send(Ok(chunk)).wait() {
+                Ok(tx_body) => tx_bodies.push(tx_body),
+                // One upload that stopped fails the whole attempt, so
+                // there's no point feeding the others.
+                Err(_) => {
+                    tx_bodies.extend(senders);
+                    return Ok(false);
                 }
-            })
-            .and_then(|res| {
-                res.body()
-                    .concat2()
-                    .map_err(|e| Error::with_chain(e, "Failed to read response"))
-            })
-            .and_then(|b| {
-                String::from_utf8(b.to_vec())
-                    .map_err(|e| Error::with_chain(e, "Failed to parse response into string"))
-            }) // TODO: Don't clone here
+            }
+        }
+        progress(n);
+        activity.touch();
+    }
+}
+
+/// Ends each of `tx_bodies` with an error, so the upload fails instead of
+/// completing with what was sent so far.
+fn abort(tx_bodies: Vec<BodySender>, kind: io::ErrorKind, message: &str) {
+    for tx_body in tx_bodies {
+        let _ = tx_body.send(Err(hyper::Error::Io(io::Error::new(kind, message)))).wait();
+    }
+}
+
+/// What a panic said, if it said it with a string.
+fn panic_message(panic: &(Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic.downcast_ref::<String>().map_or("(no message)", |message| message.as_str()),
     }
 }
 
@@ -267,3 +1501,91 @@ where
 {
     Error::with_chain(error, "failed to upload file")
 }
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn attach_uri_escapes_each_component() {
+        // Formed the way `attach` forms it.
+        let uri = Uri::from_str(&format!(
+            "http://127.0.0.1:3456/uri/{}/{}?t=uri",
+            encode_path("URI:DIR2:abc:def"),
+            encode_path("My Docs/2023+01")
+        )).unwrap();
+        assert_eq!(uri.path(), "/uri/URI%3ADIR2%3Aabc%3Adef/My%20Docs/2023%2B01");
+        assert_eq!(uri.query(), Some("t=uri"));
+        assert_eq!(encode_segment("a/b c"), "a%2Fb%20c");
+    }
+
+    fn is_seconds(value: &serde_json::Value) -> bool {
+        value.as_f64().is_some_and(|secs| secs > 0.0)
+    }
+
+    #[test]
+    fn dir_node_serializes_as_tahoe_lays_it_out() {
+        let meta = ::std::fs::metadata(env!("CARGO_MANIFEST_DIR")).unwrap();
+        let node = DirNode::new(String::from("URI:CHK:abc:def"), Some(meta));
+        let json = serde_json::to_value(&node).unwrap();
+        let parts = json.as_array().unwrap();
+        assert_eq!(parts.len(), 2);
+        assert_eq!(parts[0], "filenode");
+        let inner = parts[1].as_object().unwrap();
+        assert_eq!(inner["ro_uri"], "URI:CHK:abc:def");
+        assert!(!inner.contains_key("rw_uri"));
+        let metadata = inner["metadata"].as_object().unwrap();
+        assert!(is_seconds(&metadata["mtime"]));
+        // Not every filesystem records when a file was made.
+        if metadata.contains_key("ctime") {
+            assert!(is_seconds(&metadata["ctime"]));
+        }
+        let tahoe = metadata["tahoe"].as_object().unwrap();
+        assert_eq!(tahoe.len(), 2);
+        assert!(is_seconds(&tahoe["linkcrtime"]));
+        assert!(is_seconds(&tahoe["linkmotime"]));
+    }
+
+    #[test]
+    fn dir_node_links_a_mutable_dir_by_its_write_cap() {
+        let node = DirNode::new(String::from("URI:DIR2-MDMF:abc:def"), None);
+        let json = serde_json::to_value(&node).unwrap();
+        assert_eq!(json[0], "dirnode");
+        assert_eq!(json[1]["rw_uri"], "URI:DIR2-MDMF:abc:def");
+        assert!(json[1].get("ro_uri").is_none());
+        assert!(json[1]["metadata"]["tahoe"].is_object());
+    }
+
+    /// A directory of one file with the given `mtime`, and `tahoe` link
+    /// times in place of the ones `DirNode::new` sets.
+    fn dir_with(mtime: f64, tahoe: &str) -> Dir {
+        let mut node = DirNode::new(String::from("URI:CHK:abc:def"), None);
+        node.insert_metadata("mtime", mtime);
+        node.insert_metadata("tahoe", serde_json::from_str::<serde_json::Value>(tahoe).unwrap());
+        Some((String::from("file"), node)).into_iter().collect()
+    }
+
+    #[test]
+    fn dir_hash_changes_with_a_child_mtime() {
+        let links = r#"{"linkcrtime": 1.0, "linkmotime": 1.0}"#;
+        assert_eq!(dir_with(1.0, links).hash(), dir_with(1.0, links).hash());
+        assert_ne!(dir_with(1.0, links).hash(), dir_with(2.0, links).hash());
+    }
+
+    #[test]
+    fn dir_hash_ignores_link_times() {
+        let first = dir_with(1.0, r#"{"linkcrtime": 1.0, "linkmotime": 1.0}"#);
+        let later = dir_with(1.0, r#"{"linkcrtime": 2.0, "linkmotime": 3.0}"#);
+        assert_eq!(first.hash(), later.hash());
+    }
+
+    #[test]
+    fn refused_connections_are_unreachable_and_transient() {
+        let refused = send_err(hyper::Error::Io(io::Error::from(io::ErrorKind::ConnectionRefused)));
+        assert!(matches!(*refused.kind(), ErrorKind::NodeUnreachable));
+        assert!(refused.is_transient());
+        let cut_off = Error::from(hyper::Error::Incomplete);
+        assert!(cut_off.is_transient());
+    }
+}
@@ -0,0 +1,103 @@
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+
+use flate2;
+use zstd;
+
+/// How file contents are compressed before they're uploaded.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Compression {
+    Gzip,
+    Zstd,
+}
+
+/// Level 0 is zstd's own default.
+const ZSTD_LEVEL: i32 = 0;
+
+impl Compression {
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        }
+    }
+
+    /// Wraps `reader` so that it reads the compressed contents.
+    pub fn encoder<R: Read + Send + 'static>(&self, reader: R) -> io::Result<Box<Read + Send>> {
+        Ok(match *self {
+            Compression::Gzip => Box::new(flate2::read::GzEncoder::new(reader, flate2::Compression::default())),
+            Compression::Zstd => Box::new(zstd::stream::read::Encoder::new(reader, ZSTD_LEVEL)?),
+        })
+    }
+}
+
+impl FromStr for Compression {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, ()> {
+        match s {
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => Err(()),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Writes contents downloaded from the grid to `W`, decompressing them on
+/// the way if they were compressed.
+pub enum Decoder<W: Write> {
+    Plain(W),
+    Gzip(flate2::write::GzDecoder<W>),
+    Zstd(zstd::stream::write::Decoder<W>),
+}
+
+impl<W: Write> Decoder<W> {
+    pub fn new(compression: Option<Compression>, writer: W) -> io::Result<Self> {
+        Ok(match compression {
+            None => Decoder::Plain(writer),
+            Some(Compression::Gzip) => Decoder::Gzip(flate2::write::GzDecoder::new(writer)),
+            Some(Compression::Zstd) => Decoder::Zstd(zstd::stream::write::Decoder::new(writer)?),
+        })
+    }
+
+    /// Writes out whatever is still buffered, failing if the compressed
+    /// contents were cut short.
+    pub fn finish(self) -> io::Result<W> {
+        match self {
+            Decoder::Plain(mut writer) => {
+                writer.flush()?;
+                Ok(writer)
+            }
+            Decoder::Gzip(decoder) => decoder.finish(),
+            Decoder::Zstd(mut decoder) => {
+                decoder.flush()?;
+                Ok(decoder.into_inner())
+            }
+        }
+    }
+}
+
+impl<W: Write> Write for Decoder<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Decoder::Plain(ref mut writer) => writer.write(buf),
+            Decoder::Gzip(ref mut decoder) => decoder.write(buf),
+            Decoder::Zstd(ref mut decoder) => decoder.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Decoder::Plain(ref mut writer) => writer.flush(),
+            Decoder::Gzip(ref mut decoder) => decoder.flush(),
+            Decoder::Zstd(ref mut decoder) => decoder.flush(),
+        }
+    }
+}
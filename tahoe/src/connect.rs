@@ -0,0 +1,197 @@
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, SocketAddr};
+
+use tokio_core::net::TcpStream;
+use tokio_core::reactor;
+use tokio_io::{io as aio, AsyncRead, AsyncWrite};
+
+use futures::{future, Future, Poll};
+
+use hyper::Uri;
+use hyper::client::{HttpConnector, Service};
+
+use hyper_tls::HttpsConnector;
+
+type Direct = HttpsConnector<HttpConnector>;
+
+/// How connections to the node are made: straight to it, or through a SOCKS5
+/// proxy.
+#[derive(Clone)]
+pub enum Connector {
+    Direct(Direct),
+    Socks5(Socks5),
+}
+
+impl Service for Connector {
+    type Request = Uri;
+    type Response = Connection;
+    type Error = io::Error;
+    type Future = Box<Future<Item = Connection, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        match *self {
+            Connector::Direct(ref connector) => Box::new(connector.call(uri).map(Connection::Direct)),
+            Connector::Socks5(ref connector) => Box::new(connector.call(uri).map(Connection::Socks5)),
+        }
+    }
+}
+
+pub enum Connection {
+    Direct(<Direct as Service>::Response),
+    Socks5(TcpStream),
+}
+
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Direct(ref mut stream) => stream.read(buf),
+            Connection::Socks5(ref mut stream) => stream.read(buf),
+        }
+    }
+}
+
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match *self {
+            Connection::Direct(ref mut stream) => stream.write(buf),
+            Connection::Socks5(ref mut stream) => stream.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match *self {
+            Connection::Direct(ref mut stream) => stream.flush(),
+            Connection::Socks5(ref mut stream) => stream.flush(),
+        }
+    }
+}
+
+impl AsyncRead for Connection {}
+
+impl AsyncWrite for Connection {
+    fn shutdown(&mut self) -> Poll<(), io::Error> {
+        match *self {
+            Connection::Direct(ref mut stream) => stream.shutdown(),
+            Connection::Socks5(ref mut stream) => AsyncWrite::shutdown(stream),
+        }
+    }
+}
+
+/// Connects through a SOCKS5 proxy without authentication. Host names are
+/// handed to the proxy as they are, so they're resolved on its end and
+/// `.onion` addresses work through Tor.
+#[derive(Clone)]
+pub struct Socks5 {
+    proxy: SocketAddr,
+    handle: reactor::Handle,
+}
+
+impl Socks5 {
+    pub fn new(proxy: SocketAddr, handle: &reactor::Handle) -> Self {
+        Socks5 {
+            proxy,
+            handle: handle.clone(),
+        }
+    }
+}
+
+fn proxy_error(message: &str) -> io::Error {
+    io::Error::other(format!("SOCKS5 proxy: {}", message))
+}
+
+/// The CONNECT request for the host and port of `uri`.
+fn connect_request(uri: &Uri) -> io::Result<Vec<u8>> {
+    let host = uri.host()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "URI has no host"))?;
+    let port = uri.port().unwrap_or(match uri.scheme() {
+        Some("https") => 443,
+        _ => 80,
+    });
+    // Version 5, CONNECT, reserved, then the address.
+    let mut request = vec![5, 1, 0];
+    match host.trim_matches(|c| c == '[' || c == ']').parse() {
+        Ok(IpAddr::V4(ip)) => {
+            request.push(1);
+            request.extend_from_slice(&ip.octets());
+        }
+        Ok(IpAddr::V6(ip)) => {
+            request.push(4);
+            request.extend_from_slice(&ip.octets());
+        }
+        Err(_) => {
+            if host.len() > 255 {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "host name too long"));
+            }
+            request.push(3);
+            request.push(host.len() as u8);
+            request.extend_from_slice(host.as_bytes());
+        }
+    }
+    request.push((port >> 8) as u8);
+    request.push(port as u8);
+    Ok(request)
+}
+
+fn reply_message(code: u8) -> &'static str {
+    match code {
+        1 => "general failure",
+        2 => "connection not allowed by ruleset",
+        3 => "network unreachable",
+        4 => "host unreachable",
+        5 => "connection refused",
+        6 => "TTL expired",
+        7 => "command not supported",
+        8 => "address type not supported",
+        _ => "unknown error",
+    }
+}
+
+impl Service for Socks5 {
+    type Request = Uri;
+    type Response = TcpStream;
+    type Error = io::Error;
+    type Future = Box<Future<Item = TcpStream, Error = io::Error>>;
+
+    fn call(&self, uri: Uri) -> Self::Future {
+        let request = match connect_request(&uri) {
+            Ok(request) => request,
+            Err(e) => return Box::new(future::err(e)),
+        };
+        Box::new(
+            TcpStream::connect(&self.proxy, &self.handle)
+                // Version 5, offering only "no authentication".
+                .and_then(|stream| aio::write_all(stream, [5u8, 1, 0]))
+                .and_then(|(stream, _)| aio::read_exact(stream, [0u8; 2]))
+                .and_then(|(stream, reply)| {
+                    if reply != [5, 0] {
+                        return Err(proxy_error("no supported authentication method"));
+                    }
+                    Ok(stream)
+                })
+                .and_then(move |stream| aio::write_all(stream, request))
+                .and_then(|(stream, _)| aio::read_exact(stream, [0u8; 4]))
+                .and_then(|(stream, reply)| {
+                    if reply[0] != 5 {
+                        return future::Either::A(future::err(proxy_error("invalid reply")));
+                    }
+                    if reply[1] != 0 {
+                        return future::Either::A(future::err(proxy_error(reply_message(reply[1]))));
+                    }
+                    // The reply ends in the address the proxy bound, which
+                    // is of no use here but has to be read past.
+                    let rest: Box<Future<Item = (TcpStream, usize), Error = io::Error>> = match reply[3] {
+                        1 => Box::new(future::ok((stream, 4 + 2))),
+                        4 => Box::new(future::ok((stream, 16 + 2))),
+                        3 => Box::new(
+                            aio::read_exact(stream, [0u8; 1]).map(|(stream, len)| (stream, len[0] as usize + 2)),
+                        ),
+                        _ => Box::new(future::err(proxy_error("invalid address type"))),
+                    };
+                    future::Either::B(
+                        rest.and_then(|(stream, len)| aio::read_exact(stream, vec![0u8; len]))
+                            .map(|(stream, _)| stream),
+                    )
+                }),
+        )
+    }
+}
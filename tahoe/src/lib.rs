@@ -1,12 +1,16 @@
-#![feature(conservative_impl_trait)]
+extern crate flate2;
 extern crate futures;
 extern crate hyper;
+extern crate hyper_tls;
+extern crate rand;
 extern crate seahash;
 extern crate serde;
 extern crate serde_json;
 extern crate threadpool;
 extern crate tokio_core;
+extern crate tokio_io;
 extern crate url;
+extern crate zstd;
 
 #[macro_use]
 extern crate serde_derive;
@@ -21,13 +25,89 @@ pub mod errors {
     use hyper;
 
     error_chain!{
+        foreign_links {
+            Hyper(hyper::Error);
+            Io(::std::io::Error);
+        }
         errors {
             Tahoe(s: hyper::StatusCode) {
                 description("Tahoe error"),
                 display("Tahoe returned {} {}", s.as_u16(), s.canonical_reason().unwrap_or("(unknown)"))
             }
+            NodeUnreachable {
+                description("Tahoe node unreachable"),
+                display("Couldn't connect to the Tahoe node"),
+            }
+            NotADirectory {
+                description("Not a directory"),
+                display("Cap is not a directory"),
+            }
+            ReadOnlyCap {
+                description("Read-only cap"),
+                display("Cap is read-only, a write cap is needed"),
+            }
+            CapNotFound {
+                description("Cap not found"),
+                display("Tahoe has no such cap or path"),
+            }
+            ServerOverloaded {
+                description("Tahoe node overloaded"),
+                display("Tahoe node is unavailable or overloaded (503)"),
+            }
+            Encoding(encoding: ::client::Encoding) {
+                description("Invalid encoding parameters"),
+                display("Invalid encoding parameters: shares needed ({}), happy ({}) and total ({}) must be positive, with needed <= happy <= total",
+                        show(encoding.needed), show(encoding.happy), show(encoding.total)),
+            }
+            NodeUrl(url: String) {
+                description("Invalid node URL"),
+                display("Invalid node URL: '{}'", url),
+            }
+            Proxy(proxy: String) {
+                description("Invalid SOCKS5 proxy"),
+                display("Invalid SOCKS5 proxy: '{}', expected HOST:PORT", proxy),
+            }
+            ProxyTls {
+                description("TLS through a SOCKS5 proxy"),
+                display("Can't use an https:// node URL through a SOCKS5 proxy, use http://"),
+            }
+            RequestTimeout(secs: u64) {
+                description("Request timed out"),
+                display("Request made no progress for {}s", secs),
+            }
+        }
+    }
+
+    fn show(param: Option<u32>) -> String {
+        param.map_or_else(|| String::from("default"), |p| p.to_string())
+    }
+
+    impl Error {
+        /// Whether the operation that produced this error is worth retrying:
+        /// connection-level failures and 5xx responses are, 4xx responses and
+        /// local errors aren't.
+        pub fn is_transient(&self) -> bool {
+            match *self.kind() {
+                ErrorKind::Tahoe(status) => status.is_server_error(),
+                ErrorKind::NodeUnreachable
+                | ErrorKind::ServerOverloaded
+                | ErrorKind::Hyper(hyper::Error::Io(_))
+                | ErrorKind::Hyper(hyper::Error::Incomplete)
+                | ErrorKind::Hyper(hyper::Error::Timeout)
+                | ErrorKind::RequestTimeout(_) => true,
+                _ => false,
+            }
+        }
+
+        /// Whether Tahoe said the requested cap or path doesn't exist.
+        pub fn is_not_found(&self) -> bool {
+            matches!(*self.kind(), ErrorKind::CapNotFound)
         }
     }
 }
 
 pub mod client;
+pub mod compress;
+mod connect;
+pub mod limit;
+mod timeout;
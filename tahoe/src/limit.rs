@@ -0,0 +1,81 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::rc::Rc;
+
+use futures::{Async, Future, Poll};
+use futures::task::{self, Task};
+
+struct State {
+    free: Option<usize>,
+    waiting: VecDeque<Task>,
+}
+
+/// Caps how many requests are running at once, and so how many connections
+/// are open, as hyper only opens one when there's no idle one to reuse.
+/// Callers can use one of their own to cap other work the same way.
+#[derive(Clone)]
+pub struct Limit(Rc<RefCell<State>>);
+
+impl Limit {
+    /// No cap at all if `max` is `None`.
+    pub fn new(max: Option<usize>) -> Self {
+        Limit(Rc::new(RefCell::new(State {
+            free: max,
+            waiting: VecDeque::new(),
+        })))
+    }
+
+    /// Runs the future made by `f` once a slot is free, keeping the slot
+    /// until it resolves.
+    pub fn run<F, R>(&self, f: F) -> impl Future<Item = R::Item, Error = R::Error>
+    where
+        F: FnOnce() -> R,
+        R: ::futures::IntoFuture,
+    {
+        // Acquiring never fails.
+        Acquire(self.clone()).then(move |slot| {
+            f().into_future().then(move |res| {
+                drop(slot);
+                res
+            })
+        })
+    }
+}
+
+struct Acquire(Limit);
+
+impl Future for Acquire {
+    type Item = Slot;
+    type Error = ();
+
+    fn poll(&mut self) -> Poll<Slot, ()> {
+        let mut state = (self.0).0.borrow_mut();
+        match state.free {
+            None => return Ok(Async::Ready(Slot(None))),
+            Some(0) => {}
+            Some(ref mut n) => {
+                *n -= 1;
+                return Ok(Async::Ready(Slot(Some(self.0.clone()))));
+            }
+        }
+        state.waiting.push_back(task::current());
+        Ok(Async::NotReady)
+    }
+}
+
+struct Slot(Option<Limit>);
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        if let Some(ref limit) = self.0 {
+            let mut state = limit.0.borrow_mut();
+            if let Some(ref mut n) = state.free {
+                *n += 1;
+            }
+            // Everyone gets to try again, in case some of them are gone.
+            for task in state.waiting.drain(..) {
+                task.notify();
+            }
+        }
+    }
+}
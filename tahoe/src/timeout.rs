@@ -0,0 +1,85 @@
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio_core::reactor;
+
+use futures::{Async, Future, Poll};
+
+use errors::*;
+
+/// When a request last made progress. Shared with whatever does the work, so
+/// it can be touched from other threads.
+#[derive(Clone)]
+pub struct Activity(Arc<Mutex<Instant>>);
+
+impl Activity {
+    pub fn new() -> Self {
+        Activity(Arc::new(Mutex::new(Instant::now())))
+    }
+
+    pub fn touch(&self) {
+        *self.0.lock().unwrap() = Instant::now();
+    }
+
+    fn last(&self) -> Instant {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Resolves like `inner`, but fails with `ErrorKind::RequestTimeout` once
+/// `limit` has passed since the last time `activity` was touched. Without a
+/// limit it's just `inner`.
+pub struct Idle<F> {
+    inner: F,
+    limit: Option<Duration>,
+    activity: Activity,
+    handle: reactor::Handle,
+    timer: Option<reactor::Timeout>,
+}
+
+impl<F> Idle<F> {
+    pub fn new(inner: F, limit: Option<Duration>, activity: Activity, handle: &reactor::Handle) -> Self {
+        Idle {
+            inner,
+            limit,
+            activity,
+            handle: handle.clone(),
+            timer: None,
+        }
+    }
+}
+
+impl<F: Future<Error = Error>> Future for Idle<F> {
+    type Item = F::Item;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<F::Item, Error> {
+        if let Async::Ready(x) = self.inner.poll()? {
+            return Ok(Async::Ready(x));
+        }
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => return Ok(Async::NotReady),
+        };
+        loop {
+            let deadline = self.activity.last() + limit;
+            if deadline <= Instant::now() {
+                bail!(ErrorKind::RequestTimeout(limit.as_secs()));
+            }
+            // The timer is moved to the latest deadline whenever it fires
+            // early because there was progress in the meantime.
+            match self.timer {
+                Some(ref mut timer) => timer.reset(deadline),
+                None => {
+                    self.timer = Some(reactor::Timeout::new_at(deadline, &self.handle)
+                        .chain_err(|| "failed to set up timeout")?)
+                }
+            }
+            match self.timer.as_mut().unwrap().poll() {
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Ok(Async::Ready(())) => {}
+                Err(e) => return Err(Error::with_chain(e, "failed to wait for timeout")),
+            }
+        }
+    }
+}